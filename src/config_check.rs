@@ -0,0 +1,261 @@
+use std::collections::HashSet;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::mail_move_rules::mail_move_settings::{find_mail_move_config_file, load_rules_file, mail_move_config_path, RulesConfig};
+use crate::mail_move_rules::scheduler::period;
+use crate::mail_reader::encryption::has_stored_password;
+use crate::mail_reader::imap::list_imap_folders;
+use crate::daemon::Service;
+use crate::settings::{self, same_listen_address, Config};
+
+// How long the check waits for the IMAP server before giving up on the folder checks
+const SERVER_TIMEOUT: Duration = Duration::from_secs(15);
+// Runs closer together than this are likely to be throttled by the server
+const MIN_SENSIBLE_PERIOD: Duration = Duration::from_secs(30);
+// Folder emptied by the spam job
+const SPAM_FOLDER: &str = "Spam";
+
+/// Findings of `config check`, printed as they are made
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+// Continuation lines of a message (e.g. a regex error) are indented under its first line
+fn indent(message: &str) -> String {
+    message.replace('\n', "\n           ")
+}
+
+impl Report {
+    fn section(&self, title: &str) {
+        println!("{}", title);
+    }
+
+    fn ok(&self, message: impl AsRef<str>) {
+        println!("  ok       {}", indent(message.as_ref()));
+    }
+
+    fn warning(&mut self, message: impl AsRef<str>) {
+        self.warnings += 1;
+        println!("  warning  {}", indent(message.as_ref()));
+    }
+
+    fn error(&mut self, message: impl AsRef<str>) {
+        self.errors += 1;
+        println!("  error    {}", indent(message.as_ref()));
+    }
+
+    /// Print the totals; true when there were no errors
+    fn finish(self) -> bool {
+        println!("{} errors, {} warnings", self.errors, self.warnings);
+        self.errors == 0
+    }
+}
+
+fn describe_path(report: &mut Report, kind: &str, path: Option<&Path>) {
+    match path {
+        Some(path) if settings::in_deprecated_dir(path) => {
+            report.warning(format!("{}: {} (deprecated myapp directory, move it to an almambet one)", kind, path.display()))
+        }
+        Some(path) => report.ok(format!("{}: {}", kind, path.display())),
+        None => report.ok(format!("{}: not found", kind)),
+    }
+}
+
+fn check_files(report: &mut Report) {
+    report.section("Files");
+    describe_path(report, "settings", settings::config_file_path().as_deref());
+    let overrides = settings::override_variables();
+    if !overrides.is_empty() {
+        report.ok(format!("environment overrides: {}", overrides.join(", ")));
+    }
+}
+
+fn check_settings(report: &mut Report, services: &[Service]) -> Option<Config> {
+    report.section("Settings");
+    let config = match settings::load_settings() {
+        Ok(config) => config,
+        Err(e) => {
+            report.error(e.to_string());
+            return None;
+        }
+    };
+
+    let errors = config.validate();
+    for error in &errors {
+        report.error(error);
+    }
+    check_intervals(report, &config);
+    check_ports(report, &config, services);
+    if errors.is_empty() {
+        report.ok("settings loaded");
+    }
+    Some(config)
+}
+
+fn check_intervals(report: &mut Report, config: &Config) {
+    for account in config.accounts() {
+        check_account_intervals(report, &account);
+    }
+    if config.server.watch_interval == 0 {
+        report.warning("server.watch_interval is 0, the folders are polled every second instead");
+    }
+}
+
+fn check_account_intervals(report: &mut Report, config: &Config) {
+    let mail_mover = &config.mail_mover;
+    let schedules = [("rules", Some(mail_mover.rules_schedule())), ("spam", mail_mover.jobs.spam.clone())];
+    // Named when there are several accounts, which may have their own schedules
+    let job_name = |job: &str| match &config.account {
+        Some(account) => format!("{} {}", account.name, job),
+        None => job.to_string(),
+    };
+    for (job, schedule) in schedules {
+        // Invalid schedules are already reported by the validation
+        let Some(Ok(every)) = schedule.as_ref().map(period) else { continue };
+        if every.is_zero() {
+            continue;
+        }
+        if every < MIN_SENSIBLE_PERIOD {
+            report.warning(format!("the {} job runs every {:?}, the server may throttle such frequent logins", job_name(job), every));
+        }
+        if mail_mover.jitter > 0 && Duration::from_secs(mail_mover.jitter) >= every {
+            report.warning(format!(
+                "mail_mover.jitter ({}s) is not shorter than the period of the {} job ({:?}), runs will be skipped",
+                mail_mover.jitter, job_name(job), every
+            ));
+        }
+    }
+}
+
+// With both --web and --rest the two servers must listen on different addresses; without the modes
+// given, a clash is only a warning since either of them may run alone
+fn check_ports(report: &mut Report, config: &Config, services: &[Service]) {
+    if let Some(clash) = config.web_and_rest_clash() {
+        if services.contains(&Service::Web) && services.contains(&Service::Rest) {
+            report.error(clash);
+        } else if services.is_empty() {
+            report.warning(clash);
+        }
+    }
+
+    let Some(metrics) = &config.metrics else { return };
+    if let Err(e) = metrics.listen.to_socket_addrs() {
+        report.error(format!("metrics.listen: invalid address {:?}: {}", metrics.listen, e));
+        return;
+    }
+    if same_listen_address(&metrics.listen, &config.web.address()) {
+        report.warning(format!("metrics.listen is the address of the web interface ({}): only use it without --web", config.web.address()));
+    } else if same_listen_address(&metrics.listen, &config.server.address()) {
+        report.warning(format!("metrics.listen is the address of server.host/server.port ({}): only use it without --rest", config.server.address()));
+    }
+}
+
+// Rules of the account of `config`; without settings, the rules file found as usual
+fn check_rules(report: &mut Report, config: Option<&Config>) -> Option<RulesConfig> {
+    let path = match config {
+        Some(config) => mail_move_config_path(config),
+        None => find_mail_move_config_file(),
+    };
+    describe_path(report, "file", path.as_deref());
+    let Some(path) = path else {
+        report.error("Could not find email_move_rules.yaml in any of the expected locations");
+        return None;
+    };
+    let rules_config = match load_rules_file(&path) {
+        Ok(rules_config) => rules_config,
+        Err(e) => {
+            report.error(e.to_string());
+            return None;
+        }
+    };
+
+    let errors = rules_config.validate();
+    for error in &errors {
+        report.error(error);
+    }
+    if rules_config.messages_to_check == 0 {
+        report.warning("messages_to_check is 0, the rules will never match anything");
+    }
+    if errors.is_empty() {
+        report.ok(format!("{} rules", rules_config.rules.len()));
+    }
+    Some(rules_config)
+}
+
+// Compare the folders the rules and jobs use with the folders of the account
+async fn check_folders(report: &mut Report, config: &Config, rules_config: Option<&RulesConfig>) {
+    if !has_stored_password(config.account_name()) {
+        report.warning("folders not checked: no password is stored yet, run `almambet credentials set` to store it");
+        return;
+    }
+
+    let imap = match config.imap() {
+        Ok(imap) => imap,
+        Err(e) => {
+            report.error(e.to_string());
+            return;
+        }
+    };
+    let folders = match tokio::time::timeout(SERVER_TIMEOUT, list_imap_folders(config)).await {
+        Ok(Ok(folders)) => folders,
+        Ok(Err(e)) => {
+            report.warning(format!("folders not checked: {}", e));
+            return;
+        }
+        Err(_) => {
+            report.warning(format!("folders not checked: no answer from {}:{} within {:?}", imap.server, imap.port, SERVER_TIMEOUT));
+            return;
+        }
+    };
+    report.ok(format!("logged in to {}:{} as {}, {} folders", imap.server, imap.port, imap.username, folders.len()));
+
+    let folders: HashSet<&str> = folders.iter().map(String::as_str).collect();
+    let mut missing = Vec::new();
+    for (index, wrapper) in rules_config.map(|rules_config| rules_config.rules.as_slice()).unwrap_or_default().iter().enumerate() {
+        if !folders.contains(wrapper.rule.target_folder.as_str()) {
+            missing.push(format!("rule {}: target folder {:?} does not exist", index + 1, wrapper.rule.target_folder));
+        }
+    }
+    if config.mail_mover.jobs.spam.is_some() && !folders.contains(SPAM_FOLDER) {
+        missing.push(format!("mail_mover.jobs.spam: the {} folder does not exist", SPAM_FOLDER));
+    }
+    if missing.is_empty() {
+        report.ok("every target folder exists");
+    }
+    for message in missing {
+        report.error(message);
+    }
+}
+
+/// Check the settings and rules files and print what was found; true when there are no errors.
+///
+/// `services` are the modes given with the command, which the settings must allow to run together.
+/// The folders used by the rules are checked against the server when a password is stored
+/// and the server answers; otherwise that part is skipped with a warning.
+pub async fn run(services: &[Service]) -> bool {
+    let mut report = Report::default();
+    check_files(&mut report);
+    let Some(config) = check_settings(&mut report, services) else {
+        report.section("Rules");
+        check_rules(&mut report, None);
+        return report.finish();
+    };
+
+    let accounts = config.accounts();
+    for account in &accounts {
+        // With a single account there is no name worth showing
+        let title = |section: &str| match &account.account {
+            Some(settings) => format!("{} ({})", section, settings.name),
+            None => section.to_string(),
+        };
+        report.section(&title("Rules"));
+        let rules_config = check_rules(&mut report, Some(account));
+        report.section(&title("Server"));
+        check_folders(&mut report, account, rules_config.as_ref()).await;
+    }
+    report.finish()
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use clap::ArgMatches;
+
+use crate::mail_reader::encryption;
+use crate::mail_reader::imap;
+use crate::settings::{self, Config};
+
+// How long a login may take before the server is considered unreachable
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Log in to the server of the account with `password`, then log out again
+async fn try_login(config: &Config, password: &str) -> Result<()> {
+    let settings = config.imap()?;
+    let mut session = tokio::time::timeout(LOGIN_TIMEOUT, imap::login(settings, password))
+        .await
+        .map_err(|_| anyhow!("no answer from {}:{} within {:?}", settings.server, settings.port, LOGIN_TIMEOUT))??;
+    session.logout().await?;
+    Ok(())
+}
+
+// The accounts picked with `--account`, every account without it
+fn selected_accounts(matches: &ArgMatches) -> Result<Vec<Config>> {
+    let config = settings::load_settings()?;
+    let Some(name) = matches.get_one::<String>("account") else {
+        return Ok(config.accounts());
+    };
+    match config.account(name) {
+        Some(account) => Ok(vec![account]),
+        None => bail!("No account named {:?}, the accounts are: {}", name, config.account_names().join(", ")),
+    }
+}
+
+/// Ask for the password of the account and store it once the server accepted it
+async fn set(config: &Config) -> Result<String> {
+    let settings = config.imap()?;
+    let password = rpassword::prompt_password(format!("Enter the password of {}: ", settings.username))?;
+    try_login(config, &password).await.map_err(|e| anyhow!("not stored, the login failed: {}", e))?;
+    encryption::store_password(config.account_name(), &password)?;
+    Ok(format!("logged in to {}:{} as {}, password stored", settings.server, settings.port, settings.username))
+}
+
+/// Log in with the stored password
+async fn verify(config: &Config) -> Result<String> {
+    let settings = config.imap()?;
+    let Some(password) = encryption::stored_password(config.account_name())? else {
+        bail!("no password is stored");
+    };
+    try_login(config, &password).await?;
+    Ok(format!("logged in to {}:{} as {}", settings.server, settings.port, settings.username))
+}
+
+fn clear(config: &Config) -> Result<String> {
+    if encryption::clear_password(config.account_name())? {
+        Ok("password removed".to_string())
+    } else {
+        Ok("no password was stored".to_string())
+    }
+}
+
+fn rotate_key() -> bool {
+    match encryption::rotate_key() {
+        Ok(files) if files.is_empty() => println!("new key created, no password is stored"),
+        Ok(files) => {
+            println!("new key created, passwords encrypted again:");
+            for file in files {
+                println!("  {}", file.display());
+            }
+        }
+        Err(e) => {
+            println!("error: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Run a `credentials` subcommand, printing one line per account; true when it worked for all of them
+pub async fn run(matches: &ArgMatches) -> bool {
+    let Some((command, matches)) = matches.subcommand() else {
+        return false;
+    };
+    if command == "rotate-key" {
+        return rotate_key();
+    }
+
+    let accounts = match selected_accounts(matches) {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            println!("error: {}", e);
+            return false;
+        }
+    };
+    let mut passed = true;
+    for account in &accounts {
+        let result = match command {
+            "set" => set(account).await,
+            "verify" => verify(account).await,
+            "clear" => clear(account),
+            _ => unreachable!("unknown credentials subcommand {}", command),
+        };
+        match result {
+            Ok(message) => println!("{}: ok, {}", account.account_name(), message),
+            Err(e) => {
+                println!("{}: error, {}", account.account_name(), e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
@@ -0,0 +1,233 @@
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{ensure_watching, stop_watching};
+use crate::reload::{self, ConfigFile, ConfigWatcher};
+use crate::settings::{self, Config};
+use crate::{mail_move_rules, web, web_services, webhooks};
+
+// How long the services get to finish their work once asked to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+// Delay before restarting a failed service, doubled at every consecutive failure up to the maximum
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// A service that ran at least this long before failing is restarted right away
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// A long-running part of the application, kept running by the daemon
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Service {
+    RuleRunner,
+    Web,
+    Rest,
+}
+
+impl Service {
+    async fn run(self, config: &Config, shutdown: CancellationToken) -> Result<(), String> {
+        let result = match self {
+            Service::RuleRunner => mail_move_rules::entrypoint(config, shutdown).await,
+            Service::Web => web::entrypoint(config, shutdown).await,
+            Service::Rest => web_services::entrypoint(config, shutdown).await,
+        };
+        result.map_err(|e| e.to_string())
+    }
+}
+
+/// What the process was asked to do by a signal
+enum Request {
+    Stop(&'static str),
+    Reload,
+}
+
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn next(&mut self) -> Request {
+        tokio::select! {
+            _ = self.terminate.recv() => Request::Stop("SIGTERM"),
+            _ = self.interrupt.recv() => Request::Stop("SIGINT"),
+            _ = self.hangup.recv() => Request::Reload,
+        }
+    }
+}
+
+// Only Ctrl-C can be caught elsewhere, there is no way to ask for a reload
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> std::io::Result<Self> {
+        Ok(Signals)
+    }
+
+    async fn next(&mut self) -> Request {
+        let _ = tokio::signal::ctrl_c().await;
+        Request::Stop("Ctrl-C")
+    }
+}
+
+// Run `service` until `shutdown` is cancelled, restarting it with an increasing delay when it stops on its own
+async fn supervise(service: Service, config: Config, shutdown: CancellationToken) {
+    let mut delay = INITIAL_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        let result = service.run(&config, shutdown.clone()).await;
+        if shutdown.is_cancelled() {
+            return;
+        }
+
+        if started.elapsed() >= STABLE_RUN {
+            delay = INITIAL_RESTART_DELAY;
+        }
+        match result {
+            Ok(()) => warn!("{:?} stopped, restarting it in {:?}", service, delay),
+            Err(e) => error!("{:?} failed: {}; restarting it in {:?}", service, e, delay),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => return,
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+fn start(services: &[Service], config: &Config, shutdown: &CancellationToken) -> JoinSet<()> {
+    let mut tasks = JoinSet::new();
+    for &service in services {
+        tasks.spawn(supervise(service, config.clone(), shutdown.clone()));
+    }
+
+    webhooks::start(config);
+    if webhooks::forwards_new_messages(config) {
+        tasks.spawn(webhooks::forward_new_messages(config.clone(), shutdown.clone()));
+        ensure_watching(config);
+    }
+    tasks
+}
+
+// Ask every task to finish and wait for them, aborting the ones still running after the timeout
+// or when a second stop signal arrives
+async fn stop(shutdown: CancellationToken, mut tasks: JoinSet<()>, signals: &mut Signals) {
+    shutdown.cancel();
+    let finished = async {
+        while tasks.join_next().await.is_some() {}
+        stop_watching().await;
+    };
+    let stopped_again = async {
+        loop {
+            if let Request::Stop(signal) = signals.next().await {
+                return signal;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = finished => info!("Every service stopped"),
+        _ = tokio::time::sleep(SHUTDOWN_TIMEOUT) => warn!("Services still running after {:?}, aborting them", SHUTDOWN_TIMEOUT),
+        signal = stopped_again => warn!("Received {} again, aborting the services", signal),
+    }
+}
+
+// Read settings.yaml again, checking the new settings before they are used
+fn load_settings() -> Result<Config, String> {
+    let config = settings::load_settings().map_err(|e| e.to_string())?;
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(errors.join("; "));
+    }
+    Ok(config)
+}
+
+fn reload_settings() -> Option<Config> {
+    let (config, result) = match load_settings() {
+        Ok(config) => (Some(config), Ok(())),
+        Err(e) => (None, Err(e)),
+    };
+    reload::record(ConfigFile::Settings, result);
+    config
+}
+
+fn watch(config: &Config) -> Option<ConfigWatcher> {
+    match ConfigWatcher::new(config) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!("Cannot watch the configuration files, send SIGHUP to reload them: {}", e);
+            None
+        }
+    }
+}
+
+// Wait for the next change of the configuration files, forever when they cannot be watched
+async fn config_changes(watcher: &mut Option<ConfigWatcher>) -> Vec<ConfigFile> {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Run `services` concurrently until SIGTERM or SIGINT, restarting the ones that fail.
+///
+/// A change of the settings or rules file, or SIGHUP, reloads them. New settings restart the
+/// services with them and new rules apply from the next run; when a file cannot be loaded or
+/// is invalid, its previous version stays in use.
+pub async fn run(services: &[Service], mut config: Config) -> std::io::Result<()> {
+    let mut signals = Signals::new()?;
+
+    loop {
+        // The new settings may list other accounts, with other rules files
+        let mut watcher = watch(&config);
+        info!("Starting {:?}", services);
+        let shutdown = CancellationToken::new();
+        let tasks = start(services, &config, &shutdown);
+
+        let reloaded = loop {
+            let files = tokio::select! {
+                request = signals.next() => match request {
+                    Request::Stop(signal) => {
+                        info!("Received {}, shutting down", signal);
+                        break None;
+                    }
+                    Request::Reload => {
+                        info!("Received SIGHUP, reloading the settings and the rules");
+                        vec![ConfigFile::Rules, ConfigFile::Settings]
+                    }
+                },
+                files = config_changes(&mut watcher) => files,
+            };
+
+            if files.contains(&ConfigFile::Rules) {
+                reload::reload_rules_file(&config);
+            }
+            if files.contains(&ConfigFile::Settings) {
+                if let Some(new_config) = reload_settings() {
+                    break Some(new_config);
+                }
+            }
+        };
+
+        stop(shutdown, tasks, &mut signals).await;
+        match reloaded {
+            Some(new_config) => config = new_config,
+            None => return Ok(()),
+        }
+    }
+}
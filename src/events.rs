@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::mail_reader::imap::{create_session, fetch_messages_by_uids, select_mailbox, ImapSession};
+use crate::settings::Config;
+
+// How many events a slow subscriber may fall behind before it starts skipping some
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A change in a mailbox or in the configuration, pushed to the web UI as it happens
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MailEvent {
+    NewMessage {
+        folder: String,
+        uid: Option<u32>,
+        subject: String,
+        from: String,
+        date: String,
+        thread_id: Option<String>,
+    },
+    Moved {
+        folder: String,
+        target_folder: String,
+        uid: Option<u32>,
+        message_id: Option<String>,
+    },
+    Deleted {
+        folder: String,
+        uid: Option<u32>,
+        message_id: Option<String>,
+    },
+    FolderStatus {
+        folder: String,
+        messages: u32,
+        unseen: u32,
+    },
+    /// The settings or the rules file changed and was reloaded, or kept at its previous version on `error`
+    ConfigReloaded {
+        file: String,
+        ok: bool,
+        error: Option<String>,
+    },
+}
+
+impl MailEvent {
+    /// Name of the event, as sent in the `event:` field of Server-Sent Events
+    pub fn name(&self) -> &'static str {
+        match self {
+            MailEvent::NewMessage { .. } => "new_message",
+            MailEvent::Moved { .. } => "moved",
+            MailEvent::Deleted { .. } => "deleted",
+            MailEvent::FolderStatus { .. } => "folder_status",
+            MailEvent::ConfigReloaded { .. } => "config_reloaded",
+        }
+    }
+}
+
+/// An event and the account it happened in
+#[derive(Debug, Clone)]
+pub struct AccountEvent {
+    /// `None` for the events of the whole process, e.g. configuration reloads
+    pub account: Option<String>,
+    pub event: MailEvent,
+}
+
+impl AccountEvent {
+    /// Whether a subscriber following `account` should see this event
+    pub fn concerns(&self, account: &str) -> bool {
+        self.account.as_deref().is_none_or(|name| name == account)
+    }
+}
+
+fn sender() -> &'static broadcast::Sender<AccountEvent> {
+    static EVENTS: OnceLock<broadcast::Sender<AccountEvent>> = OnceLock::new();
+    EVENTS.get_or_init(|| broadcast::channel(CHANNEL_CAPACITY).0)
+}
+
+/// Send an event of the account of `config` to every subscriber; nothing happens when nobody is listening
+pub fn publish(config: &Config, event: MailEvent) {
+    send(Some(config.account_name().to_string()), event);
+}
+
+/// Send an event that concerns every account
+pub fn publish_global(event: MailEvent) {
+    send(None, event);
+}
+
+fn send(account: Option<String>, event: MailEvent) {
+    debug!("Publishing event {:?} of account {:?}", event, account);
+    let _ = sender().send(AccountEvent { account, event });
+}
+
+pub fn subscribe() -> broadcast::Receiver<AccountEvent> {
+    sender().subscribe()
+}
+
+fn has_subscribers() -> bool {
+    sender().receiver_count() > 0
+}
+
+// Last known state of a folder
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FolderState {
+    messages: u32,
+    unseen: u32,
+    uid_next: u32,
+}
+
+async fn folder_state(session: &mut ImapSession, folder: &str) -> Result<FolderState> {
+    let mailbox = session.status(folder, "(MESSAGES UNSEEN UIDNEXT)").await?;
+    Ok(FolderState {
+        messages: mailbox.exists,
+        unseen: mailbox.unseen.unwrap_or_default(),
+        uid_next: mailbox.uid_next.unwrap_or_default(),
+    })
+}
+
+// Publish the messages that arrived in `folder` since `uid_next` was last seen
+async fn publish_new_messages(config: &Config, session: &mut ImapSession, folder: &str, uid_next: u32) -> Result<()> {
+    select_mailbox(session, folder).await?;
+    let uids: Vec<u32> = session
+        .uid_search(format!("UID {}:*", uid_next))
+        .await?
+        .into_iter()
+        // "n:*" always matches the last message, even when it is older than n
+        .filter(|&uid| uid >= uid_next)
+        .collect();
+
+    let mut messages = fetch_messages_by_uids(session, &uids).await?;
+    messages.reverse();
+    for message in messages {
+        publish(config, MailEvent::NewMessage {
+            folder: folder.to_string(),
+            uid: message.uid,
+            subject: message.subject,
+            from: message.from,
+            date: message.date,
+            thread_id: message.thread_id,
+        });
+    }
+    Ok(())
+}
+
+// Poll every folder once, publishing what changed since the previous poll
+async fn poll_folders(config: &Config, session: &mut ImapSession, states: &mut HashMap<String, FolderState>) -> Result<()> {
+    let folders: Vec<String> = session
+        .list(Some(""), Some("*"))
+        .await?
+        .map_ok(|name| name.name().to_string())
+        .try_collect()
+        .await?;
+
+    for folder in folders {
+        let state = match folder_state(session, &folder).await {
+            Ok(state) => state,
+            // Some folders (e.g. \Noselect ones) have no status
+            Err(e) => {
+                debug!("Cannot get the status of {}: {}", folder, e);
+                continue;
+            }
+        };
+
+        let previous = states.insert(folder.clone(), state);
+        let Some(previous) = previous else { continue };
+        if previous == state {
+            continue;
+        }
+
+        // Mail arrives in INBOX; elsewhere new UIDs are messages moved there, by the rules or by a client
+        if state.uid_next > previous.uid_next && folder.eq_ignore_ascii_case("INBOX") {
+            publish_new_messages(config, session, &folder, previous.uid_next).await?;
+        }
+        publish(config, MailEvent::FolderStatus {
+            folder,
+            messages: state.messages,
+            unseen: state.unseen,
+        });
+    }
+
+    Ok(())
+}
+
+/// Watch the mailboxes for changes made by other clients, polling with STATUS.
+///
+/// Polling only happens while someone is subscribed; the known state is
+/// forgotten in between, so a new subscriber does not get a burst of old changes.
+/// Returns, logging out, once `shutdown` is cancelled.
+pub async fn watch_folders(config: Config, shutdown: CancellationToken) {
+    let interval = Duration::from_secs(config.server.watch_interval.max(1));
+    let mut session: Option<ImapSession> = None;
+    let mut states: HashMap<String, FolderState> = HashMap::new();
+
+    info!("Watching the folders of {} for changes every {:?}", config.account_name(), interval);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.cancelled() => {
+                if let Some(mut open_session) = session.take() {
+                    let _ = open_session.logout().await;
+                }
+                info!("Stopped watching the folders of {}", config.account_name());
+                return;
+            }
+        }
+
+        if !has_subscribers() {
+            states.clear();
+            if let Some(mut idle_session) = session.take() {
+                let _ = idle_session.logout().await;
+            }
+            continue;
+        }
+
+        let mut current = match session.take() {
+            Some(current) => current,
+            None => match create_session(&config).await {
+                Ok(current) => current,
+                Err(e) => {
+                    error!("Folder watcher cannot connect: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        match poll_folders(&config, &mut current, &mut states).await {
+            Ok(()) => session = Some(current),
+            // Drop the session, a fresh one is opened on the next poll
+            Err(e) => error!("Folder watcher failed: {}", e),
+        }
+    }
+}
+
+struct Watcher {
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+// One watcher per account, by account name
+fn watchers() -> &'static Mutex<HashMap<String, Watcher>> {
+    static WATCHERS: OnceLock<Mutex<HashMap<String, Watcher>>> = OnceLock::new();
+    WATCHERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Start `watch_folders` in the background for every account, unless it is already running
+pub fn ensure_watching(config: &Config) {
+    let mut watchers = watchers().lock().unwrap();
+    for account in config.accounts() {
+        let name = account.account_name().to_string();
+        if watchers.get(&name).is_some_and(|running| !running.task.is_finished()) {
+            continue;
+        }
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(watch_folders(account, shutdown.clone()));
+        watchers.insert(name, Watcher { shutdown, task });
+    }
+}
+
+/// Stop the watchers started by `ensure_watching` and wait until they have logged out
+pub async fn stop_watching() {
+    let running: Vec<Watcher> = watchers().lock().unwrap().drain().map(|(_, watcher)| watcher).collect();
+    for watcher in &running {
+        watcher.shutdown.cancel();
+    }
+    for watcher in running {
+        let _ = watcher.task.await;
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Utc};
+
+/// Outcome of the last attempt to open an IMAP session
+#[derive(Debug, Clone)]
+pub struct LoginStatus {
+    pub at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+/// What the readiness check needs to know about one account
+#[derive(Debug, Clone, Default)]
+pub struct AccountHealth {
+    pub last_login: Option<LoginStatus>,
+    pub last_rule_run: Option<DateTime<Utc>>,
+}
+
+/// What the readiness check needs to know about the rest of the process
+#[derive(Debug, Clone, Default)]
+pub struct HealthState {
+    /// By account name, so that an account failing is not hidden by another one working
+    pub accounts: HashMap<String, AccountHealth>,
+    /// When the periodic rule runner was started, None unless `--periodic` is running
+    pub scheduler_started: Option<DateTime<Utc>>,
+}
+
+impl HealthState {
+    pub fn account(&self, account: &str) -> AccountHealth {
+        self.accounts.get(account).cloned().unwrap_or_default()
+    }
+}
+
+fn state() -> &'static Mutex<HealthState> {
+    static STATE: OnceLock<Mutex<HealthState>> = OnceLock::new();
+    STATE.get_or_init(|| Mutex::new(HealthState::default()))
+}
+
+pub fn record_login<T, E: Display>(account: &str, result: &Result<T, E>) {
+    state().lock().unwrap().accounts.entry(account.to_string()).or_default().last_login = Some(LoginStatus {
+        at: Utc::now(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    });
+}
+
+pub fn scheduler_started() {
+    state().lock().unwrap().scheduler_started = Some(Utc::now());
+}
+
+pub fn rule_run_succeeded(account: &str) {
+    state().lock().unwrap().accounts.entry(account.to_string()).or_default().last_rule_run = Some(Utc::now());
+}
+
+pub fn snapshot() -> HealthState {
+    state().lock().unwrap().clone()
+}
@@ -1,14 +1,21 @@
 pub mod mail_move_settings;
+pub mod scheduler;
 
 use crate::mail_move_rules::mail_move_settings::*;
-use tokio::time::Duration;
 use crate::{mail_reader::message::Message, settings::Config};
-use crate::mail_move_rules::mail_move_settings::load_mail_move_config;
-use crate::mail_reader::imap::{create_session, delete_email_with_authentication, fetch_messages, move_email_with_authentication};
-use tokio_cron_scheduler::{Job, JobScheduler};
+use crate::mail_move_rules::mail_move_settings::current_rules;
+use crate::events::{publish, MailEvent};
+use crate::settings::WebhookEvent;
+use crate::webhooks;
+use crate::metrics;
+use crate::health;
+use crate::mail_reader::imap::{create_session, delete_email_with_authentication, fetch_messages, move_email_with_authentication, move_messages_by_uids};
+use tokio_util::sync::CancellationToken;
 use log::{debug,info,error};
 use regex::Regex;
-use itertools::Itertools;
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
 
 fn sanitize_for_display(s: &str, max_chars: usize) -> String {
     s.chars()
@@ -36,13 +43,51 @@ fn match_string(string: &str, pattern: &str) -> bool {
     result
 }
 
+/// Regex matching the sender address of a From header, e.g. `(?i)(^|[<\s])john@example\.com>?$`
+pub fn sender_pattern(from: &str) -> Option<String> {
+    let sender = mailparse::addrparse(from).ok()?.extract_single_info()?;
+    Some(format!(r"(?i)(^|[<\s]){}>?$", regex::escape(&sender.addr)))
+}
+
+/// Regex matching any address of the sender's domain, e.g. `(?i)@example\.com>?$`
+pub fn domain_pattern(from: &str) -> Option<String> {
+    let sender = mailparse::addrparse(from).ok()?.extract_single_info()?;
+    let (_, domain) = sender.addr.rsplit_once('@')?;
+    Some(format!(r"(?i)@{}>?$", regex::escape(domain)))
+}
+
+/// Regex matching a mailing list identifier exactly
+pub fn list_id_pattern(list_id: &str) -> String {
+    format!("^{}$", regex::escape(list_id))
+}
+
+/// Regex matching the recurring part of a subject: a `[tag]` or `Prefix:` when
+/// there is one, otherwise the whole subject
+pub fn subject_pattern(subject: &str) -> String {
+    let subject = subject.trim();
+
+    if subject.starts_with('[') {
+        if let Some(end) = subject.find(']') {
+            return format!("^{}", regex::escape(&subject[..=end]));
+        }
+    }
+
+    if let Some((prefix, _)) = subject.split_once(':') {
+        if !prefix.is_empty() && prefix.len() <= 30 && !prefix.eq_ignore_ascii_case("re") && !prefix.eq_ignore_ascii_case("fwd") {
+            return format!("^{}:", regex::escape(prefix));
+        }
+    }
+
+    format!("^{}$", regex::escape(subject))
+}
+
 fn match_many_strings(string: &str, patterns: &[String]) -> bool {
     patterns.iter().any(|pattern| match_string(string, pattern))
 }
 
 pub fn check_message_matches(message: &Message, rule: &Rule) -> bool {
     // Early return if rule has no patterns to check (all fields are None)
-    if rule.from.is_none() && rule.title.is_none() && rule.body.is_none() && rule.user_agent.is_none() && rule.to.is_none() {
+    if rule.from.is_none() && rule.title.is_none() && rule.body.is_none() && rule.user_agent.is_none() && rule.to.is_none() && rule.list_id.is_none() {
         return false;
     }
 
@@ -83,52 +128,193 @@ pub fn check_message_matches(message: &Message, rule: &Rule) -> bool {
         }
     }
 
+    if let Some(patterns) = &rule.list_id {
+        if let Some(list_id) = &message.list_id {
+            if match_many_strings(list_id, patterns) {
+                return true;
+            }
+        }
+    }
+
     false
 }
 
+/// What happened (or would happen, in a dry run) to a message matching a rule
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RuleOutcome {
+    pub uid: Option<u32>,
+    pub message_id: Option<String>,
+    pub subject: String,
+    pub from: String,
+    /// Position of the matching rule in the rules file
+    pub rule_index: usize,
+    pub rule_name: Option<String>,
+    pub target_folder: String,
+    pub status: OutcomeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeStatus {
+    Moved,
+    WouldMove,
+    Failed,
+}
+
+/// Report of one run of the rules over the INBOX
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RunReport {
+    pub dry_run: bool,
+    /// Number of INBOX messages checked
+    pub checked: usize,
+    pub moved: usize,
+    pub failed: usize,
+    pub outcomes: Vec<RuleOutcome>,
+}
+
+/// Index of the first rule matching the message; rules are tried in file order
+pub fn first_matching_rule(message: &Message, rules: &[RuleWrapper]) -> Option<usize> {
+    rules.iter().position(|wrapper| check_message_matches(message, &wrapper.rule))
+}
+
+/// Move the recent INBOX messages to the folder of the first rule they match, or
+/// only report what would be moved when `dry_run` is set
+pub async fn run_rules(config: &Config, dry_run: bool) -> anyhow::Result<RunReport> {
+    info!("Rule application running{}", if dry_run { " (dry run)" } else { "" });
+    let rules_config = current_rules(config)?;
+    let mut imap_session = create_session(config).await?;
+
+    let messages = fetch_messages(
+        &mut imap_session,
+        "INBOX",
+        rules_config.messages_to_check
+    ).await?;
+
+    let mut report = RunReport { dry_run, checked: messages.len(), moved: 0, failed: 0, outcomes: Vec::new() };
+    for message in &messages {
+        let Some(rule_index) = first_matching_rule(message, &rules_config.rules) else {
+            continue;
+        };
+        let rule = &rules_config.rules[rule_index].rule;
+        info!("The message {:?} is matching, trying to move it", message.subject);
+
+        let result = if dry_run {
+            Ok(OutcomeStatus::WouldMove)
+        } else if let Some(id) = &message.message_id {
+            move_email_with_authentication(&mut imap_session, id.to_string(), "INBOX", &rule.target_folder)
+                .await
+                .map(|()| OutcomeStatus::Moved)
+                .map_err(|e| e.to_string())
+        } else {
+            Err("missing message ID".to_string())
+        };
+
+        let (status, error) = match result {
+            Ok(status) => (status, None),
+            Err(e) => {
+                error!("Failed to move message: {}", e);
+                webhooks::notify(config, WebhookEvent::Error, json!({
+                    "operation": "apply_rules",
+                    "message": format!("Cannot move message {:?} to {}: {}", message.message_id, rule.target_folder, e),
+                }));
+                (OutcomeStatus::Failed, Some(e))
+            }
+        };
+        match status {
+            OutcomeStatus::Moved => {
+                report.moved += 1;
+                publish(config, MailEvent::Moved {
+                    folder: "INBOX".to_string(),
+                    target_folder: rule.target_folder.clone(),
+                    uid: message.uid,
+                    message_id: message.message_id.clone(),
+                });
+            }
+            OutcomeStatus::Failed => report.failed += 1,
+            OutcomeStatus::WouldMove => {}
+        }
+
+        let outcome = RuleOutcome {
+            uid: message.uid,
+            message_id: message.message_id.clone(),
+            subject: message.subject.clone(),
+            from: message.from.clone(),
+            rule_index,
+            rule_name: rule.name.clone(),
+            target_folder: rule.target_folder.clone(),
+            status,
+            error,
+        };
+        if !dry_run {
+            metrics::rule_matched(rule_index, rule.name.as_deref(), &rule.target_folder);
+            webhooks::notify(config, WebhookEvent::RuleMatched, json!(outcome));
+        }
+        report.outcomes.push(outcome);
+    }
+
+    imap_session.logout().await?;
+    if !dry_run {
+        metrics::job_succeeded(config.account_name(), "rules");
+        health::rule_run_succeeded(config.account_name());
+    }
+    Ok(report)
+}
+
 pub async fn apply_rules(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Rule application running");
-    let rules_config = load_mail_move_config()?;
+    if let Err(e) = run_rules(config, false).await {
+        webhooks::notify(config, WebhookEvent::Error, json!({ "operation": "apply_rules", "message": e.to_string() }));
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Move the INBOX messages matching a single rule right away, returning how many were moved
+pub async fn apply_rule_to_inbox(config: &Config, rule: &Rule) -> Result<usize, Box<dyn std::error::Error>> {
+    let rules_config = current_rules(config)?;
     let mut imap_session = create_session(config).await?;
-    
+
     let messages = fetch_messages(
-        &mut imap_session, 
-        "INBOX", 
+        &mut imap_session,
+        "INBOX",
         rules_config.messages_to_check
     ).await?;
 
-    let matching_messages_and_rules: Vec<_> = messages
+    let uids: Vec<u32> = messages
         .iter()
-        .cartesian_product(&rules_config.rules)
-        .filter(|(message, rule_wrapper)| check_message_matches(message, &rule_wrapper.rule))
+        .filter(|message| check_message_matches(message, rule))
+        .filter_map(|message| message.uid)
         .collect();
 
-    for (message, rule_wrapper) in matching_messages_and_rules {
-        info!("The message {:?} is matching, trying to move it", message.subject);
-        
-        let Some(id) = &message.message_id else {
-            error!("Cannot move message to another folder: missing message ID");
-            continue;
-        };
-    
-        info!("The matching message id is {}", id);
-        if let Err(e) = move_email_with_authentication(
-            &mut imap_session, 
-            id.to_string(), 
-            "INBOX", 
-            &rule_wrapper.rule.target_folder
-        ).await {
-            error!("Failed to move message: {}", e);
+    if !uids.is_empty() {
+        move_messages_by_uids(&mut imap_session, "INBOX", &uids, &rule.target_folder).await?;
+        for &uid in &uids {
+            publish(config, MailEvent::Moved {
+                folder: "INBOX".to_string(),
+                target_folder: rule.target_folder.clone(),
+                uid: Some(uid),
+                message_id: None,
+            });
         }
     }
 
     imap_session.logout().await?;
-    Ok(())
+    Ok(uids.len())
 }
 
 pub async fn delete_spam(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    delete_spam_messages(config)
+        .await
+        .inspect(|()| metrics::job_succeeded(config.account_name(), "spam"))
+        .inspect_err(|e| {
+            webhooks::notify(config, WebhookEvent::Error, json!({ "operation": "delete_spam", "message": e.to_string() }));
+        })
+}
+
+async fn delete_spam_messages(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     info!("Deleting spam");
-    let rules_config = load_mail_move_config()?;
+    let rules_config = current_rules(config)?;
     let mut imap_session = create_session(config).await?;
     
     let messages = fetch_messages(
@@ -146,12 +332,32 @@ pub async fn delete_spam(config: &Config) -> Result<(), Box<dyn std::error::Erro
         };
     
         info!("The matching message id is {}", id);
-        if let Err(e) = delete_email_with_authentication(
+        match delete_email_with_authentication(
             &mut imap_session, 
             id.to_string(), 
             "Spam"
         ).await {
-            error!("Failed to move message: {}", e);
+            Ok(()) => {
+                publish(config, MailEvent::Deleted {
+                    folder: "Spam".to_string(),
+                    uid: message.uid,
+                    message_id: message.message_id.clone(),
+                });
+                webhooks::notify(config, WebhookEvent::SpamDeleted, json!({
+                    "folder": "Spam",
+                    "uid": message.uid,
+                    "message_id": message.message_id,
+                    "subject": message.subject,
+                    "from": message.from,
+                }));
+            }
+            Err(e) => {
+                error!("Failed to delete message: {}", e);
+                webhooks::notify(config, WebhookEvent::Error, json!({
+                    "operation": "delete_spam",
+                    "message": format!("Cannot delete message {}: {}", id, e),
+                }));
+            }
         }
     }
 
@@ -161,7 +367,7 @@ pub async fn delete_spam(config: &Config) -> Result<(), Box<dyn std::error::Erro
 
 pub async fn print_emails(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     info!("Printing e-mails");
-    let rules_config = load_mail_move_config()?;
+    let rules_config = current_rules(config)?;
     let mut imap_session = create_session(config).await?;
     
     let messages = fetch_messages(
@@ -178,30 +384,9 @@ pub async fn print_emails(config: &Config) -> Result<(), Box<dyn std::error::Err
     Ok(())
 }
 
-pub async fn entrypoint(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    let sched = JobScheduler::new().await?;
-    
-    // Clone settings for the closure
-    let config_clone = config.clone();
-    
-    // Add a job that runs every N seconds
-    sched.add(
-        Job::new_repeated_async(
-            Duration::from_secs(config.mail_mover.interval_seconds), 
-            move |_uuid, _l| {
-                let second_config_clone = config_clone.clone();
-                Box::pin(async move {
-                    let _ = apply_rules(&second_config_clone).await;
-                })
-        })?
-    ).await?;
-
-    // Start the scheduler
-    tokio::spawn(async move {
-        if let Err(e) = sched.start().await {
-            eprintln!("Scheduler error: {}", e);
-        }
-    });
-
+/// Run the jobs of `--periodic` on their schedules until `shutdown` is cancelled.
+/// Runs in progress at that moment finish their moves and log out first.
+pub async fn entrypoint(config: &Config, shutdown: CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    scheduler::run(config, shutdown).await?;
     Ok(())
-}
\ No newline at end of file
+}
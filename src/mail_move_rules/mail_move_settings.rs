@@ -1,18 +1,35 @@
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
-use log::error;
+use utoipa::ToSchema;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use log::{error, info};
 use anyhow::{anyhow, Result};
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+use crate::settings::{find_in_config_dirs, Config, CONFIG_DIR_VARIABLE};
+
+// Set by `--rules`, replacing the search for email_move_rules.yaml
+static RULES_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, ToSchema)]
 pub struct Rule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub target_folder: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub from: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub title: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub body: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user_agent: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub to: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list_id: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -26,16 +43,20 @@ pub struct RulesConfig {
     pub rules: Vec<RuleWrapper>,
 }
 
-pub fn load_mail_move_config() -> Result<RulesConfig> {
+/// Read the rules file of the account of `config`
+pub fn load_mail_move_config(config: &Config) -> Result<RulesConfig> {
     // Find the config file in multiple locations
-    let config_path = find_mail_move_config_file().ok_or_else(|| {
+    let config_path = mail_move_config_path(config).ok_or_else(|| {
         let msg = "Could not find email_move_rules.yaml in any of the expected locations";
         error!("{}", msg);
         anyhow!(msg)
     })?;
+    load_rules_file(&config_path)
+}
 
+pub fn load_rules_file(config_path: &Path) -> Result<RulesConfig> {
     // Open the file
-    let file = File::open(&config_path)
+    let file = File::open(config_path)
         .map_err(|err| {
             error!("Error opening config file at {:?}: {}", config_path, err);
             anyhow!("Cannot open config file: {}", err)
@@ -48,48 +69,189 @@ pub fn load_mail_move_config() -> Result<RulesConfig> {
         Ok(config) => Ok(config),
         Err(err) => {
             error!("Error parsing config file at {:?}: {}", config_path, err);
-            Err(anyhow!("Failed to parse YAML: {}", err))
+            Err(anyhow!("Failed to parse YAML in {}: {}", config_path.display(), err))
         }
     }
 }
 
-fn find_mail_move_config_file() -> Option<PathBuf> {
-    let mut possible_paths: Vec<PathBuf> = Vec::new();
-    
-    // 1. Current working directory
-    possible_paths.push(PathBuf::from("email_move_rules.yaml"));
-    possible_paths.push(PathBuf::from("email_move_rules.yml"));
-    
-    // 2. Config directory on Linux/Unix
-    if let Some(config_dir) = dirs::config_dir() {
-        possible_paths.push(config_dir.join("myapp").join("email_move_rules.yaml"));
-        possible_paths.push(config_dir.join("myapp").join("email_move_rules.yml"));
+impl Rule {
+    /// Every pattern list of the rule, with the name of the message field it applies to
+    pub fn pattern_fields(&self) -> [(&'static str, &Option<Vec<String>>); 6] {
+        [
+            ("from", &self.from),
+            ("title", &self.title),
+            ("body", &self.body),
+            ("user_agent", &self.user_agent),
+            ("to", &self.to),
+            ("list_id", &self.list_id),
+        ]
     }
-    
-    // 3. Config directory on Windows
-    if let Some(config_dir) = dirs::config_dir() {
-        possible_paths.push(config_dir.join("MyApp").join("email_move_rules.yaml"));
-        possible_paths.push(config_dir.join("MyApp").join("email_move_rules.yml"));
+
+    /// Check the rule can be applied, returning one message per problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.target_folder.trim().is_empty() {
+            errors.push("target_folder: must not be empty".to_string());
+        }
+
+        let mut has_patterns = false;
+        for (field, patterns) in self.pattern_fields() {
+            for pattern in patterns.iter().flatten() {
+                has_patterns = true;
+                if let Err(err) = regex::Regex::new(pattern) {
+                    errors.push(format!("{}: invalid regex '{}': {}", field, pattern, err));
+                }
+            }
+        }
+
+        if !has_patterns {
+            errors.push("the rule needs at least one pattern".to_string());
+        }
+
+        errors
     }
-    
-    // 4. Same directory as executable
-    if let Ok(exe_path) = std::env::current_exe() {
-        if let Some(exe_dir) = exe_path.parent() {
-            possible_paths.push(exe_dir.join("email_move_rules.yaml"));
-            possible_paths.push(exe_dir.join("email_move_rules.yml"));
+}
+
+impl RulesConfig {
+    /// Check every rule of the file, returning one message per problem found
+    pub fn validate(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .enumerate()
+            .flat_map(|(index, wrapper)| {
+                wrapper.rule.validate().into_iter().map(move |error| format!("rule {}: {}", index + 1, error))
+            })
+            .collect()
+    }
+
+    /// Put the rules in a new order, given as the list of their current positions
+    pub fn reorder(&mut self, order: &[usize]) -> Result<()> {
+        let mut sorted = order.to_vec();
+        sorted.sort_unstable();
+        if sorted != (0..self.rules.len()).collect::<Vec<_>>() {
+            return Err(anyhow!("order must list each rule position from 0 to {} exactly once", self.rules.len().saturating_sub(1)));
         }
+
+        self.rules = order.iter().map(|&position| self.rules[position].clone()).collect();
+        Ok(())
+    }
+}
+
+// The rules in use, by rules file, as accounts may share one
+fn active_rules() -> &'static RwLock<HashMap<PathBuf, Arc<RulesConfig>>> {
+    static ACTIVE_RULES: OnceLock<RwLock<HashMap<PathBuf, Arc<RulesConfig>>>> = OnceLock::new();
+    ACTIVE_RULES.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Rules applied by the rule runner to the account of `config`: the last version of its rules
+/// file that loaded and validated
+pub fn current_rules(config: &Config) -> Result<Arc<RulesConfig>> {
+    let active = mail_move_config_path(config).and_then(|path| active_rules().read().unwrap().get(&path).cloned());
+    match active {
+        Some(rules) => Ok(rules),
+        None => reload_rules(config),
+    }
+}
+
+/// Read the rules file of the account again and apply it from now on, unless it cannot be loaded
+/// or a rule is invalid, in which case the previous rules stay in use
+pub fn reload_rules(config: &Config) -> Result<Arc<RulesConfig>> {
+    let config_path = mail_move_config_path(config)
+        .ok_or_else(|| anyhow!("Could not find email_move_rules.yaml in any of the expected locations"))?;
+    let rules_config = load_rules_file(&config_path)?;
+    let errors = rules_config.validate();
+    if !errors.is_empty() {
+        return Err(anyhow!("Invalid rules: {}", errors.join("; ")));
+    }
+
+    let rules_config = Arc::new(rules_config);
+    active_rules().write().unwrap().insert(config_path, rules_config.clone());
+    Ok(rules_config)
+}
+
+/// Use `path` as the rules file instead of searching for one
+pub fn set_rules_path(path: PathBuf) {
+    let _ = RULES_PATH.set(path);
+}
+
+/// Path of the rules file of the account of `config`, if any: its own `rules`, or the rules file found as usual
+pub fn mail_move_config_path(config: &Config) -> Option<PathBuf> {
+    config.account.as_ref().and_then(|account| account.rules.clone()).or_else(find_mail_move_config_file)
+}
+
+/// Path the rules of the account of `config` are saved to: the file they were loaded from, or a new
+/// one in $ALMAMBET_CONFIG_DIR or the working directory
+pub fn rules_save_path(config: &Config) -> PathBuf {
+    mail_move_config_path(config).unwrap_or_else(|| {
+        std::env::var_os(CONFIG_DIR_VARIABLE).map(PathBuf::from).unwrap_or_default().join("email_move_rules.yaml")
+    })
+}
+
+// One lock per rules file, held from loading the rules to saving them again
+fn rules_file_lock(path: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = LOCKS.get_or_init(Default::default).lock().unwrap();
+    locks.entry(path.to_path_buf()).or_default().clone()
+}
+
+/// Load the rules of the account, change them with `change` and save them, without another change
+/// to the same rules file in between. Nothing is saved when `change` fails.
+pub fn update_mail_move_config<T, E: From<anyhow::Error>>(
+    config: &Config,
+    change: impl FnOnce(&mut RulesConfig) -> Result<T, E>,
+) -> Result<T, E> {
+    let lock = rules_file_lock(&rules_save_path(config));
+    let _guard = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let mut rules_config = load_mail_move_config(config)?;
+    let result = change(&mut rules_config)?;
+    save_mail_move_config(config, &rules_config)?;
+    Ok(result)
+}
+
+fn save_mail_move_config(config: &Config, rules_config: &RulesConfig) -> Result<PathBuf> {
+    let config_path = rules_save_path(config);
+
+    let yaml = yaml_serde::to_string(rules_config)
+        .map_err(|err| anyhow!("Failed to serialize rules: {}", err))?;
+
+    // Write next to the target and rename, so readers never see a half-written file
+    let temp_path = config_path.with_extension("yaml.tmp");
+    let backup_path = config_path.with_extension("yaml.bak");
+
+    let write_temp = || -> std::io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(yaml.as_bytes())?;
+        file.sync_all()
+    };
+    write_temp().map_err(|err| {
+        error!("Error writing config file at {:?}: {}", temp_path, err);
+        anyhow!("Cannot write config file: {}", err)
+    })?;
+
+    if config_path.exists() {
+        fs::copy(&config_path, &backup_path).map_err(|err| {
+            error!("Error backing up config file to {:?}: {}", backup_path, err);
+            anyhow!("Cannot back up config file: {}", err)
+        })?;
     }
-    
-    // 5. /etc directory on Linux/Unix
-    possible_paths.push(PathBuf::from("/etc/myapp/email_move_rules.yaml"));
-    possible_paths.push(PathBuf::from("/etc/myapp/email_move_rules.yml"));
-    
-    // 6. Project source directory (development fallback)
-    possible_paths.push(PathBuf::from("src/resources/email_move_rules.yaml"));
-    possible_paths.push(PathBuf::from("src/resources/email_move_rules.yml"));
-
-    // Find the first existing file
-    possible_paths
-        .into_iter()
-        .find(|path| path.exists() && path.is_file())
-}
\ No newline at end of file
+
+    fs::rename(&temp_path, &config_path).map_err(|err| {
+        error!("Error replacing config file at {:?}: {}", config_path, err);
+        anyhow!("Cannot replace config file: {}", err)
+    })?;
+
+    info!("Rules saved to {:?}, previous version kept in {:?}", config_path, backup_path);
+    if rules_config.validate().is_empty() {
+        active_rules().write().unwrap().insert(config_path.clone(), Arc::new(rules_config.clone()));
+    }
+    Ok(config_path)
+}
+
+pub fn find_mail_move_config_file() -> Option<PathBuf> {
+    if let Some(path) = RULES_PATH.get() {
+        return Some(path.clone());
+    }
+    find_in_config_dirs(&["email_move_rules.yaml", "email_move_rules.yml"])
+}
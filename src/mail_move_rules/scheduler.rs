@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use croner::parser::{CronParser, Seconds};
+use croner::Cron;
+use log::{debug, error, info, warn};
+use tokio_cron_scheduler::{Job, JobScheduler};
+use tokio_util::sync::CancellationToken;
+
+use crate::health;
+use crate::metrics;
+use crate::settings::{Config, OverlapPolicy, Schedule};
+
+// After a failed run the job pauses for this long, doubled at every consecutive failure up to the maximum
+const INITIAL_FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Something `--periodic` does on a schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    Rules,
+    Spam,
+}
+
+impl JobKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            JobKind::Rules => "rules",
+            JobKind::Spam => "spam",
+        }
+    }
+
+    async fn run(self, config: &Config) -> Result<(), String> {
+        let result = match self {
+            JobKind::Rules => super::apply_rules(config).await,
+            JobKind::Spam => super::delete_spam(config).await,
+        };
+        result.map_err(|e| e.to_string())
+    }
+}
+
+/// Parse a cron expression the way the scheduler does: with seconds, `sec min hour day month weekday [year]`
+pub fn parse_cron(expression: &str) -> Result<Cron> {
+    CronParser::builder()
+        .seconds(Seconds::Required)
+        .dom_and_dow(true)
+        .build()
+        .parse(expression)
+        .map_err(|e| anyhow!("Invalid cron expression {:?}: {}", expression, e))
+}
+
+/// Time between two runs on `schedule`, measured from now for cron expressions
+pub fn period(schedule: &Schedule) -> Result<Duration> {
+    match schedule {
+        Schedule::Every(seconds) => Ok(Duration::from_secs(*seconds)),
+        Schedule::Cron(expression) => {
+            let cron = parse_cron(expression)?;
+            let next = cron.find_next_occurrence(&Local::now(), false)?;
+            let after = cron.find_next_occurrence(&next, false)?;
+            Ok((after - next).to_std()?)
+        }
+    }
+}
+
+/// Pause after `failures` consecutive failed runs
+pub fn failure_backoff(failures: u32) -> Duration {
+    INITIAL_FAILURE_BACKOFF
+        .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_FAILURE_BACKOFF)
+}
+
+/// Run lock and failure count of one job of one account
+struct JobState {
+    kind: JobKind,
+    account: String,
+    /// Name of the job in the logs, with the account when there are several
+    label: String,
+    running: tokio::sync::Mutex<()>,
+    queued: AtomicBool,
+    failures: AtomicU32,
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl JobState {
+    fn new(kind: JobKind, config: &Config) -> Self {
+        let label = match &config.account {
+            Some(account) => format!("{} ({})", kind.name(), account.name),
+            None => kind.name().to_string(),
+        };
+        JobState {
+            kind,
+            account: config.account_name().to_string(),
+            label,
+            running: tokio::sync::Mutex::new(()),
+            queued: AtomicBool::new(false),
+            failures: AtomicU32::new(0),
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused_until.lock().unwrap().is_some_and(|until| Instant::now() < until)
+    }
+
+    fn record(&self, result: Result<(), String>) {
+        let name = &self.label;
+        match result {
+            Ok(()) => {
+                let failures = self.failures.swap(0, Ordering::SeqCst);
+                if failures > 0 {
+                    info!("The {} job succeeded again after {} failed runs", name, failures);
+                }
+                *self.paused_until.lock().unwrap() = None;
+            }
+            Err(e) => {
+                let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+                let backoff = failure_backoff(failures);
+                error!("The {} job failed ({} in a row), pausing it for {:?}: {}", name, failures, backoff, e);
+                metrics::job_failed(&self.account, self.kind.name());
+                *self.paused_until.lock().unwrap() = Some(Instant::now() + backoff);
+            }
+        }
+    }
+}
+
+// Jobs by account name and kind
+type JobStates = HashMap<(String, JobKind), Arc<JobState>>;
+
+// State of the `kind` job of the account of `config`, shared by its scheduled and on-demand runs
+fn job_state(kind: JobKind, config: &Config) -> Arc<JobState> {
+    static JOBS: OnceLock<Mutex<JobStates>> = OnceLock::new();
+    let mut jobs = JOBS.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+    jobs.entry((config.account_name().to_string(), kind))
+        .or_insert_with(|| Arc::new(JobState::new(kind, config)))
+        .clone()
+}
+
+/// Run `future`, e.g. a run requested from the web or REST interface, holding the run lock of the `kind`
+/// job of the account, so that it never overlaps a scheduled run; it waits for a run in progress to finish
+pub async fn run_exclusive<F: Future>(kind: JobKind, config: &Config, future: F) -> F::Output {
+    let job = job_state(kind, config);
+    let _running = match job.running.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            info!("The {} job is running, the requested run will start after it", job.label);
+            job.running.lock().await
+        }
+    };
+    future.await
+}
+
+// One scheduled occurrence of a job: honours the failure pause, the run lock and the jitter
+async fn trigger(job: Arc<JobState>, config: Config, shutdown: CancellationToken) {
+    let name = &job.label;
+    if job.is_paused() {
+        debug!("The {} job is paused after failures, skipping this run", name);
+        metrics::job_skipped(&job.account, job.kind.name(), "backoff");
+        return;
+    }
+
+    let _running = match job.running.try_lock() {
+        Ok(guard) => guard,
+        Err(_) if config.mail_mover.overlap == OverlapPolicy::Queue && !job.queued.swap(true, Ordering::SeqCst) => {
+            info!("The previous {} run is still going, this one will start after it", name);
+            let guard = job.running.lock().await;
+            job.queued.store(false, Ordering::SeqCst);
+            // The run it waited for may have failed
+            if job.is_paused() {
+                metrics::job_skipped(&job.account, job.kind.name(), "backoff");
+                return;
+            }
+            guard
+        }
+        Err(_) => {
+            warn!("The previous {} run is still going, skipping this one", name);
+            metrics::job_skipped(&job.account, job.kind.name(), "overlap");
+            return;
+        }
+    };
+
+    if config.mail_mover.jitter > 0 {
+        let delay = Duration::from_secs(rand::random_range(0..=config.mail_mover.jitter));
+        debug!("Starting the {} job in {:?}", name, delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+    if shutdown.is_cancelled() {
+        return;
+    }
+
+    let result = job.kind.run(&config).await;
+    job.record(result);
+}
+
+fn create_job(job: Arc<JobState>, schedule: &Schedule, config: &Config, shutdown: &CancellationToken) -> Result<Job> {
+    let config = config.clone();
+    let shutdown = shutdown.clone();
+    let run = move |_uuid, _lock| -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> {
+        Box::pin(trigger(job.clone(), config.clone(), shutdown.clone()))
+    };
+
+    let created = match schedule {
+        Schedule::Every(seconds) => Job::new_repeated_async(Duration::from_secs((*seconds).max(1)), run),
+        Schedule::Cron(expression) => {
+            // Checked first for a readable message, the scheduler only reports that parsing failed
+            parse_cron(expression)?;
+            Job::new_async_tz(expression.as_str(), Local, run)
+        }
+    };
+    created.map_err(|e| anyhow!("Cannot schedule the job: {:?}", e))
+}
+
+/// Run the scheduled jobs of every account until `shutdown` is cancelled, then wait for the runs in progress.
+///
+/// Each account has its own jobs, so they run concurrently and a failing account does not hold back the others.
+pub async fn run(config: &Config, shutdown: CancellationToken) -> Result<()> {
+    let mut sched = JobScheduler::new().await?;
+
+    let mut jobs = Vec::new();
+    for account in config.accounts() {
+        let mail_mover = &account.mail_mover;
+        let schedules = [(JobKind::Rules, Some(mail_mover.rules_schedule())), (JobKind::Spam, mail_mover.jobs.spam.clone())];
+        for (kind, schedule) in schedules {
+            let Some(schedule) = schedule else { continue };
+            let job = job_state(kind, &account);
+            let created = create_job(job.clone(), &schedule, &account, &shutdown)
+                .map_err(|e| anyhow!("The {} job: {}", job.label, e))?;
+            sched.add(created).await?;
+            info!("Scheduled the {} job: {:?}", job.label, schedule);
+            jobs.push(job);
+        }
+    }
+
+    sched.start().await?;
+    health::scheduler_started();
+
+    shutdown.cancelled().await;
+    sched.shutdown().await?;
+    for job in &jobs {
+        let _finished = job.running.lock().await;
+    }
+    info!("Periodic rule runner stopped");
+
+    Ok(())
+}
@@ -1,3 +1,10 @@
+pub mod attachment;
 pub mod encryption;
+pub mod error;
+pub mod flags;
+pub mod folders;
+pub mod html;
 pub mod message;
 pub mod imap;
+pub mod search;
+pub mod thread;
\ No newline at end of file
@@ -0,0 +1,142 @@
+use anyhow::Result;
+use async_imap::imap_proto::types::{BodyStructure, ContentEncoding};
+use mailparse::parse_mail;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Attachment metadata read from the BODYSTRUCTURE of a message, so that a
+/// single part can be downloaded with `BODY.PEEK[section]` without fetching
+/// the whole message.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AttachmentPart {
+    pub index: usize,
+    pub section: String,
+    pub filename: String,
+    pub content_type: String,
+    pub content_id: Option<String>,
+    pub size: u32,
+    #[serde(skip)]
+    encoding: String,
+}
+
+impl AttachmentPart {
+    /// Whether browsers can safely display the part inline
+    pub fn is_previewable(&self) -> bool {
+        is_previewable(&self.content_type)
+    }
+
+    /// Decode the raw section data according to its Content-Transfer-Encoding
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // Let mailparse deal with base64/quoted-printable by wrapping the data in a minimal part
+        let mut part = format!("Content-Transfer-Encoding: {}\r\n\r\n", self.encoding).into_bytes();
+        part.extend_from_slice(data);
+        Ok(parse_mail(&part)?.get_body_raw()?)
+    }
+}
+
+/// Whether a MIME part is listed as an attachment: a leaf part whose disposition is `attachment`,
+/// or a resource the HTML body references by Content-ID.
+///
+/// `Message.attachments` and `attachment_parts` both use it, so that a position in one list is the
+/// same part in the other.
+pub fn is_attachment_part(mimetype: &str, attachment_disposition: bool, has_content_id: bool) -> bool {
+    if mimetype.starts_with("multipart/") {
+        return false;
+    }
+    let is_inline_resource = has_content_id && mimetype != "text/plain" && mimetype != "text/html";
+    attachment_disposition || is_inline_resource
+}
+
+pub fn is_previewable(content_type: &str) -> bool {
+    let mimetype = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    (mimetype.starts_with("image/") && mimetype != "image/svg+xml")
+        || mimetype == "application/pdf"
+        || mimetype == "text/plain"
+}
+
+/// Build a Content-Disposition header value for downloading or previewing a part
+pub fn content_disposition(filename: &str, inline: bool) -> String {
+    let disposition = if inline { "inline" } else { "attachment" };
+    let ascii_name: String = filename
+        .chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        ascii_name,
+        urlencoding::encode(filename)
+    )
+}
+
+fn param<'a>(params: &'a Option<Vec<(std::borrow::Cow<'_, str>, std::borrow::Cow<'_, str>)>>, name: &str) -> Option<&'a str> {
+    params.as_ref()?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_ref())
+}
+
+fn encoding_name(encoding: &ContentEncoding) -> String {
+    match encoding {
+        ContentEncoding::SevenBit => "7bit".to_string(),
+        ContentEncoding::EightBit => "8bit".to_string(),
+        ContentEncoding::Binary => "binary".to_string(),
+        ContentEncoding::Base64 => "base64".to_string(),
+        ContentEncoding::QuotedPrintable => "quoted-printable".to_string(),
+        ContentEncoding::Other(other) => other.to_string(),
+    }
+}
+
+/// List the attachments of a message, in the same order as `Message.attachments`
+pub fn attachment_parts(structure: &BodyStructure) -> Vec<AttachmentPart> {
+    fn walk(structure: &BodyStructure, section: Vec<u32>, parts: &mut Vec<AttachmentPart>) {
+        let (common, other) = match structure {
+            BodyStructure::Multipart { bodies, .. } => {
+                for (i, body) in bodies.iter().enumerate() {
+                    let mut child_section = section.clone();
+                    child_section.push(i as u32 + 1);
+                    walk(body, child_section, parts);
+                }
+                return;
+            }
+            BodyStructure::Basic { common, other, .. }
+            | BodyStructure::Text { common, other, .. }
+            | BodyStructure::Message { common, other, .. } => (common, other),
+        };
+
+        let mimetype = format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+        let attachment_disposition = common.disposition
+            .as_ref()
+            .is_some_and(|disposition| disposition.ty.eq_ignore_ascii_case("attachment"));
+        if !is_attachment_part(&mimetype, attachment_disposition, other.id.is_some()) {
+            return;
+        }
+
+        let filename = common.disposition
+            .as_ref()
+            .and_then(|disposition| param(&disposition.params, "filename"))
+            .or_else(|| param(&common.ty.params, "name"))
+            .unwrap_or("unnamed_attachment")
+            .to_string();
+
+        // A message that is not multipart has its whole body in section 1
+        let section = if section.is_empty() { vec![1] } else { section };
+
+        parts.push(AttachmentPart {
+            index: parts.len(),
+            section: section.iter().map(|n| n.to_string()).collect::<Vec<_>>().join("."),
+            filename,
+            content_type: mimetype,
+            content_id: other.id
+                .as_ref()
+                .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string()),
+            size: other.octets,
+            encoding: encoding_name(&other.transfer_encoding),
+        });
+    }
+
+    let mut parts = Vec::new();
+    walk(structure, Vec::new(), &mut parts);
+    parts
+}
@@ -1,6 +1,10 @@
-use anyhow::{Result};
-use std::fs;
-use std::path::PathBuf;
+use anyhow::{anyhow, bail, Result};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
 use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm, Nonce,
@@ -8,72 +12,279 @@ use aes_gcm::{
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use rand::RngCore;
 
+use crate::settings::DEFAULT_ACCOUNT;
+
 const PASSWORD_FILE: &str = ".encrypted_password";
 const KEY_FILE: &str = ".encryption_key";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
 
-pub fn get_encryption_key() -> Result<Aes256Gcm> {
-    let key_path = PathBuf::from(KEY_FILE);
-    let key = if key_path.exists() {
-        // Read existing key
-        let key_bytes = fs::read(key_path)?;
-        Aes256Gcm::new_from_slice(&key_bytes)
-            .map_err(|e| anyhow::anyhow!("Failed to create cipher from key: {}", e))?
-    } else {
-        // Generate new key
-        let mut key_bytes = [0u8; 32];
-        rand::rng().fill_bytes(&mut key_bytes);
-        fs::write(key_path, key_bytes)?;
-        Aes256Gcm::new_from_slice(&key_bytes)
-            .map_err(|e| anyhow::anyhow!("Failed to create cipher from new key: {}", e))?
-    };
-    Ok(key)
+fn new_key() -> ([u8; KEY_LENGTH], Aes256Gcm) {
+    let mut key_bytes = [0u8; KEY_LENGTH];
+    rand::rng().fill_bytes(&mut key_bytes);
+    let key = Aes256Gcm::new_from_slice(&key_bytes).expect("the key has the length of an AES-256 key");
+    (key_bytes, key)
 }
 
-pub fn encrypt_password(password: &str) -> Result<String> {
-    let cipher = get_encryption_key()?;
-    let mut nonce_bytes = [0u8; 12];
+fn encrypt_with(cipher: &Aes256Gcm, password: &str) -> Result<String> {
+    let mut nonce_bytes = [0u8; NONCE_LENGTH];
     rand::rng().fill_bytes(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
-    
+
     let ciphertext = cipher.encrypt(nonce, password.as_bytes())
-        .map_err(|e| anyhow::anyhow!("Failed to encrypt password: {}", e))?;
-    
+        .map_err(|e| anyhow!("Failed to encrypt password: {}", e))?;
+
     let mut combined = Vec::new();
     combined.extend_from_slice(&nonce_bytes);
     combined.extend_from_slice(&ciphertext);
-    
+
     Ok(BASE64.encode(&combined))
 }
 
-pub fn decrypt_password(encrypted: &str) -> Result<String> {
-    let cipher = get_encryption_key()?;
-    let combined = BASE64.decode(encrypted)
-        .map_err(|e| anyhow::anyhow!("Failed to decode base64: {}", e))?;
-    
-    let (nonce_bytes, ciphertext) = combined.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
-    
-    let plaintext = cipher.decrypt(nonce, ciphertext)
-        .map_err(|e| anyhow::anyhow!("Failed to decrypt password: {}", e))?;
-    
-    String::from_utf8(plaintext)
-        .map_err(|e| anyhow::anyhow!("Failed to convert decrypted bytes to string: {}", e))
+// None when the text was not encrypted with `cipher`, or is not an encrypted password at all
+fn decrypt_with(cipher: &Aes256Gcm, encrypted: &str) -> Option<String> {
+    let combined = BASE64.decode(encrypted.trim()).ok()?;
+    if combined.len() < NONCE_LENGTH {
+        return None;
+    }
+    let (nonce_bytes, ciphertext) = combined.split_at(NONCE_LENGTH);
+    let plaintext = cipher.decrypt(Nonce::from_slice(nonce_bytes), ciphertext).ok()?;
+    String::from_utf8(plaintext).ok()
 }
 
-pub fn get_credentials(login: &str) -> Result<(String, String)> {
-    let password_path = PathBuf::from(PASSWORD_FILE);
-    
-    let password = if password_path.exists() {
-        // Read and decrypt stored password
-        let encrypted = fs::read_to_string(password_path)?;
-        decrypt_password(&encrypted)?
+// How to store the password of `account` again, for the error messages
+fn set_command(account: &str) -> String {
+    if account == DEFAULT_ACCOUNT {
+        "almambet credentials set".to_string()
     } else {
-        // Get new password and store it
-        let password = rpassword::prompt_password("Enter your password: ")?;
-        let encrypted = encrypt_password(&password)?;
-        fs::write(password_path, encrypted)?;
-        password
-    };
-    
-    Ok((login.to_string(), password))
-} 
\ No newline at end of file
+        format!("almambet credentials set --account {}", account)
+    }
+}
+
+fn temp_file(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+// A new file only its owner can read, failing when `path` exists
+fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+// Write `contents` to the temporary file of `path`, readable only by its owner
+fn write_private_temp(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp_path = temp_file(path);
+    // A file left by an interrupted write may have other permissions
+    if temp_path.exists() {
+        fs::remove_file(&temp_path)?;
+    }
+    create_private(&temp_path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// The key file and the password files of the accounts, kept together in one directory
+pub struct CredentialStore {
+    dir: PathBuf,
+}
+
+impl CredentialStore {
+    /// Files in `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        CredentialStore { dir: dir.into() }
+    }
+
+    /// Files in the working directory, where the application keeps them
+    pub fn current() -> Self {
+        CredentialStore::new(PathBuf::new())
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(KEY_FILE)
+    }
+
+    /// File holding the encrypted password of `account`; the default account keeps the original name
+    fn password_file(&self, account: &str) -> PathBuf {
+        if account == DEFAULT_ACCOUNT {
+            self.dir.join(PASSWORD_FILE)
+        } else {
+            self.dir.join(format!("{}.{}", PASSWORD_FILE, account))
+        }
+    }
+
+    // The key in use, or None when there is no key file yet
+    fn load_key(&self) -> Result<Option<Aes256Gcm>> {
+        let key_path = self.key_path();
+        if !key_path.exists() {
+            return Ok(None);
+        }
+        let key_bytes = fs::read(key_path)?;
+        if key_bytes.len() != KEY_LENGTH {
+            bail!("{} is not a valid key: it has {} bytes instead of {}", KEY_FILE, key_bytes.len(), KEY_LENGTH);
+        }
+        let key = Aes256Gcm::new_from_slice(&key_bytes)
+            .map_err(|e| anyhow!("Failed to create cipher from key: {}", e))?;
+        Ok(Some(key))
+    }
+
+    /// The key in use, created when there is none yet
+    pub fn encryption_key(&self) -> Result<Aes256Gcm> {
+        // The accounts log in at the same time, and only one of them may create the key
+        static CREATING: Mutex<()> = Mutex::new(());
+        let _creating = CREATING.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(key) = self.load_key()? {
+            return Ok(key);
+        }
+        // Generate new key
+        let (key_bytes, key) = new_key();
+        match create_private(&self.key_path()) {
+            Ok(mut file) => file.write_all(&key_bytes)?,
+            // Another process created one in the meantime
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return self.load_key()?.ok_or_else(|| anyhow!("{} was removed while it was created", KEY_FILE));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(key)
+    }
+
+    /// Whether a password is stored for `account`, so that logging in will not prompt for one
+    pub fn has_password(&self, account: &str) -> bool {
+        self.password_file(account).exists()
+    }
+
+    /// The password stored for `account`, if any.
+    ///
+    /// Fails with an explanation, rather than a bare decryption error, when the password file
+    /// does not go with the key file.
+    pub fn password(&self, account: &str) -> Result<Option<String>> {
+        let password_path = self.password_file(account);
+        if !password_path.exists() {
+            return Ok(None);
+        }
+        let Some(cipher) = self.load_key()? else {
+            bail!(
+                "{} is stored but the key it was encrypted with, {}, is missing; run `{}` to store the password again",
+                password_path.display(), KEY_FILE, set_command(account)
+            );
+        };
+
+        // Read and decrypt stored password
+        let encrypted = fs::read_to_string(&password_path)?;
+        match decrypt_with(&cipher, &encrypted) {
+            Some(password) => Ok(Some(password)),
+            None => bail!(
+                "{} cannot be decrypted with {}: the two files do not belong together (the key was replaced, or one \
+                of them was copied from another installation); run `{}` to store the password again",
+                password_path.display(), KEY_FILE, set_command(account)
+            ),
+        }
+    }
+
+    /// Encrypt and store the password of `account`, replacing the previous one
+    pub fn store(&self, account: &str, password: &str) -> Result<()> {
+        let encrypted = encrypt_with(&self.encryption_key()?, password)?;
+        let password_path = self.password_file(account);
+        write_private_temp(&password_path, encrypted.as_bytes())?;
+        fs::rename(temp_file(&password_path), password_path)?;
+        Ok(())
+    }
+
+    /// Forget the password of `account`; false when none was stored
+    pub fn clear(&self, account: &str) -> Result<bool> {
+        let password_path = self.password_file(account);
+        if !password_path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(password_path)?;
+        Ok(true)
+    }
+
+    // Every stored password file: `.encrypted_password` and the `.encrypted_password.<account>` ones
+    fn password_files(&self) -> Result<Vec<PathBuf>> {
+        let prefix = format!("{}.", PASSWORD_FILE);
+        let dir = if self.dir.as_os_str().is_empty() { Path::new(".") } else { self.dir.as_path() };
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let is_account_file = name.strip_prefix(&prefix).is_some_and(|account| !account.ends_with(".tmp"));
+            if name == PASSWORD_FILE || is_account_file {
+                files.push(self.dir.join(name));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Replace the key with a new one and encrypt every stored password with it, returning the
+    /// password files. Nothing changes when one of them cannot be decrypted with the current key.
+    pub fn rotate_key(&self) -> Result<Vec<PathBuf>> {
+        let files = self.password_files()?;
+        let old_key = self.load_key()?;
+        let mut passwords = Vec::new();
+        for path in &files {
+            let Some(old_key) = &old_key else {
+                bail!("{} is stored but {} is missing, so it cannot be encrypted again; clear it first", path.display(), KEY_FILE);
+            };
+            let encrypted = fs::read_to_string(path)?;
+            let password = decrypt_with(old_key, &encrypted).ok_or_else(|| {
+                anyhow!("{} cannot be decrypted with {}, so nothing was changed; clear or set it first", path.display(), KEY_FILE)
+            })?;
+            passwords.push(password);
+        }
+
+        // Everything is written next to the current files first, then renamed over them
+        let (key_bytes, new_key) = new_key();
+        let key_path = self.key_path();
+        write_private_temp(&key_path, &key_bytes)?;
+        for (path, password) in files.iter().zip(&passwords) {
+            write_private_temp(path, encrypt_with(&new_key, password)?.as_bytes())?;
+        }
+        for path in std::iter::once(&key_path).chain(&files) {
+            fs::rename(temp_file(path), path)?;
+        }
+        Ok(files)
+    }
+}
+
+/// Whether a password is stored for `account`, so that logging in will not prompt for one
+pub fn has_stored_password(account: &str) -> bool {
+    CredentialStore::current().has_password(account)
+}
+
+/// The password stored for `account`, if any; see `CredentialStore::password`
+pub fn stored_password(account: &str) -> Result<Option<String>> {
+    CredentialStore::current().password(account)
+}
+
+/// Encrypt and store the password of `account`, replacing the previous one
+pub fn store_password(account: &str, password: &str) -> Result<()> {
+    CredentialStore::current().store(account, password)
+}
+
+/// Forget the password of `account`; false when none was stored
+pub fn clear_password(account: &str) -> Result<bool> {
+    CredentialStore::current().clear(account)
+}
+
+/// The password of `account`: the stored one, or else one typed in for `login`.
+///
+/// The flag tells that it was typed in: it is not stored yet, so that a mistyped password is
+/// not kept, and the caller stores it with `store_password` once a login succeeded with it.
+pub fn get_password(account: &str, login: &str) -> Result<(String, bool)> {
+    if let Some(password) = stored_password(account)? {
+        return Ok((password, false));
+    }
+    let password = rpassword::prompt_password(format!("Enter the password of {}: ", login))?;
+    Ok((password, true))
+}
+
+/// Replace the key of the working directory; see `CredentialStore::rotate_key`
+pub fn rotate_key() -> Result<Vec<PathBuf>> {
+    CredentialStore::current().rotate_key()
+}
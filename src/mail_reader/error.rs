@@ -0,0 +1,37 @@
+use std::fmt;
+
+/// Failures of the IMAP layer that callers need to tell apart, e.g. to answer
+/// with the right HTTP status. They travel inside `anyhow::Error` and are
+/// recovered with `downcast_ref`.
+#[derive(Debug)]
+pub enum ImapError {
+    /// The server could not be reached (DNS, TCP or TLS failure, or lost connection)
+    Unreachable(String),
+    /// The server rejected the credentials
+    Authentication(String),
+    /// The mailbox does not exist or cannot be selected
+    MailboxNotFound(String),
+    /// No message with this UID in the mailbox
+    MessageNotFound { mailbox: String, uid: u32 },
+    /// The server refused a change, e.g. creating a mailbox that already exists
+    Rejected(String),
+    /// The server lacks an extension the operation needs to be done safely
+    Unsupported(String),
+}
+
+impl fmt::Display for ImapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImapError::Unreachable(reason) => write!(f, "IMAP server unreachable: {}", reason),
+            ImapError::Authentication(reason) => write!(f, "IMAP authentication failed: {}", reason),
+            ImapError::MailboxNotFound(mailbox) => write!(f, "Mailbox '{}' not found", mailbox),
+            ImapError::MessageNotFound { mailbox, uid } => {
+                write!(f, "Message with UID {} not found in mailbox '{}'", uid, mailbox)
+            }
+            ImapError::Rejected(reason) => write!(f, "The IMAP server refused the change: {}", reason),
+            ImapError::Unsupported(reason) => write!(f, "The IMAP server does not support this: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ImapError {}
@@ -0,0 +1,65 @@
+use anyhow::{bail, Result};
+use async_imap::types::Flag;
+
+pub const SEEN: &str = "\\Seen";
+pub const FLAGGED: &str = "\\Flagged";
+
+// Flags defined by RFC 3501 that clients may set
+const SYSTEM_FLAGS: [&str; 5] = ["\\Seen", "\\Answered", "\\Flagged", "\\Deleted", "\\Draft"];
+
+/// IMAP name of a flag, e.g. `\Seen` or a keyword such as `$Label1`
+pub fn flag_name(flag: &Flag) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_string(),
+        Flag::Answered => "\\Answered".to_string(),
+        Flag::Flagged => "\\Flagged".to_string(),
+        Flag::Deleted => "\\Deleted".to_string(),
+        Flag::Draft => "\\Draft".to_string(),
+        Flag::Recent => "\\Recent".to_string(),
+        Flag::MayCreate => "\\*".to_string(),
+        Flag::Custom(name) => name.to_string(),
+    }
+}
+
+/// Keywords are the user or server defined flags, i.e. the ones without a backslash
+pub fn is_keyword(flag: &str) -> bool {
+    !flag.starts_with('\\')
+}
+
+/// Check a flag can be sent in a STORE command: a settable system flag, or a
+/// keyword made only of IMAP atom characters
+pub fn validate_flag(flag: &str) -> Result<()> {
+    if flag.starts_with('\\') {
+        if !SYSTEM_FLAGS.iter().any(|system| system.eq_ignore_ascii_case(flag)) {
+            bail!("Unknown system flag '{}'", flag);
+        }
+        return Ok(());
+    }
+
+    let is_atom_char = |c: char| c.is_ascii_graphic() && !"(){%*\"\\]".contains(c);
+    if flag.is_empty() || !flag.chars().all(is_atom_char) {
+        bail!("Invalid keyword '{}': use letters, digits and symbols without spaces", flag);
+    }
+    Ok(())
+}
+
+/// Build a STORE query adding (`add`) or removing flags, e.g. `+FLAGS (\Seen $Work)`
+pub fn store_query(add: bool, flags: &[String]) -> Result<String> {
+    for flag in flags {
+        validate_flag(flag)?;
+    }
+    if flags.is_empty() {
+        bail!("No flags given");
+    }
+
+    Ok(format!("{}FLAGS ({})", if add { "+" } else { "-" }, flags.join(" ")))
+}
+
+/// Build a STORE query replacing every flag of a message, e.g. `FLAGS (\Seen)`;
+/// an empty list clears them
+pub fn replace_query(flags: &[String]) -> Result<String> {
+    for flag in flags {
+        validate_flag(flag)?;
+    }
+    Ok(format!("FLAGS ({})", flags.join(" ")))
+}
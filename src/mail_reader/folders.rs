@@ -0,0 +1,83 @@
+use anyhow::Result;
+use futures::TryStreamExt;
+use log::info;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::mail_reader::error::ImapError;
+use crate::mail_reader::imap::ImapSession;
+use crate::metrics::timed;
+
+/// Counters of a mailbox, as returned by STATUS
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FolderStatus {
+    pub name: String,
+    pub messages: u32,
+    pub unseen: u32,
+    pub uid_next: Option<u32>,
+    pub uid_validity: Option<u32>,
+}
+
+// Report a NO or BAD answer as a refused change instead of a server failure
+fn rejected(error: async_imap::error::Error) -> anyhow::Error {
+    match error {
+        async_imap::error::Error::No(reason) | async_imap::error::Error::Bad(reason) => {
+            ImapError::Rejected(reason).into()
+        }
+        other => other.into(),
+    }
+}
+
+/// Names of every mailbox of the account
+pub async fn list_folders(session: &mut ImapSession) -> Result<Vec<String>> {
+    let folders = timed("list", async {
+        session
+            .list(Some(""), Some("*"))
+            .await?
+            .map_ok(|name| name.name().to_string())
+            .try_collect()
+            .await
+    }).await?;
+    Ok(folders)
+}
+
+// Fail with MailboxNotFound unless the mailbox exists
+async fn ensure_folder_exists(session: &mut ImapSession, name: &str) -> Result<()> {
+    if !list_folders(session).await?.iter().any(|folder| folder == name) {
+        return Err(ImapError::MailboxNotFound(name.to_string()).into());
+    }
+    Ok(())
+}
+
+pub async fn folder_status(session: &mut ImapSession, name: &str) -> Result<FolderStatus> {
+    ensure_folder_exists(session, name).await?;
+    let mailbox = timed("status", session.status(name, "(MESSAGES UNSEEN UIDNEXT UIDVALIDITY)")).await?;
+    Ok(FolderStatus {
+        name: name.to_string(),
+        messages: mailbox.exists,
+        unseen: mailbox.unseen.unwrap_or_default(),
+        uid_next: mailbox.uid_next,
+        uid_validity: mailbox.uid_validity,
+    })
+}
+
+pub async fn create_folder(session: &mut ImapSession, name: &str) -> Result<()> {
+    session.create(name).await.map_err(rejected)?;
+    info!("Created folder {}", name);
+    Ok(())
+}
+
+pub async fn rename_folder(session: &mut ImapSession, name: &str, new_name: &str) -> Result<()> {
+    ensure_folder_exists(session, name).await?;
+    session.rename(name, new_name).await.map_err(rejected)?;
+    info!("Renamed folder {} to {}", name, new_name);
+    Ok(())
+}
+
+/// Delete a mailbox together with the messages it contains
+pub async fn delete_folder(session: &mut ImapSession, name: &str) -> Result<()> {
+    ensure_folder_exists(session, name).await?;
+    session.delete(name).await.map_err(rejected)?;
+    info!("Deleted folder {}", name);
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use ammonia::Builder;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Result of sanitizing the HTML body of an e-mail
+#[derive(Debug, Clone)]
+pub struct SanitizedHtml {
+    pub html: String,
+    /// Number of remote resources (images, trackers) that were removed
+    pub blocked_remote: usize,
+}
+
+fn is_remote_url(url: &str) -> bool {
+    let url = url.trim_start().to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("//")
+}
+
+/// Sanitize an HTML e-mail body so it can be displayed safely.
+///
+/// Scripts, styles, forms and event handler attributes are removed. Remote
+/// images are dropped unless `allow_remote` is set, and `cid:` references to
+/// inline parts are rewritten to `{cid_base_url}/{content-id}`.
+pub fn sanitize_email_html(html: &str, allow_remote: bool, cid_base_url: &str) -> SanitizedHtml {
+    let blocked = Arc::new(AtomicUsize::new(0));
+    let blocked_in_filter = Arc::clone(&blocked);
+    let cid_base_url = cid_base_url.trim_end_matches('/').to_string();
+
+    let html = Builder::default()
+        .add_url_schemes(&["cid"])
+        .link_rel(Some("noopener noreferrer"))
+        .attribute_filter(move |element, attribute, value| {
+            if element != "img" || attribute != "src" {
+                return Some(value.into());
+            }
+
+            if let Some(content_id) = value.strip_prefix("cid:") {
+                let url = format!("{}/{}", cid_base_url, urlencoding::encode(content_id));
+                return Some(Cow::Owned(url));
+            }
+
+            if is_remote_url(value) && !allow_remote {
+                blocked_in_filter.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+
+            Some(value.into())
+        })
+        .clean(html)
+        .to_string();
+
+    SanitizedHtml {
+        html,
+        blocked_remote: blocked.load(Ordering::Relaxed),
+    }
+}
+
+/// Content-Security-Policy sent along with sanitized e-mail bodies, as a
+/// second line of defence in case something slips through the sanitizer.
+pub fn content_security_policy(allow_remote: bool) -> &'static str {
+    if allow_remote {
+        "default-src 'none'; img-src 'self' data: http: https:; style-src 'unsafe-inline'; sandbox allow-popups allow-popups-to-escape-sandbox"
+    } else {
+        "default-src 'none'; img-src 'self' data:; style-src 'unsafe-inline'; sandbox allow-popups allow-popups-to-escape-sandbox"
+    }
+}
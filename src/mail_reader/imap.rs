@@ -1,5 +1,6 @@
 use anyhow::{Result,Error};
 use async_imap::{Client, Session};
+use async_imap::imap_proto::types::SectionPath;
 use futures::TryStreamExt;
 use tokio::net::TcpStream;
 use tokio::time::sleep;
@@ -7,11 +8,19 @@ use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 use std::{cmp::Ordering, error::Error as StdError, time::Duration};
 use chrono::DateTime;
 
+use crate::mail_reader::attachment::{attachment_parts, AttachmentPart};
+use crate::mail_reader::error::ImapError;
+use crate::mail_reader::flags::flag_name;
+use crate::mail_reader::folders::list_folders;
 use crate::mail_reader::message::Message;
-use crate::settings::Config;
+use crate::mail_reader::search::{quote, SearchQuery};
+use crate::mail_reader::thread::{assign_thread_ids, normalize_subject, synthetic_id_uid};
+use crate::settings::{Config, ImapConfig};
 use crate::mail_reader::encryption;
+use crate::metrics::{self, timed};
+use crate::health;
 use log::{debug, info, error, warn};
-use futures::StreamExt;  // For the stream's next() method
+use itertools::Itertools;
 
 use super::message::fetch_to_message;
 
@@ -20,9 +29,11 @@ pub type ImapSession = Session<Compat<tokio_native_tls::TlsStream<tokio::net::Tc
 // Establish a TLS-encrypted connection to the IMAP server
 async fn connect_to_server(server: &str, port: u16) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
     let imap_addr = (server, port);
-    let tcp_stream = TcpStream::connect(imap_addr).await?;
-    let tls = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
-    let tls_stream = tls.connect(server, tcp_stream).await?;
+    let tls_stream = timed("connect", async {
+        let tcp_stream = TcpStream::connect(imap_addr).await?;
+        let tls = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        anyhow::Ok(tls.connect(server, tcp_stream).await?)
+    }).await?;
     
     info!("connected to {}:{}", server, port);
     Ok(tls_stream)
@@ -34,8 +45,7 @@ async fn login_to_server(
     username: &str, 
     password: &str
 ) -> Result<Session<Compat<tokio_native_tls::TlsStream<TcpStream>>>> {
-    let imap_session = client
-        .login(username, password)
+    let imap_session = timed("login", client.login(username, password))
         .await
         .map_err(|e| e.0)?;
     
@@ -43,13 +53,31 @@ async fn login_to_server(
     Ok(imap_session)
 }
 
-// Calculate the range string for fetching the most recent messages
-fn calculate_message_range(total_messages: u32, count: u32) -> String {
-    let start = if total_messages > count { total_messages - count + 1 } else { 1 };
-    format!("{}:{}", start, total_messages)
+// Calculate the range string for fetching the most recent messages, skipping the
+// `offset` newest ones; None when there is nothing to fetch
+fn calculate_message_range(total_messages: u32, offset: u32, count: u32) -> Option<String> {
+    if count == 0 || offset >= total_messages {
+        return None;
+    }
+    let end = total_messages - offset;
+    let start = if end > count { end - count + 1 } else { 1 };
+    Some(format!("{}:{}", start, end))
+}
+
+/// Select a mailbox, reporting a mailbox the server refuses as `ImapError::MailboxNotFound`
+pub async fn select_mailbox(session: &mut ImapSession, mailbox: &str) -> Result<async_imap::types::Mailbox> {
+    timed("select", session.select(mailbox)).await.map_err(|e| match e {
+        async_imap::error::Error::No(_) | async_imap::error::Error::Bad(_) => {
+            ImapError::MailboxNotFound(mailbox.to_string()).into()
+        }
+        async_imap::error::Error::Io(_) | async_imap::error::Error::ConnectionLost => {
+            ImapError::Unreachable(e.to_string()).into()
+        }
+        other => other.into(),
+    })
 }
 
-pub fn sort_messages_by_date_desc(messages: &mut Vec<Message>) {
+pub fn sort_messages_by_date_desc(messages: &mut [Message]) {
     messages.sort_by(|a, b| {
         let a_date = DateTime::parse_from_rfc2822(&a.date).ok();
         let b_date = DateTime::parse_from_rfc2822(&b.date).ok();
@@ -63,11 +91,21 @@ pub fn sort_messages_by_date_desc(messages: &mut Vec<Message>) {
     });
 }
 
-// Fetch and process messages from the given mailbox with retry logic
+// Fetch and process the most recent messages of the given mailbox with retry logic
 pub async fn fetch_messages(
     session: &mut Session<Compat<tokio_native_tls::TlsStream<TcpStream>>>,
     mailbox: &str,
     count: u32,
+) -> Result<Vec<Message>> {
+    fetch_messages_page(session, mailbox, 0, count).await
+}
+
+// Fetch `count` messages of the mailbox, newest first, after skipping the `offset` newest ones
+pub async fn fetch_messages_page(
+    session: &mut ImapSession,
+    mailbox: &str,
+    offset: u32,
+    count: u32,
 ) -> Result<Vec<Message>> {
     const MAX_RETRIES: u32 = 3;
     const INITIAL_BACKOFF_MS: u64 = 500;
@@ -78,7 +116,7 @@ pub async fn fetch_messages(
     while attempt < MAX_RETRIES {
         attempt += 1;
         
-        match fetch_messages_internal(session, mailbox, count).await {
+        match fetch_messages_internal(session, mailbox, offset, count).await {
             Ok(messages) => {
                 if attempt > 1 {
                     info!("Successfully fetched messages on attempt {}", attempt);
@@ -93,6 +131,7 @@ pub async fn fetch_messages(
                 }
                 
                 let backoff = Duration::from_millis(INITIAL_BACKOFF_MS * 2u64.pow(attempt - 1));
+                metrics::fetch_retried();
                 warn!(
                     "Failed to fetch messages (attempt {}/{}): {}. Retrying in {:?}...",
                     attempt, MAX_RETRIES, e, backoff
@@ -112,17 +151,22 @@ pub async fn fetch_messages(
 async fn fetch_messages_internal(
     session: &mut Session<Compat<tokio_native_tls::TlsStream<TcpStream>>>,
     mailbox: &str,
+    offset: u32,
     count: u32,
 ) -> Result<Vec<Message>> {
-    let mailbox_data = session.select(mailbox).await?;
+    let mailbox_data = select_mailbox(session, mailbox).await?;
     info!("{} selected", mailbox);
     
     let total_messages = mailbox_data.exists;
-    let range = calculate_message_range(total_messages, count);
+    let Some(range) = calculate_message_range(total_messages, offset, count) else {
+        return Ok(Vec::new());
+    };
     
-    // Fetch both headers and body
-    let messages_stream = session.fetch(&range, "(RFC822 BODY.PEEK[])").await?;
-    let messages: Vec<_> = messages_stream.try_collect().await?;
+    // Fetch flags and the whole message; PEEK leaves the read state untouched
+    let messages: Vec<_> = timed("fetch", async {
+        session.fetch(&range, "(UID FLAGS BODY.PEEK[])").await?.try_collect().await
+    }).await?;
+    metrics::messages_fetched(messages.len());
     
     let mut successful_results: Vec<Message> = messages
         .iter()
@@ -131,10 +175,182 @@ async fn fetch_messages_internal(
 
     // Sort by date descending
     sort_messages_by_date_desc(&mut successful_results);
+    assign_thread_ids(&mut successful_results);
     
     Ok(successful_results)
 }
 
+// Fetch the messages with the given UIDs from the currently selected mailbox
+pub async fn fetch_messages_by_uids(
+    session: &mut ImapSession,
+    uids: &[u32],
+) -> Result<Vec<Message>> {
+    if uids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let uid_set = uids.iter().join(",");
+    let messages: Vec<_> = timed("uid_fetch", async {
+        session.uid_fetch(&uid_set, "(UID FLAGS BODY.PEEK[])").await?.try_collect().await
+    }).await?;
+    metrics::messages_fetched(messages.len());
+
+    let mut successful_results: Vec<Message> = messages
+        .iter()
+        .filter_map(|message| fetch_to_message(message).ok())
+        .collect();
+
+    sort_messages_by_date_desc(&mut successful_results);
+    assign_thread_ids(&mut successful_results);
+
+    Ok(successful_results)
+}
+
+pub async fn fetch_message_by_uid(
+    session: &mut ImapSession,
+    mailbox: &str,
+    uid: u32,
+) -> Result<Option<Message>> {
+    select_mailbox(session, mailbox).await?;
+    let messages = fetch_messages_by_uids(session, &[uid]).await?;
+    Ok(messages.into_iter().next())
+}
+
+// List the attachments of a message from its BODYSTRUCTURE, without downloading them
+pub async fn fetch_attachment_parts(
+    session: &mut ImapSession,
+    mailbox: &str,
+    uid: u32,
+) -> Result<Vec<AttachmentPart>> {
+    select_mailbox(session, mailbox).await?;
+
+    let fetches: Vec<_> = session
+        .uid_fetch(uid.to_string(), "(UID BODYSTRUCTURE)")
+        .await?
+        .try_collect()
+        .await?;
+
+    let structure = fetches
+        .iter()
+        .find_map(|fetch| fetch.bodystructure())
+        .ok_or_else(|| ImapError::MessageNotFound { mailbox: mailbox.to_string(), uid })?;
+
+    Ok(attachment_parts(structure))
+}
+
+// Download and decode a single attachment with BODY.PEEK[section]; the mailbox must be selected
+pub async fn fetch_attachment_content(
+    session: &mut ImapSession,
+    uid: u32,
+    part: &AttachmentPart,
+) -> Result<Vec<u8>> {
+    let fetches: Vec<_> = session
+        .uid_fetch(uid.to_string(), format!("(UID BODY.PEEK[{}])", part.section))
+        .await?
+        .try_collect()
+        .await?;
+
+    let section_path = SectionPath::Part(
+        part.section.split('.').filter_map(|n| n.parse().ok()).collect(),
+        None,
+    );
+    let data = fetches
+        .iter()
+        .find_map(|fetch| fetch.section(&section_path))
+        .ok_or_else(|| anyhow::anyhow!("Section {} of message {} is empty", part.section, uid))?;
+
+    part.decode(data)
+}
+
+// A search key matching any of `keys`, None when there is none
+fn any_of(keys: &[String]) -> Option<String> {
+    let (last, others) = keys.split_last()?;
+    Some(others.iter().rev().fold(last.clone(), |matched, key| format!("OR {} {}", key, matched)))
+}
+
+// UIDs of the messages with one of the `ids` as Message-ID or among their References or In-Reply-To
+async fn search_related(session: &mut ImapSession, ids: &[String], subject: Option<&str>) -> Result<Vec<u32>> {
+    let mut keys: Vec<String> = ids
+        .iter()
+        .flat_map(|id| {
+            let id = quote(id);
+            [format!("HEADER Message-ID {}", id), format!("HEADER References {}", id), format!("HEADER In-Reply-To {}", id)]
+        })
+        .collect();
+    if let Some(subject) = subject.filter(|subject| !subject.is_empty()) {
+        keys.push(format!("SUBJECT {}", quote(subject)));
+    }
+    let Some(criteria) = any_of(&keys) else {
+        return Ok(Vec::new());
+    };
+    let uids = timed("uid_search", session.uid_search(format!("CHARSET UTF-8 {}", criteria))).await?;
+    Ok(uids.into_iter().collect())
+}
+
+// Fetch the candidates, thread them like the message list does and keep the conversation `thread_id`
+async fn fetch_thread_members(session: &mut ImapSession, uids: &[u32], thread_id: &str) -> Result<Vec<Message>> {
+    let messages = fetch_messages_by_uids(session, uids).await?;
+    Ok(messages
+        .into_iter()
+        .filter(|message| message.thread_id.as_deref() == Some(thread_id))
+        .collect())
+}
+
+// Fetch every message of the mailbox belonging to the conversation `thread_id`, as threaded by the message list.
+//
+// The messages referencing the thread id are looked for first; the conversation found is then widened with the
+// replies to any of its messages and the messages with the same subject, which the threading may group with it.
+pub async fn fetch_thread(
+    session: &mut ImapSession,
+    mailbox: &str,
+    thread_id: &str,
+) -> Result<Vec<Message>> {
+    select_mailbox(session, mailbox).await?;
+
+    let mut uids = search_related(session, &[thread_id.to_string()], None).await?;
+    uids.extend(synthetic_id_uid(thread_id));
+    uids.sort_unstable();
+    uids.dedup();
+    let members = fetch_thread_members(session, &uids, thread_id).await?;
+    let Some(oldest) = members.last() else {
+        return Ok(members);
+    };
+
+    let subject = normalize_subject(&oldest.subject);
+    let ids: Vec<String> = members.iter().filter_map(|message| message.message_id.clone()).collect();
+    let mut wider = search_related(session, &ids, Some(&subject)).await?;
+    wider.extend(uids);
+    wider.sort_unstable();
+    wider.dedup();
+    fetch_thread_members(session, &wider, thread_id).await
+}
+
+// Run a UID SEARCH on the mailbox and fetch the most recent matching messages
+pub async fn search_messages(
+    session: &mut ImapSession,
+    mailbox: &str,
+    query: &SearchQuery,
+    offset: u32,
+    count: u32,
+) -> Result<Vec<Message>> {
+    select_mailbox(session, mailbox).await?;
+    debug!("searching {} for {}", mailbox, query.to_imap());
+
+    let uids = timed("uid_search", session.uid_search(query.to_imap())).await?;
+
+    // UIDs grow with arrival order, so the highest ones are the most recent
+    let recent_uids: Vec<u32> = uids
+        .into_iter()
+        .sorted_unstable()
+        .rev()
+        .skip(offset as usize)
+        .take(count as usize)
+        .collect();
+
+    info!("{} messages found in {}", recent_uids.len(), mailbox);
+    fetch_messages_by_uids(session, &recent_uids).await
+}
+
 // Determine if an error is retryable
 fn is_retryable_error(error: &anyhow::Error) -> bool {
     let error_string = error.to_string().to_lowercase();
@@ -151,64 +367,42 @@ fn is_retryable_error(error: &anyhow::Error) -> bool {
     error_string.contains("io error")
 }
 
-pub async fn find_message_by_id(
+pub async fn move_message_by_message_id(
     session: &mut Session<Compat<tokio_native_tls::TlsStream<TcpStream>>>,
     message_id: &str,
     source_mailbox: &str,
-) -> Result<Option<Message>> {
-    // First, select the source mailbox to ensure we're in the right folder
-    session.select(source_mailbox).await?;
-
-    // Search for the message by its Message-ID header
-    let messages = session.search(format!("HEADER Message-ID {}", message_id)).await?;
+    target_mailbox: &str,
+) -> Result<()> {
+    debug!("move_message_by_message_id message_id {} source {} target {}", message_id, source_mailbox, target_mailbox);
 
-    if messages.is_empty() {
-        return Ok(None);
-    }
-    
-    // Get the first message ID from the search results
-    let first_message_id = messages.iter().next().expect("Non-empty messages but couldn't get first").to_string();
-    
-    // Fetch the first matching message
-    let mut fetch_result = session.fetch(first_message_id, "RFC822").await?;
-    
-    // Extract the message body from the fetch result
-    let option_result_fetch = fetch_result.next().await;
-
-    match option_result_fetch {
-        Some(result_fetch) => match result_fetch{
-            Ok(fetch) => Ok(Some(fetch_to_message(&fetch).expect("Error"))),
-            Err(_) => Ok(None),
-        },
-        None => Ok(None),
-    }
+    let result = move_by_message_id(session, message_id, source_mailbox, target_mailbox).await;
+    metrics::message_operation("move", 1, &result);
+    result
 }
 
-pub async fn move_message_by_message_id(
-    session: &mut Session<Compat<tokio_native_tls::TlsStream<TcpStream>>>,
+async fn move_by_message_id(
+    session: &mut ImapSession,
     message_id: &str,
     source_mailbox: &str,
     target_mailbox: &str,
 ) -> Result<()> {
-    debug!("move_message_by_message_id message_id {} source {} target {}", message_id, source_mailbox, target_mailbox);
-
     // First, select the INBOX to ensure we're in the right folder
-    session.select(source_mailbox).await?;
+    select_mailbox(session, source_mailbox).await?;
     
     // Search for the message by its Message-ID header
-    let search_result = session.search(format!("HEADER Message-ID {}", message_id)).await?;
+    let search_result = timed("search", session.search(format!("HEADER Message-ID {}", message_id))).await?;
     
     if search_result.is_empty() {
         return Err(anyhow::anyhow!("Message not found"));
     }
     
     // Get the UID of the message
-    let uid_result = session.uid_search(format!("HEADER Message-ID {}", message_id)).await?;
+    let uid_result = timed("uid_search", session.uid_search(format!("HEADER Message-ID {}", message_id))).await?;
     let uid = uid_result.into_iter().next()
         .ok_or_else(|| anyhow::anyhow!("No UID found"))?;
     
     // Move the message to the specified folder using UID
-    session.uid_mv(uid.to_string(), target_mailbox).await?;
+    timed("uid_move", session.uid_mv(uid.to_string(), target_mailbox)).await?;
     info!("Moved message {} to {} folder", message_id, target_mailbox);
     
     Ok(())
@@ -222,11 +416,17 @@ pub async fn delete_message_by_message_id(
 ) -> Result<()> {
     debug!("delete_message_by_message_id message_id {} mailbox {}", message_id, mailbox);
 
+    let result = delete_by_message_id(session, message_id, mailbox).await;
+    metrics::message_operation("delete", 1, &result);
+    result
+}
+
+async fn delete_by_message_id(session: &mut ImapSession, message_id: &str, mailbox: &str) -> Result<()> {
     // Select the mailbox to ensure we're in the right folder
-    session.select(mailbox).await?;
+    select_mailbox(session, mailbox).await?;
     
     // Search for the message by its Message-ID header using UID search
-    let uid_result = session.uid_search(format!("HEADER Message-ID {}", message_id)).await?;
+    let uid_result = timed("uid_search", session.uid_search(format!("HEADER Message-ID {}", message_id))).await?;
     
     if uid_result.is_empty() {
         return Err(anyhow::anyhow!("Message with Message-ID '{}' not found in mailbox '{}'", message_id, mailbox));
@@ -236,25 +436,154 @@ pub async fn delete_message_by_message_id(
     let uid = uid_result.into_iter().next()
         .ok_or_else(|| anyhow::anyhow!("No UID found for message with Message-ID '{}'", message_id))?;
     
+    ensure_uid_expunge(session).await?;
+
     // Mark the message for deletion using UID store and consume the stream
-    session
-        .uid_store(uid.to_string(), "+FLAGS (\\Deleted)")
-        .await?
-        .try_collect::<Vec<_>>()
-        .await?;
+    timed("uid_store", async {
+        session.uid_store(uid.to_string(), "+FLAGS (\\Deleted)").await?.try_collect::<Vec<_>>().await
+    }).await?;
     
-    // Permanently remove the message by expunging the mailbox and consume the stream
-    session
-        .expunge()
-        .await?
-        .try_collect::<Vec<_>>()
-        .await?;
+    // Permanently remove only this message
+    expunge_uids(session, &[uid]).await?;
     
     info!("Deleted message {} from mailbox {}", message_id, mailbox);
     
     Ok(())
 }
 
+// A plain EXPUNGE removes every message flagged \Deleted in the mailbox, including those another client
+// flagged, so deleting is refused unless the server can expunge given UIDs (UIDPLUS, RFC 4315)
+async fn ensure_uid_expunge(session: &mut ImapSession) -> Result<()> {
+    let capabilities = timed("capability", session.capabilities()).await?;
+    if !capabilities.has_str("UIDPLUS") {
+        return Err(ImapError::Unsupported(
+            "deleting needs UID EXPUNGE (UIDPLUS), without it other messages flagged as deleted would be removed too".to_string(),
+        ).into());
+    }
+    Ok(())
+}
+
+// Permanently remove the messages among `uids` flagged \Deleted, and no other
+async fn expunge_uids(session: &mut ImapSession, uids: &[u32]) -> Result<()> {
+    timed("uid_expunge", async { session.uid_expunge(uid_set(uids)).await?.try_collect::<Vec<_>>().await }).await?;
+    Ok(())
+}
+
+/// Format UIDs as an IMAP sequence set, e.g. `4,8,15`
+pub fn uid_set(uids: &[u32]) -> String {
+    uids.iter().join(",")
+}
+
+// Move several messages at once with a single UID MOVE
+pub async fn move_messages_by_uids(
+    session: &mut ImapSession,
+    mailbox: &str,
+    uids: &[u32],
+    target_mailbox: &str,
+) -> Result<()> {
+    let result = async {
+        select_mailbox(session, mailbox).await?;
+        timed("uid_move", session.uid_mv(uid_set(uids), target_mailbox)).await?;
+        anyhow::Ok(())
+    }.await;
+    metrics::message_operation("move", uids.len(), &result);
+    result?;
+    info!("Moved {} messages from {} to {}", uids.len(), mailbox, target_mailbox);
+    Ok(())
+}
+
+// Copy several messages at once with a single UID COPY
+pub async fn copy_messages_by_uids(
+    session: &mut ImapSession,
+    mailbox: &str,
+    uids: &[u32],
+    target_mailbox: &str,
+) -> Result<()> {
+    let result = async {
+        select_mailbox(session, mailbox).await?;
+        timed("uid_copy", session.uid_copy(uid_set(uids), target_mailbox)).await?;
+        anyhow::Ok(())
+    }.await;
+    metrics::message_operation("copy", uids.len(), &result);
+    result?;
+    info!("Copied {} messages from {} to {}", uids.len(), mailbox, target_mailbox);
+    Ok(())
+}
+
+// Change the flags of several messages at once, `query` being e.g. "+FLAGS (\\Seen)"
+pub async fn store_flags_by_uids(
+    session: &mut ImapSession,
+    mailbox: &str,
+    uids: &[u32],
+    query: &str,
+) -> Result<()> {
+    select_mailbox(session, mailbox).await?;
+    timed("uid_store", async { session.uid_store(uid_set(uids), query).await?.try_collect::<Vec<_>>().await }).await?;
+    info!("Stored {} on {} messages in {}", query, uids.len(), mailbox);
+    Ok(())
+}
+
+// Current flags of a message
+pub async fn fetch_flags(session: &mut ImapSession, mailbox: &str, uid: u32) -> Result<Vec<String>> {
+    select_mailbox(session, mailbox).await?;
+    let fetches: Vec<_> = session
+        .uid_fetch(uid.to_string(), "(UID FLAGS)")
+        .await?
+        .try_collect()
+        .await?;
+
+    let fetch = fetches
+        .iter()
+        .find(|fetch| fetch.uid == Some(uid))
+        .ok_or_else(|| ImapError::MessageNotFound { mailbox: mailbox.to_string(), uid })?;
+    Ok(fetch.flags().map(|flag| flag_name(&flag)).collect())
+}
+
+// Complete RFC 822 source of a message, without marking it as read
+pub async fn fetch_raw_message(session: &mut ImapSession, mailbox: &str, uid: u32) -> Result<Vec<u8>> {
+    select_mailbox(session, mailbox).await?;
+    let fetches: Vec<_> = session
+        .uid_fetch(uid.to_string(), "(UID BODY.PEEK[])")
+        .await?
+        .try_collect()
+        .await?;
+
+    fetches
+        .iter()
+        .find(|fetch| fetch.uid == Some(uid))
+        .and_then(|fetch| fetch.body())
+        .map(|body| body.to_vec())
+        .ok_or_else(|| ImapError::MessageNotFound { mailbox: mailbox.to_string(), uid }.into())
+}
+
+// Permanently delete several messages at once
+pub async fn delete_messages_by_uids(
+    session: &mut ImapSession,
+    mailbox: &str,
+    uids: &[u32],
+) -> Result<()> {
+    let result = async {
+        ensure_uid_expunge(session).await?;
+        store_flags_by_uids(session, mailbox, uids, "+FLAGS (\\Deleted)").await?;
+        expunge_uids(session, uids).await
+    }.await;
+    metrics::message_operation("delete", uids.len(), &result);
+    result?;
+    info!("Deleted {} messages from {}", uids.len(), mailbox);
+    Ok(())
+}
+
+// Store a complete RFC 822 message in a mailbox, marked as read (e.g. a copy of a sent message)
+pub async fn append_message(
+    session: &mut ImapSession,
+    mailbox: &str,
+    content: &[u8],
+) -> Result<()> {
+    timed("append", session.append(mailbox, Some("(\\Seen)"), None, content)).await?;
+    info!("Appended a message of {} bytes to {}", content.len(), mailbox);
+    Ok(())
+}
+
 pub async fn move_email_with_authentication(
     imap_session: &mut ImapSession,
     message_id: String, 
@@ -287,25 +616,54 @@ pub async fn delete_email_with_authentication(
 }
 
 pub async fn create_session(config: &Config) -> Result<ImapSession, Error>{
+    let session = open_session(config).await;
+    health::record_login(config.account_name(), &session);
+    session
+}
+
+async fn open_session(config: &Config) -> Result<ImapSession> {
     // Get credentials
-    let (username, password) = encryption::get_credentials(config.imap.username.as_str())?;
-        
+    let imap = config.imap()?;
+    let (password, typed_in) = encryption::get_password(config.account_name(), imap.username.as_str())?;
+    let imap_session = login(imap, &password).await?;
+
+    // Only a password that worked is kept
+    if typed_in {
+        encryption::store_password(config.account_name(), &password)?;
+    }
+    Ok(imap_session)
+}
+
+/// Connect to the server of `imap` and log in with `password`, whether it is stored or not
+pub async fn login(imap: &ImapConfig, password: &str) -> Result<ImapSession> {
+    let username = imap.username.as_str();
+
     // Connect to server
-    let tls_stream = connect_to_server(config.imap.server.as_str(), config.imap.port).await?;
+    let tls_stream = connect_to_server(imap.server.as_str(), imap.port)
+        .await
+        .map_err(|e| ImapError::Unreachable(format!("{}:{}: {}", imap.server, imap.port, e)))?;
     let compat_stream = tls_stream.compat();
     let client = Client::new(compat_stream);
 
     // Log in
-    let imap_session = login_to_server(client, &username, &password).await?;
+    let imap_session = login_to_server(client, username, password)
+        .await
+        .map_err(|e| match e.downcast_ref::<async_imap::error::Error>() {
+            Some(async_imap::error::Error::No(reason)) | Some(async_imap::error::Error::Bad(reason)) => {
+                metrics::login_failed();
+                ImapError::Authentication(reason.clone()).into()
+            }
+            _ => e,
+        })?;
 
     Ok(imap_session)
 }
 
-pub async fn fetch_messages_from_server(config: &Config, mailbox: &str, count: u32) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+pub async fn fetch_messages_from_server(config: &Config, mailbox: &str, offset: u32, count: u32) -> Result<Vec<Message>> {
     
     let mut imap_session = create_session(config).await?;
     // Fetch messages
-    let messages = fetch_messages(&mut imap_session, mailbox, count).await?;
+    let messages = fetch_messages_page(&mut imap_session, mailbox, offset, count).await?;
     
     // Be nice to the server and log out
     imap_session.logout().await?;
@@ -313,19 +671,29 @@ pub async fn fetch_messages_from_server(config: &Config, mailbox: &str, count: u
     Ok(messages)
 } 
 
+pub async fn search_messages_from_server(
+    config: &Config,
+    mailbox: &str,
+    query: &SearchQuery,
+    offset: u32,
+    count: u32,
+) -> Result<Vec<Message>> {
+    let mut imap_session = create_session(config).await?;
+    let messages = search_messages(&mut imap_session, mailbox, query, offset, count).await?;
+
+    // Be nice to the server and log out
+    imap_session.logout().await?;
+
+    Ok(messages)
+}
+
 pub async fn list_imap_folders(
     config: &Config
 ) -> Result<Vec<String>, Error> {
 
     let mut imap_session = create_session(config).await?;
 
-    let folders = imap_session
-        .list(Some(""), Some("*"))
-        .await?
-        // First convert Result to Option with ok()
-        .map_ok(|name|name.name().to_string())
-        .try_collect::<Vec<String>>()
-        .await?;
+    let folders = list_folders(&mut imap_session).await?;
 
     // Be nice to the server and log out
     imap_session.logout().await?;
@@ -1,9 +1,15 @@
 use anyhow::{bail, Result};
-use mailparse::{parse_mail, MailHeaderMap};
+use mailparse::{parse_mail, DispositionType, MailHeaderMap};
 use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+use super::attachment::{is_attachment_part, is_previewable};
+use super::flags::{flag_name, is_keyword, FLAGGED, SEEN};
+use super::thread::parse_message_ids;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Message {
+    pub uid: Option<u32>,
     pub subject: String,
     pub from: String,
     pub date: String,
@@ -12,18 +18,30 @@ pub struct Message {
     pub bcc: Option<String>,
     pub reply_to: Option<String>,
     pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub thread_id: Option<String>,
     pub content_type: Option<String>,
     pub content: Option<String>,
+    pub html_content: Option<String>,
     pub attachments: Vec<Attachment>,
     pub user_agent: Option<String>,
+    pub list_id: Option<String>,
+    /// Every IMAP flag of the message, e.g. `\Seen` or `$Label1`
+    pub flags: Vec<String>,
+    pub seen: bool,
+    pub flagged: bool,
+    /// Flags that are not system flags, shown as tags
+    pub keywords: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Attachment {
     pub filename: String,
     pub content_type: String,
     pub size: usize,
-    pub content: Vec<u8>,
+    pub content_id: Option<String>,
+    pub previewable: bool,
 }
 
 fn extract_attachments(parsed_mail: &mailparse::ParsedMail) -> Result<Vec<Attachment>> {
@@ -33,26 +51,28 @@ fn extract_attachments(parsed_mail: &mailparse::ParsedMail) -> Result<Vec<Attach
         let content_type = part.headers.get_first_value("Content-Type")
             .unwrap_or_else(|| "text/plain".to_string());
         
-        let content_disposition = part.headers.get_first_value("Content-Disposition")
-            .unwrap_or_default();
-    
-        // Check if this is an attachment
-        if content_disposition.to_lowercase().contains("attachment") {
-            let filename = part.headers.get_first_value("Content-Disposition")
-                .and_then(|disp| {
-                    disp.split("filename=")
-                        .nth(1)
-                        .map(|f| f.trim_matches('"').to_string()) // Ensure ownership here
-                })
+        let content_disposition = part.get_content_disposition();
+        let attachment_disposition = content_disposition.disposition == DispositionType::Attachment;
+
+        // Inline parts (e.g. images referenced from the HTML body as cid:) carry a Content-ID
+        let content_id = part.headers.get_first_value("Content-ID")
+            .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string());
+
+        // Same rules as the BODYSTRUCTURE listing the downloads are looked up in
+        if is_attachment_part(&part.ctype.mimetype, attachment_disposition, content_id.is_some()) {
+            let filename = content_disposition.params.get("filename")
+                .or_else(|| part.ctype.params.get("name"))
+                .cloned()
                 .unwrap_or_else(|| "unnamed_attachment".to_string());
     
-            let content = part.get_body_raw()?;
-            
+            let size = part.get_body_raw()?.len();
+
             attachments.push(Attachment {
                 filename,
+                previewable: is_previewable(&content_type),
                 content_type,
-                size: content.len(),
-                content,
+                size,
+                content_id,
             });
         }
     
@@ -68,32 +88,46 @@ fn extract_attachments(parsed_mail: &mailparse::ParsedMail) -> Result<Vec<Attach
     Ok(attachments)
 }
 
-fn extract_text_content(parsed_mail: &mailparse::ParsedMail) -> Result<Option<String>> {
-    fn find_text_part(part: &mailparse::ParsedMail) -> Result<Option<String>> {
-        let content_type = part.headers.get_first_value("Content-Type")
-            .unwrap_or_else(|| "text/plain".to_string());
+// Find the first part with the given MIME type, depth first
+fn find_part_by_type<'a>(
+    part: &'a mailparse::ParsedMail<'a>,
+    mimetype: &str,
+) -> Option<&'a mailparse::ParsedMail<'a>> {
+    let is_attachment = part.headers.get_first_value("Content-Disposition")
+        .map(|disposition| disposition.to_lowercase().contains("attachment"))
+        .unwrap_or(false);
 
-        // If this is a text part, return its content
-        if content_type.starts_with("text/") {
-            return Ok(Some(part.get_body()?.to_string()));
-        }
+    if part.ctype.mimetype.starts_with(mimetype) && !is_attachment {
+        return Some(part);
+    }
 
-        // Recursively search subparts
-        for subpart in &part.subparts {
-            if let Some(text) = find_text_part(subpart)? {
-                return Ok(Some(text));
-            }
-        }
+    part.subparts
+        .iter()
+        .find_map(|subpart| find_part_by_type(subpart, mimetype))
+}
 
-        Ok(None)
+fn extract_text_content(parsed_mail: &mailparse::ParsedMail) -> Result<Option<String>> {
+    // Prefer the plain text alternative, then fall back to any text part
+    let part = find_part_by_type(parsed_mail, "text/plain")
+        .or_else(|| find_part_by_type(parsed_mail, "text/"));
+
+    match part {
+        Some(part) => Ok(Some(part.get_body()?)),
+        None => Ok(None),
     }
+}
 
-    find_text_part(parsed_mail)
+fn extract_html_content(parsed_mail: &mailparse::ParsedMail) -> Result<Option<String>> {
+    match find_part_by_type(parsed_mail, "text/html") {
+        Some(part) => Ok(Some(part.get_body()?)),
+        None => Ok(None),
+    }
 }
 
 pub fn fetch_to_message(message: &async_imap::types::Fetch) -> Result<Message> {
     let body = message.body().unwrap_or(&[]);
     let parsed_mail = parse_mail(body)?;
+    let flags: Vec<String> = message.flags().map(|flag| flag_name(&flag)).collect();
     
     let subject = parsed_mail.headers.get_first_value("Subject");
     let from = parsed_mail.headers.get_first_value("From");
@@ -103,16 +137,29 @@ pub fn fetch_to_message(message: &async_imap::types::Fetch) -> Result<Message> {
     let bcc = parsed_mail.headers.get_first_value("Bcc");
     let reply_to = parsed_mail.headers.get_first_value("Reply-To");
     let message_id = parsed_mail.headers.get_first_value("Message-ID");
+    let in_reply_to = parsed_mail.headers.get_first_value("In-Reply-To")
+        .and_then(|header| parse_message_ids(&header).into_iter().next());
+    let references = parsed_mail.headers.get_first_value("References")
+        .map(|header| parse_message_ids(&header))
+        .unwrap_or_default();
     let content_type = parsed_mail.headers.get_first_value("Content-Type");
     let user_agent = parsed_mail.headers.get_first_value("User-Agent")
     .or_else(|| parsed_mail.headers.get_first_value("X-Mailer"));
+    // List-Id: Some List <list.example.com> identifies a list by the part in angle brackets
+    let list_id = parsed_mail.headers.get_first_value("List-Id")
+        .map(|header| match (header.rfind('<'), header.rfind('>')) {
+            (Some(start), Some(end)) if start < end => header[start + 1..end].trim().to_string(),
+            _ => header.trim().to_string(),
+        });
 
     // Extract text content and attachments
     let content = extract_text_content(&parsed_mail)?;
+    let html_content = extract_html_content(&parsed_mail)?;
     let attachments = extract_attachments(&parsed_mail)?;
     
     match (subject, from, date) {
         (Some(s), Some(f), Some(d)) => Ok(Message {
+            uid: message.uid,
             subject: s,
             from: f,
             date: d,
@@ -121,10 +168,19 @@ pub fn fetch_to_message(message: &async_imap::types::Fetch) -> Result<Message> {
             bcc,
             reply_to,
             message_id,
+            in_reply_to,
+            references,
+            thread_id: None,
             content_type,
             content,
+            html_content,
             attachments,
             user_agent,
+            list_id,
+            seen: flags.iter().any(|flag| flag == SEEN),
+            flagged: flags.iter().any(|flag| flag == FLAGGED),
+            keywords: flags.iter().filter(|flag| is_keyword(flag)).cloned().collect(),
+            flags,
         }),
         _ => bail!("Cannot parse the message"),
     }
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDate;
+
+/// A parsed search query, ready to be sent as IMAP `UID SEARCH` criteria.
+///
+/// The query language is a list of whitespace separated terms:
+/// `from:`, `to:`, `subject:`, `body:`, `since:YYYY-MM-DD`, `before:YYYY-MM-DD`,
+/// `has:attachment`, `is:unread`/`is:read`, and bare words which match
+/// anywhere in the message. Values containing spaces can be double-quoted,
+/// e.g. `from:"John Doe" subject:invoice since:2024-01-01`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SearchQuery {
+    criteria: Vec<String>,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut criteria = Vec::new();
+
+        for token in tokenize(query)? {
+            let criterion = match token.split_once(':') {
+                Some((key, value)) if is_known_key(key) => term_to_criterion(key, value)?,
+                _ => format!("TEXT {}", quote(&token)),
+            };
+            criteria.push(criterion);
+        }
+
+        Ok(SearchQuery { criteria })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.criteria.is_empty()
+    }
+
+    /// Render the query as IMAP search criteria, including the charset.
+    pub fn to_imap(&self) -> String {
+        if self.criteria.is_empty() {
+            return "CHARSET UTF-8 ALL".to_string();
+        }
+        format!("CHARSET UTF-8 {}", self.criteria.join(" "))
+    }
+}
+
+fn is_known_key(key: &str) -> bool {
+    matches!(
+        key.to_lowercase().as_str(),
+        "from" | "to" | "subject" | "body" | "since" | "before" | "has" | "is"
+    )
+}
+
+fn term_to_criterion(key: &str, value: &str) -> Result<String> {
+    if value.is_empty() {
+        bail!("Missing value for '{}:'", key);
+    }
+
+    let criterion = match key.to_lowercase().as_str() {
+        "from" => format!("FROM {}", quote(value)),
+        "to" => format!("TO {}", quote(value)),
+        "subject" => format!("SUBJECT {}", quote(value)),
+        "body" => format!("BODY {}", quote(value)),
+        "since" => format!("SINCE {}", imap_date(value)?),
+        "before" => format!("BEFORE {}", imap_date(value)?),
+        "has" => match value.to_lowercase().as_str() {
+            // IMAP has no attachment criterion, mixed multiparts are the closest match
+            "attachment" => "HEADER Content-Type \"multipart/mixed\"".to_string(),
+            other => bail!("Unknown search term 'has:{}'", other),
+        },
+        "is" => match value.to_lowercase().as_str() {
+            "unread" => "UNSEEN".to_string(),
+            "read" => "SEEN".to_string(),
+            other => bail!("Unknown search term 'is:{}'", other),
+        },
+        other => bail!("Unknown search key '{}'", other),
+    };
+
+    Ok(criterion)
+}
+
+// Convert a YYYY-MM-DD date into the IMAP date format (e.g. 1-Feb-2024)
+fn imap_date(value: &str) -> Result<String> {
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid date '{}', expected YYYY-MM-DD", value))?;
+    Ok(date.format("%-d-%b-%Y").to_string())
+}
+
+/// Quote a value as an IMAP quoted string
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Split the query on whitespace, keeping double-quoted sections together
+fn tokenize(query: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        bail!("Unterminated quote in search query");
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    Ok(tokens)
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use serde::Serialize;
+
+use crate::mail_reader::message::Message;
+
+/// A conversation: every message sharing the same `thread_id`, oldest first
+#[derive(Debug, Clone, Serialize)]
+pub struct Thread {
+    pub id: String,
+    pub subject: String,
+    pub messages: Vec<Message>,
+}
+
+// A node of the JWZ threading tree; containers without a message stand for
+// messages that are referenced but were not fetched
+struct Container {
+    id: String,
+    message: Option<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Extract every `<message-id>` found in a References or In-Reply-To header
+pub fn parse_message_ids(header: &str) -> Vec<String> {
+    header
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(id, _)| format!("<{}>", id.trim()))
+        .filter(|id| id.len() > 2)
+        .collect()
+}
+
+fn normalize_id(id: &str) -> String {
+    parse_message_ids(id)
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| id.trim().to_string())
+}
+
+/// Subject without reply/forward prefixes, used to group threads with broken references
+pub fn normalize_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        let lower = subject.to_lowercase();
+        let prefix = ["re:", "fwd:", "fw:", "aw:", "sv:"]
+            .iter()
+            .find(|prefix| lower.starts_with(*prefix));
+        match prefix {
+            Some(prefix) => subject = subject[prefix.len()..].trim_start(),
+            None => return subject.to_lowercase(),
+        }
+    }
+}
+
+// Id of a message without a Message-ID; it only depends on the UID, so that the thread page finds
+// the same thread as the message list
+fn synthetic_id(uid: Option<u32>, index: usize) -> String {
+    match uid {
+        Some(uid) => format!("<uid-{}@almambet>", uid),
+        None => format!("<message-{}@almambet>", index),
+    }
+}
+
+/// UID of the message behind an id made up by `assign_thread_ids` for a message without a Message-ID
+pub fn synthetic_id_uid(id: &str) -> Option<u32> {
+    id.strip_prefix("<uid-")?.strip_suffix("@almambet>")?.parse().ok()
+}
+
+fn is_reply_subject(subject: &str) -> bool {
+    normalize_subject(subject) != subject.trim().to_lowercase()
+}
+
+fn container_for(containers: &mut Vec<Container>, by_id: &mut HashMap<String, usize>, id: &str) -> usize {
+    if let Some(&index) = by_id.get(id) {
+        return index;
+    }
+    containers.push(Container {
+        id: id.to_string(),
+        message: None,
+        parent: None,
+        children: Vec::new(),
+    });
+    by_id.insert(id.to_string(), containers.len() - 1);
+    containers.len() - 1
+}
+
+// Whether `ancestor` is `node` itself or one of its ancestors
+fn is_ancestor(containers: &[Container], ancestor: usize, node: usize) -> bool {
+    let mut current = Some(node);
+    while let Some(index) = current {
+        if index == ancestor {
+            return true;
+        }
+        current = containers[index].parent;
+    }
+    false
+}
+
+fn set_parent(containers: &mut [Container], child: usize, parent: usize) {
+    if let Some(old_parent) = containers[child].parent {
+        containers[old_parent].children.retain(|&c| c != child);
+    }
+    containers[child].parent = Some(parent);
+    containers[parent].children.push(child);
+}
+
+fn root_of(containers: &[Container], mut index: usize) -> usize {
+    while let Some(parent) = containers[index].parent {
+        index = parent;
+    }
+    index
+}
+
+// First message found in the subtree, used for the subject of an empty root
+fn first_message(containers: &[Container], index: usize) -> Option<usize> {
+    containers[index].message.or_else(|| {
+        containers[index].children
+            .iter()
+            .find_map(|&child| first_message(containers, child))
+    })
+}
+
+/// Set `thread_id` on every message, using the JWZ threading algorithm
+/// (https://www.jwz.org/doc/threading.html) on References and In-Reply-To.
+///
+/// The thread id is the Message-ID at the root of the conversation, even when
+/// that message itself was not part of the fetched set.
+pub fn assign_thread_ids(messages: &mut [Message]) {
+    let mut containers: Vec<Container> = Vec::new();
+    let mut by_id: HashMap<String, usize> = HashMap::new();
+    let mut message_containers = Vec::with_capacity(messages.len());
+
+    for (i, message) in messages.iter().enumerate() {
+        let id = message.message_id
+            .as_deref()
+            .map(normalize_id)
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| synthetic_id(message.uid, i));
+
+        let mut this = container_for(&mut containers, &mut by_id, &id);
+        if containers[this].message.is_some() {
+            // Duplicate Message-ID: keep both messages, in separate containers
+            this = container_for(&mut containers, &mut by_id, &format!("{}#{}", id, i));
+        }
+        containers[this].message = Some(i);
+        message_containers.push(this);
+
+        // Link the references chain together, oldest ancestor first
+        let mut references = message.references.clone();
+        if let Some(in_reply_to) = &message.in_reply_to {
+            if references.last() != Some(in_reply_to) {
+                references.push(in_reply_to.clone());
+            }
+        }
+
+        let mut previous: Option<usize> = None;
+        for reference in references {
+            let current = container_for(&mut containers, &mut by_id, &reference);
+            if let Some(parent) = previous {
+                if containers[current].parent.is_none() && !is_ancestor(&containers, current, parent) {
+                    set_parent(&mut containers, current, parent);
+                }
+            }
+            previous = Some(current);
+        }
+
+        // The last reference is the parent of this message, whatever was guessed before
+        match previous {
+            Some(parent) if !is_ancestor(&containers, this, parent) => {
+                set_parent(&mut containers, this, parent);
+            }
+            Some(_) => {}
+            None => {
+                if let Some(old_parent) = containers[this].parent.take() {
+                    containers[old_parent].children.retain(|&c| c != this);
+                }
+            }
+        }
+    }
+
+    // Group root threads whose references are broken but whose subjects match,
+    // attaching replies ("Re: x") under the original ("x")
+    let roots: Vec<usize> = (0..containers.len())
+        .filter(|&index| containers[index].parent.is_none() && first_message(&containers, index).is_some())
+        .collect();
+
+    let mut originals: HashMap<String, usize> = HashMap::new();
+    for &root in &roots {
+        let Some(first) = first_message(&containers, root) else { continue };
+        let subject = &messages[first].subject;
+        let normalized = normalize_subject(subject);
+        if containers[root].message.is_some() && !is_reply_subject(subject) && !normalized.is_empty() {
+            originals.entry(normalized).or_insert(root);
+        }
+    }
+
+    for &root in &roots {
+        let Some(first) = first_message(&containers, root) else { continue };
+        let subject = &messages[first].subject;
+        if !is_reply_subject(subject) {
+            continue;
+        }
+        if let Some(&original) = originals.get(&normalize_subject(subject)) {
+            if original != root && !is_ancestor(&containers, root, original) {
+                set_parent(&mut containers, root, original);
+            }
+        }
+    }
+
+    for (message, &container) in messages.iter_mut().zip(&message_containers) {
+        let root = root_of(&containers, container);
+        message.thread_id = Some(containers[root].id.clone());
+    }
+}
+
+fn parse_date(message: &Message) -> Option<DateTime<chrono::FixedOffset>> {
+    DateTime::parse_from_rfc2822(&message.date).ok()
+}
+
+/// Group messages by `thread_id`, most recently active thread first
+pub fn group_threads(messages: Vec<Message>) -> Vec<Thread> {
+    let mut threads: Vec<Thread> = Vec::new();
+    let mut by_id: HashMap<String, usize> = HashMap::new();
+
+    for message in messages {
+        let id = message.thread_id
+            .clone()
+            .or_else(|| message.message_id.clone())
+            .unwrap_or_default();
+
+        match by_id.get(&id) {
+            Some(&index) => threads[index].messages.push(message),
+            None => {
+                by_id.insert(id.clone(), threads.len());
+                threads.push(Thread {
+                    id,
+                    subject: String::new(),
+                    messages: vec![message],
+                });
+            }
+        }
+    }
+
+    for thread in &mut threads {
+        thread.messages.sort_by_key(parse_date);
+        thread.subject = thread.messages
+            .first()
+            .map(|message| message.subject.clone())
+            .unwrap_or_default();
+    }
+
+    threads.sort_by_key(|thread| std::cmp::Reverse(thread.messages.iter().filter_map(parse_date).max()));
+    threads
+}
@@ -0,0 +1,2 @@
+pub mod compose;
+pub mod smtp;
\ No newline at end of file
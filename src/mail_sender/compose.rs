@@ -0,0 +1,179 @@
+use anyhow::{anyhow, bail, Result};
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Mailboxes};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::mail_reader::message::Message;
+use crate::mail_reader::thread::parse_message_ids;
+
+/// A message being written, before it is turned into a MIME message and sent.
+///
+/// Address fields hold comma separated lists, as typed in the compose form.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Draft {
+    pub to: String,
+    #[serde(default)]
+    pub cc: String,
+    #[serde(default)]
+    pub bcc: String,
+    pub subject: String,
+    pub body: String,
+    /// Message-ID of the message being answered
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    /// Message-IDs of the conversation so far, oldest first
+    #[serde(default)]
+    pub references: Vec<String>,
+}
+
+fn prefixed_subject(prefix: &str, subject: &str) -> String {
+    let subject = subject.trim();
+    if subject.to_lowercase().starts_with(&prefix.to_lowercase()) {
+        subject.to_string()
+    } else {
+        format!("{} {}", prefix, subject)
+    }
+}
+
+fn quote_body(message: &Message) -> String {
+    let quoted: Vec<String> = message.content
+        .as_deref()
+        .unwrap_or_default()
+        .lines()
+        .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+        .collect();
+
+    format!("\n\nOn {}, {} wrote:\n{}\n", message.date, message.from, quoted.join("\n"))
+}
+
+fn address_of(mailbox: &str) -> Option<String> {
+    mailbox.parse::<Mailbox>().ok().map(|mailbox| mailbox.email.to_string().to_lowercase())
+}
+
+// Addresses of a header value, skipping `exclude` and the ones already in `seen`
+fn other_addresses(value: Option<&str>, exclude: &[String], seen: &mut Vec<String>) -> Vec<String> {
+    let Some(mailboxes) = value.and_then(|value| value.parse::<Mailboxes>().ok()) else {
+        return Vec::new();
+    };
+
+    mailboxes
+        .into_iter()
+        .filter_map(|mailbox| {
+            let address = mailbox.email.to_string().to_lowercase();
+            if exclude.contains(&address) || seen.contains(&address) {
+                return None;
+            }
+            seen.push(address);
+            Some(mailbox.to_string())
+        })
+        .collect()
+}
+
+impl Draft {
+    /// Answer to `message`: to its Reply-To or sender, and with `reply_all` also to every
+    /// other recipient but `own_address`
+    pub fn reply(message: &Message, reply_all: bool, own_address: &str) -> Self {
+        let recipient = message.reply_to.clone().unwrap_or_else(|| message.from.clone());
+        let own = address_of(own_address).into_iter().collect::<Vec<_>>();
+
+        let mut seen: Vec<String> = recipient
+            .parse::<Mailboxes>()
+            .map(|mailboxes| mailboxes.into_iter().map(|mailbox| mailbox.email.to_string().to_lowercase()).collect())
+            .unwrap_or_default();
+        let cc = if reply_all {
+            let mut cc = other_addresses(message.to.as_deref(), &own, &mut seen);
+            cc.extend(other_addresses(message.cc.as_deref(), &own, &mut seen));
+            cc.join(", ")
+        } else {
+            String::new()
+        };
+
+        let in_reply_to = message.message_id.clone();
+        let mut references = message.references.clone();
+        if let Some(id) = &in_reply_to {
+            if !references.contains(id) {
+                references.push(id.clone());
+            }
+        }
+
+        Draft {
+            to: recipient,
+            cc,
+            subject: prefixed_subject("Re:", &message.subject),
+            body: quote_body(message),
+            in_reply_to,
+            references,
+            ..Default::default()
+        }
+    }
+
+    /// Forward `message` inline; its attachments are not included
+    pub fn forward(message: &Message) -> Self {
+        let mut body = String::from("\n\n---------- Forwarded message ----------\n");
+        body.push_str(&format!("From: {}\n", message.from));
+        body.push_str(&format!("Date: {}\n", message.date));
+        body.push_str(&format!("Subject: {}\n", message.subject));
+        if let Some(to) = &message.to {
+            body.push_str(&format!("To: {}\n", to));
+        }
+        body.push('\n');
+        body.push_str(message.content.as_deref().unwrap_or_default());
+
+        Draft {
+            subject: prefixed_subject("Fwd:", &message.subject),
+            body,
+            ..Default::default()
+        }
+    }
+
+    /// Turn the draft into a MIME message sent by `from`
+    pub fn build(&self, from: &str) -> Result<lettre::Message> {
+        let from: Mailbox = from
+            .parse()
+            .map_err(|e| anyhow!("Invalid sender address '{}': {}", from, e))?;
+
+        let mut builder = lettre::Message::builder()
+            .from(from)
+            .subject(self.subject.trim())
+            .message_id(None)
+            .user_agent(format!("almambet/{}", env!("CARGO_PKG_VERSION")));
+
+        let mut recipients = 0;
+        for (field, value) in [("to", &self.to), ("cc", &self.cc), ("bcc", &self.bcc)] {
+            if value.trim().is_empty() {
+                continue;
+            }
+            let mailboxes: Mailboxes = value
+                .parse()
+                .map_err(|e| anyhow!("Invalid {} address list '{}': {}", field, value, e))?;
+            for mailbox in mailboxes {
+                recipients += 1;
+                builder = match field {
+                    "to" => builder.to(mailbox),
+                    "cc" => builder.cc(mailbox),
+                    _ => builder.bcc(mailbox),
+                };
+            }
+        }
+        if recipients == 0 {
+            bail!("The message has no recipients");
+        }
+
+        if let Some(in_reply_to) = self.in_reply_to.as_deref().filter(|id| !id.trim().is_empty()) {
+            builder = builder.in_reply_to(in_reply_to.trim().to_string());
+        }
+        let references: Vec<String> = self.references
+            .iter()
+            .flat_map(|reference| parse_message_ids(reference))
+            .collect();
+        if !references.is_empty() {
+            builder = builder.references(references.join(" "));
+        }
+
+        builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.replace("\r\n", "\n"))
+            .map_err(|e| anyhow!("Cannot build the message: {}", e))
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{anyhow, Result};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use log::{error, info};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::mail_reader::encryption;
+use crate::mail_reader::imap::{append_message, create_session};
+use crate::mail_sender::compose::Draft;
+use crate::settings::{Config, SmtpConfig, SmtpSecurity};
+
+/// Outcome of sending a draft
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SentMessage {
+    pub message_id: Option<String>,
+    /// Folder where the copy of the message was stored, if that worked
+    pub saved_to: Option<String>,
+}
+
+pub fn smtp_config(config: &Config) -> Result<&SmtpConfig> {
+    config.smtp
+        .as_ref()
+        .ok_or_else(|| anyhow!("Sending is not configured: add an smtp section to settings.yaml"))
+}
+
+/// Address the messages are sent from
+pub fn sender_address(config: &Config) -> String {
+    config.smtp
+        .as_ref()
+        .and_then(|smtp| smtp.from.clone())
+        .or_else(|| config.imap.as_ref().map(|imap| imap.username.clone()))
+        .unwrap_or_default()
+}
+
+// The transport, and the password when it was typed in rather than stored
+fn build_transport(config: &Config, smtp: &SmtpConfig) -> Result<(AsyncSmtpTransport<Tokio1Executor>, Option<String>)> {
+    let builder = match smtp.security {
+        SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.server)?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.server)?
+            .tls(Tls::Wrapper(TlsParameters::new(smtp.server.clone())?)),
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.server),
+    };
+    let builder = builder.port(smtp.port);
+
+    // Same account as IMAP, so the password stored for it is reused
+    if !smtp.authenticate {
+        return Ok((builder.build(), None));
+    }
+    let username = config.imap()?.username.clone();
+    let (password, typed_in) = encryption::get_password(config.account_name(), &username)?;
+    let typed_in = typed_in.then(|| password.clone());
+    Ok((builder.credentials(Credentials::new(username, password)).build(), typed_in))
+}
+
+/// Send a draft over SMTP, then store a copy in the sent folder with IMAP APPEND
+pub async fn send_draft(config: &Config, draft: &Draft) -> Result<SentMessage> {
+    let smtp = smtp_config(config)?;
+    let message = draft.build(&sender_address(config))?;
+    let message_id = message.headers().get_raw("Message-ID").map(str::to_string);
+    let content = message.formatted();
+
+    let (transport, typed_in) = build_transport(config, smtp)?;
+    transport
+        .send(message)
+        .await
+        .map_err(|e| anyhow!("Cannot send the message: {}", e))?;
+    info!("Sent message {:?} to {}", message_id, draft.to);
+
+    // The server accepted the password, so it is kept like after an IMAP login
+    if let Some(password) = typed_in {
+        encryption::store_password(config.account_name(), &password)?;
+    }
+
+    // The message is already gone, so failing to keep a copy is only logged
+    let saved_to = match save_copy(config, &smtp.sent_folder, &content).await {
+        Ok(()) => Some(smtp.sent_folder.clone()),
+        Err(e) => {
+            error!("Cannot store the sent message in {}: {}", smtp.sent_folder, e);
+            None
+        }
+    };
+
+    Ok(SentMessage { message_id, saved_to })
+}
+
+async fn save_copy(config: &Config, folder: &str, content: &[u8]) -> Result<()> {
+    let mut imap_session = create_session(config).await?;
+    append_message(&mut imap_session, folder, content).await?;
+    imap_session.logout().await?;
+    Ok(())
+}
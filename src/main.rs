@@ -1,11 +1,21 @@
+mod config_check;
+mod credentials;
+mod daemon;
+mod events;
+mod health;
 mod mail_reader;
+mod mail_sender;
 mod web;
 mod web_services;
 mod mail_move_rules;
+mod metrics;
+mod reload;
 mod settings;
 mod tests;
+mod webhooks;
 
 use std::error::Error as StdError;
+use std::path::PathBuf;
 use clap::{Arg, ArgAction, Command};
 use log::{error, info};
 
@@ -66,7 +76,33 @@ enum OperationMode {
     Print,
 }
 
+/// Operation modes that exit when done
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OneShotMode {
+    Once,
+    Spam,
+    Print,
+}
+
+/// How an operation mode runs
+enum ModeRun {
+    OneShot(OneShotMode),
+    Service(daemon::Service),
+}
+
 impl OperationMode {
+    /// Whether the mode exits when done or runs as a service until the process is stopped
+    fn run(self) -> ModeRun {
+        match self {
+            OperationMode::Once => ModeRun::OneShot(OneShotMode::Once),
+            OperationMode::Spam => ModeRun::OneShot(OneShotMode::Spam),
+            OperationMode::Print => ModeRun::OneShot(OneShotMode::Print),
+            OperationMode::Periodic => ModeRun::Service(daemon::Service::RuleRunner),
+            OperationMode::Web => ModeRun::Service(daemon::Service::Web),
+            OperationMode::Rest => ModeRun::Service(daemon::Service::Rest),
+        }
+    }
+
     fn from_cli_matches(matches: &clap::ArgMatches) -> Vec<Self> {
         let mut modes = Vec::new();
 
@@ -93,6 +129,14 @@ impl OperationMode {
     }
 }
 
+// `--account` of the credentials subcommands
+fn account_arg() -> Arg {
+    Arg::new("account")
+        .long("account")
+        .value_name("NAME")
+        .help("Only this account, instead of every account")
+}
+
 /// Build CLI command structure
 fn build_cli() -> Command {
     Command::new("Email Rules Processor")
@@ -140,38 +184,110 @@ fn build_cli() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("Deletes spam messages"),
         )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Settings file to use instead of searching for settings.yaml"),
+        )
+        .arg(
+            Arg::new("rules")
+                .long("rules")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Rules file to use instead of searching for email_move_rules.yaml"),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Validate the settings and rules files, and the rule folders against the server when it answers"),
+                ),
+        )
+        .subcommand(
+            Command::new("credentials")
+                .about("Manage the stored passwords")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("set")
+                        .about("Ask for the password and store it once the server accepted it")
+                        .arg(account_arg()),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Log in with the stored password")
+                        .arg(account_arg()),
+                )
+                .subcommand(
+                    Command::new("clear")
+                        .about("Remove the stored password")
+                        .arg(account_arg()),
+                )
+                .subcommand(
+                    Command::new("rotate-key")
+                        .about("Replace the encryption key and encrypt the stored passwords with the new one"),
+                ),
+        )
         .after_help(
             "Note: Multiple modes can be specified. If no mode is specified, \
             the application will run in 'once' mode by default."
         )
 }
 
-/// Execute the requested operation mode
-async fn execute_mode(mode: OperationMode, config: &settings::Config) -> AppResult<()> {
+/// Execute one of the operation modes that exit when done, for every account.
+///
+/// The accounts are processed concurrently and a failing account does not stop the others;
+/// the mode fails when any of them failed.
+async fn execute_mode(mode: OneShotMode, config: &settings::Config) -> AppResult<()> {
     info!("Executing operation mode: {:?}", mode);
-    
+
+    let accounts = config.accounts();
+    if let [account] = accounts.as_slice() {
+        return execute_account_mode(mode, account).await;
+    }
+
+    let results = if mode == OneShotMode::Print {
+        // One listing after the other, so that they do not interleave
+        let mut results = Vec::new();
+        for account in &accounts {
+            println!("{}:", account.account_name());
+            results.push(execute_account_mode(mode, account).await);
+        }
+        results
+    } else {
+        futures::future::join_all(accounts.iter().map(|account| execute_account_mode(mode, account))).await
+    };
+
+    let mut failed = Vec::new();
+    for (account, result) in accounts.iter().zip(results) {
+        if let Err(e) = result {
+            error!("{:?} failed for the account {}: {}", mode, account.account_name(), e);
+            failed.push(account.account_name());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!("{:?} failed for the accounts {}", mode, failed.join(", ")).into());
+    }
+    Ok(())
+}
+
+async fn execute_account_mode(mode: OneShotMode, config: &settings::Config) -> AppResult<()> {
     match mode {
-        OperationMode::Once => {
+        OneShotMode::Once => {
             mail_move_rules::apply_rules(config).await?;
             info!("Successfully executed once mode");
         }
-        OperationMode::Periodic => {
-            mail_move_rules::entrypoint(config).await?;
-            info!("Successfully started periodic mode");
-        }
-        OperationMode::Web => {
-            web::entrypoint(config).await?;
-            info!("Successfully started web mode");
-        }
-        OperationMode::Rest => {
-            web_services::entrypoint(config).await?;
-            info!("Successfully started REST mode");
-        }
-        OperationMode::Spam => {
+        OneShotMode::Spam => {
             mail_move_rules::delete_spam(config).await?;
             info!("Successfully executed spam deletion");
         }
-        OperationMode::Print => {
+        OneShotMode::Print => {
             mail_move_rules::print_emails(config).await?;
             info!("Successfully printed e-mail messages")
         }
@@ -180,12 +296,20 @@ async fn execute_mode(mode: OperationMode, config: &settings::Config) -> AppResu
     Ok(())
 }
 
-/// Validate that the selected operation modes are compatible
-fn validate_modes(modes: &[OperationMode]) -> AppResult<()> {
+/// Validate that the selected operation modes are compatible, with each other and with the settings
+fn validate_modes(modes: &[OperationMode], config: &settings::Config) -> AppResult<()> {
     if modes.is_empty() {
         return Ok(());
     }
 
+    // Otherwise the second server cannot bind and the daemon keeps restarting it
+    if modes.contains(&OperationMode::Web) && modes.contains(&OperationMode::Rest) {
+        if let Some(clash) = config.web_and_rest_clash() {
+            error!("{}", clash);
+            return Err(clash.into());
+        }
+    }
+
     // Check for incompatible modes
     let has_server_mode = modes.contains(&OperationMode::Web) || modes.contains(&OperationMode::Rest);
     let has_processing_mode = modes.contains(&OperationMode::Once) || 
@@ -206,9 +330,38 @@ fn validate_modes(modes: &[OperationMode]) -> AppResult<()> {
 
 #[tokio::main]
 async fn main() -> AppResult<()> {
-    // Initialize logger first
+    // Parse command line arguments
+    let matches = build_cli().get_matches();
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        settings::set_config_path(path.clone());
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("rules") {
+        mail_move_rules::mail_move_settings::set_rules_path(path.clone());
+    }
+
+    // `config check` and `credentials` print their own output, without the log
+    match matches.subcommand() {
+        Some(("config", _)) => {
+            // e.g. `almambet --web --rest config check` checks that the two can run together
+            let services: Vec<daemon::Service> = OperationMode::from_cli_matches(&matches)
+                .into_iter()
+                .filter_map(|mode| match mode.run() {
+                    ModeRun::Service(service) => Some(service),
+                    ModeRun::OneShot(_) => None,
+                })
+                .collect();
+            let passed = config_check::run(&services).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(("credentials", credentials)) => {
+            let passed = credentials::run(credentials).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        _ => {}
+    }
+
     setup_logger(LoggerConfig::default()).expect("Failed to initialize logger");
-    
+
     info!("Starting Email Rules Processor");
 
     // Load configuration
@@ -217,10 +370,13 @@ async fn main() -> AppResult<()> {
             error!("Failed to load settings: {}", e);
             e
         })?;
+    let errors = config.validate();
+    if !errors.is_empty() {
+        error!("Invalid settings: {}", errors.join("; "));
+        return Err(errors.join("; ").into());
+    }
     info!("Configuration loaded successfully");
 
-    // Parse command line arguments
-    let matches = build_cli().get_matches();
     let modes = OperationMode::from_cli_matches(&matches);
 
     // If no modes specified, default to 'once'
@@ -232,16 +388,35 @@ async fn main() -> AppResult<()> {
     };
 
     // Validate selected modes
-    validate_modes(&modes)?;
+    validate_modes(&modes, &config)?;
+
+    webhooks::start(&config);
+    metrics::start(&config);
 
-    // Execute all requested modes
+    // Run the modes that exit when done first, then keep the others running until stopped
+    let mut one_shots = Vec::new();
+    let mut services = Vec::new();
     for mode in modes {
+        match mode.run() {
+            ModeRun::OneShot(one_shot) => one_shots.push(one_shot),
+            ModeRun::Service(service) => services.push(service),
+        }
+    }
+    for mode in one_shots {
         if let Err(e) = execute_mode(mode, &config).await {
             error!("Failed to execute mode {:?}: {}", mode, e);
+            webhooks::deliver_due().await;
             return Err(e);
         }
     }
 
+    if !services.is_empty() {
+        daemon::run(&services, config).await?;
+    }
+
+    // Try the pending webhooks once before exiting, the rest wait for the next run
+    webhooks::deliver_due().await;
+
     info!("Email Rules Processor completed successfully");
     Ok(())
 }
\ No newline at end of file
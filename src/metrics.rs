@@ -0,0 +1,229 @@
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use axum::extract::{MatchedPath, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use log::{error, info};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::settings::Config;
+
+// Buckets of the IMAP command and HTTP request durations, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+struct Metrics {
+    registry: Registry,
+    messages_fetched: IntCounter,
+    rule_matches: IntCounterVec,
+    message_operations: IntCounterVec,
+    imap_command_seconds: HistogramVec,
+    login_failures: IntCounter,
+    fetch_retries: IntCounter,
+    last_success: GaugeVec,
+    job_failures: IntCounterVec,
+    job_skipped: IntCounterVec,
+    config_reloads: IntCounterVec,
+    config_reload_successful: GaugeVec,
+    http_requests: IntCounterVec,
+    http_request_seconds: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("almambet".to_string()), None)?,
+            messages_fetched: IntCounter::new("messages_fetched_total", "Messages fetched from the IMAP server")?,
+            rule_matches: IntCounterVec::new(
+                Opts::new("rule_matches_total", "Messages matched by each rule, dry runs excluded"),
+                &["rule", "target_folder"],
+            )?,
+            message_operations: IntCounterVec::new(
+                Opts::new("message_operations_total", "Messages moved, copied or deleted on the IMAP server"),
+                &["operation", "result"],
+            )?,
+            imap_command_seconds: HistogramVec::new(
+                HistogramOpts::new("imap_command_duration_seconds", "Duration of the IMAP commands")
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                &["command"],
+            )?,
+            login_failures: IntCounter::new("imap_login_failures_total", "Logins refused by the IMAP server")?,
+            fetch_retries: IntCounter::new("fetch_retries_total", "Retries of a failed message fetch")?,
+            last_success: GaugeVec::new(
+                Opts::new("last_success_timestamp_seconds", "Unix time of the last successful run of each job"),
+                &["account", "job"],
+            )?,
+            job_failures: IntCounterVec::new(
+                Opts::new("job_failures_total", "Failed runs of the scheduled jobs"),
+                &["account", "job"],
+            )?,
+            job_skipped: IntCounterVec::new(
+                Opts::new("job_runs_skipped_total", "Scheduled runs skipped because the previous one was still going or after failures"),
+                &["account", "job", "reason"],
+            )?,
+            config_reloads: IntCounterVec::new(
+                Opts::new("config_reloads_total", "Reloads of the settings and rules files"),
+                &["file", "result"],
+            )?,
+            config_reload_successful: GaugeVec::new(
+                Opts::new("config_last_reload_successful", "Whether the last reload of each file succeeded (1) or failed (0)"),
+                &["file"],
+            )?,
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests answered by the web and REST interfaces"),
+                &["app", "method", "route", "status"],
+            )?,
+            http_request_seconds: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Duration of the HTTP requests")
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                &["app", "method", "route"],
+            )?,
+        };
+
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.messages_fetched.clone()))?;
+        registry.register(Box::new(metrics.rule_matches.clone()))?;
+        registry.register(Box::new(metrics.message_operations.clone()))?;
+        registry.register(Box::new(metrics.imap_command_seconds.clone()))?;
+        registry.register(Box::new(metrics.login_failures.clone()))?;
+        registry.register(Box::new(metrics.fetch_retries.clone()))?;
+        registry.register(Box::new(metrics.last_success.clone()))?;
+        registry.register(Box::new(metrics.job_failures.clone()))?;
+        registry.register(Box::new(metrics.job_skipped.clone()))?;
+        registry.register(Box::new(metrics.config_reloads.clone()))?;
+        registry.register(Box::new(metrics.config_reload_successful.clone()))?;
+        registry.register(Box::new(metrics.http_requests.clone()))?;
+        registry.register(Box::new(metrics.http_request_seconds.clone()))?;
+        Ok(metrics)
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("metric definitions are valid"))
+}
+
+pub fn messages_fetched(count: usize) {
+    metrics().messages_fetched.inc_by(count as u64);
+}
+
+/// Count a message matched by a rule, named by `name` or by its position in the rules file
+pub fn rule_matched(index: usize, name: Option<&str>, target_folder: &str) {
+    let rule = name.map(str::to_string).unwrap_or_else(|| format!("#{}", index));
+    metrics().rule_matches.with_label_values(&[rule.as_str(), target_folder]).inc();
+}
+
+/// Count `count` messages moved, copied or deleted by one IMAP operation
+pub fn message_operation<T, E>(operation: &str, count: usize, result: &Result<T, E>) {
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    metrics().message_operations.with_label_values(&[operation, outcome]).inc_by(count as u64);
+}
+
+pub fn login_failed() {
+    metrics().login_failures.inc();
+}
+
+pub fn fetch_retried() {
+    metrics().fetch_retries.inc();
+}
+
+/// Record the end of a successful run of `job` of `account`, e.g. `rules` or `spam`
+pub fn job_succeeded(account: &str, job: &str) {
+    metrics().last_success.with_label_values(&[account, job]).set(chrono::Utc::now().timestamp() as f64);
+}
+
+pub fn job_failed(account: &str, job: &str) {
+    metrics().job_failures.with_label_values(&[account, job]).inc();
+}
+
+/// Count a scheduled run of `job` that did not happen, `reason` being `overlap` or `backoff`
+pub fn job_skipped(account: &str, job: &str, reason: &str) {
+    metrics().job_skipped.with_label_values(&[account, job, reason]).inc();
+}
+
+/// Count a reload of `file`, `settings` or `rules`
+pub fn config_reloaded(file: &str, success: bool) {
+    let metrics = metrics();
+    let result = if success { "success" } else { "failure" };
+    metrics.config_reloads.with_label_values(&[file, result]).inc();
+    metrics.config_reload_successful.with_label_values(&[file]).set(if success { 1.0 } else { 0.0 });
+}
+
+/// Run an IMAP command, recording how long it took
+pub async fn timed<F: Future>(command: &str, future: F) -> F::Output {
+    let _timer = metrics().imap_command_seconds.with_label_values(&[command]).start_timer();
+    future.await
+}
+
+/// Middleware counting and timing the requests of the router it wraps, `app` telling the routers apart
+pub async fn track_requests(app: &'static str, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    let metrics = metrics();
+    metrics
+        .http_request_seconds
+        .with_label_values(&[app, method.as_str(), route.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .with_label_values(&[app, method.as_str(), route.as_str(), response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Every metric in the Prometheus text format
+pub fn render() -> prometheus::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&metrics().registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+async fn serve_metrics() -> Response {
+    match render() {
+        Ok(text) => ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], text).into_response(),
+        Err(e) => {
+            error!("Cannot render the metrics: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+/// `GET /metrics`, merged into the web and REST routers
+pub fn metrics_router() -> Router {
+    Router::new().route("/metrics", get(serve_metrics))
+}
+
+/// Serve `/metrics` on its own address when `metrics.listen` is set, e.g. for `--periodic` alone
+pub fn start(config: &Config) {
+    let Some(listen) = config.metrics.as_ref().map(|metrics| metrics.listen.clone()) else {
+        return;
+    };
+    tokio::spawn(async move {
+        let listener = match tokio::net::TcpListener::bind(&listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Cannot serve the metrics on {}: {}", listen, e);
+                return;
+            }
+        };
+        info!("Metrics available on http://{}/metrics", listen);
+        if let Err(e) = axum::serve(listener, metrics_router()).await {
+            error!("Metrics server error: {}", e);
+        }
+    });
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::events::{publish_global, MailEvent};
+use crate::mail_move_rules::mail_move_settings::{mail_move_config_path, reload_rules};
+use crate::metrics;
+use crate::settings::{self, Config};
+
+// Editors often write a file in several steps; changes this close together are handled once
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// A configuration file reloaded while running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConfigFile {
+    Settings,
+    Rules,
+}
+
+impl ConfigFile {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConfigFile::Settings => "settings",
+            ConfigFile::Rules => "rules",
+        }
+    }
+}
+
+/// Result of the last reload of a file
+#[derive(Debug, Clone)]
+pub struct ReloadOutcome {
+    pub at: DateTime<Utc>,
+    /// Why the file was not reloaded; the previous version is still in use
+    pub error: Option<String>,
+}
+
+fn outcomes() -> &'static Mutex<HashMap<ConfigFile, ReloadOutcome>> {
+    static OUTCOMES: OnceLock<Mutex<HashMap<ConfigFile, ReloadOutcome>>> = OnceLock::new();
+    OUTCOMES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn last_outcome(file: ConfigFile) -> Option<ReloadOutcome> {
+    outcomes().lock().unwrap().get(&file).cloned()
+}
+
+/// Log, count and publish the outcome of a reload of `file`
+pub fn record(file: ConfigFile, result: Result<(), String>) {
+    match &result {
+        Ok(()) => info!("Reloaded the {} file", file.name()),
+        Err(e) => error!("Cannot reload the {} file, keeping the previous version: {}", file.name(), e),
+    }
+    metrics::config_reloaded(file.name(), result.is_ok());
+
+    let error = result.err();
+    outcomes().lock().unwrap().insert(file, ReloadOutcome { at: Utc::now(), error: error.clone() });
+    publish_global(MailEvent::ConfigReloaded { file: file.name().to_string(), ok: error.is_none(), error });
+}
+
+/// Read the rules file of every account again, keeping the current rules of an account when its new ones are invalid
+pub fn reload_rules_file(config: &Config) {
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+    for account in config.accounts() {
+        // Accounts may share a rules file
+        if !seen.insert(mail_move_config_path(&account)) {
+            continue;
+        }
+        if let Err(e) = reload_rules(&account) {
+            match &account.account {
+                Some(settings) => errors.push(format!("{}: {}", settings.name, e)),
+                None => errors.push(e.to_string()),
+            }
+        }
+    }
+    record(ConfigFile::Rules, if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) });
+}
+
+/// Watches the settings and rules files of `config` for changes
+pub struct ConfigWatcher {
+    // Stops watching when dropped
+    _watcher: RecommendedWatcher,
+    changes: mpsc::UnboundedReceiver<ConfigFile>,
+}
+
+impl ConfigWatcher {
+    /// Watch the files in use, including the rules file of each account; a file that does not exist yet is not watched
+    pub fn new(config: &Config) -> notify::Result<Self> {
+        let rules_files = config.accounts().iter().map(|account| (mail_move_config_path(account), ConfigFile::Rules)).collect::<Vec<_>>();
+        let mut files: Vec<(PathBuf, ConfigFile)> = std::iter::once((settings::config_file_path(), ConfigFile::Settings))
+            .chain(rules_files)
+            .filter_map(|(path, file)| Some((std::fs::canonicalize(path?).ok()?, file)))
+            .collect();
+        files.sort();
+        files.dedup();
+
+        // Files are often replaced rather than written in place, so their directories are watched;
+        // the other files there (e.g. a log file) are ignored
+        let mut directories: Vec<PathBuf> = files.iter().filter_map(|(path, _)| path.parent()).map(Path::to_path_buf).collect();
+        directories.sort();
+        directories.dedup();
+        for (path, file) in &files {
+            info!("Watching the {} file {:?} for changes", file.name(), path);
+        }
+
+        let (sender, changes) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                for (path, file) in &files {
+                    if event.paths.contains(path) {
+                        let _ = sender.send(*file);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Error watching the configuration files: {}", e),
+        })?;
+        for directory in &directories {
+            watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        }
+
+        Ok(ConfigWatcher { _watcher: watcher, changes })
+    }
+
+    /// Wait until some of the files changed, returning which ones once the changes settled
+    pub async fn changed(&mut self) -> Vec<ConfigFile> {
+        let mut changed = Vec::new();
+        loop {
+            let next = if changed.is_empty() {
+                self.changes.recv().await
+            } else {
+                match tokio::time::timeout(SETTLE_DELAY, self.changes.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => return changed,
+                }
+            };
+            let Some(file) = next else {
+                if !changed.is_empty() {
+                    return changed;
+                }
+                // The watcher is gone, nothing will change anymore
+                return std::future::pending().await;
+            };
+
+            debug!("The {} file changed", file.name());
+            if !changed.contains(&file) {
+                changed.push(file);
+            }
+        }
+    }
+}
@@ -1,15 +1,160 @@
-use serde::Deserialize;
-use std::fs::File;
-use std::io::BufReader;
-use yaml_serde::Error;
-use log::error;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::ToSocketAddrs;
+use std::sync::{Mutex, OnceLock};
+use yaml_serde::{Error, Mapping, Value};
+use log::warn;
+use std::path::{Path, PathBuf};
+
+/// Directory searched first for `settings.yaml` and `email_move_rules.yaml`
+pub const CONFIG_DIR_VARIABLE: &str = "ALMAMBET_CONFIG_DIR";
+// Prefix of the variables overriding a setting, e.g. `ALMAMBET__IMAP__SERVER` for `imap.server`
+const OVERRIDE_PREFIX: &str = "ALMAMBET__";
+
+// Set by `--config`, replacing the search for settings.yaml
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Name of the account described by the `imap` section, when there is no `accounts` list
+pub const DEFAULT_ACCOUNT: &str = "default";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
-    pub imap: ImapConfig,
+    /// The mail account, unless several are listed in `accounts`
+    #[serde(default)]
+    pub imap: Option<ImapConfig>,
     pub mail_mover: MailMoverConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub web: WebConfig,
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    /// The account these settings were picked for by `Config::accounts`
+    #[serde(skip)]
+    pub account: Option<AccountConfig>,
+}
+
+/// One of several mail accounts, with what differs from the top-level settings
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Used in the web UI, in the REST paths and to name the credential files
+    pub name: String,
+    pub imap: ImapConfig,
+    /// Rules file of the account; the usual rules file when not set
+    #[serde(default)]
+    pub rules: Option<PathBuf>,
+    /// Schedule of the account's jobs; the top-level `mail_mover` when not set
+    #[serde(default)]
+    pub mail_mover: Option<MailMoverConfig>,
+    /// Outgoing mail of the account; the top-level `smtp` when not set
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+}
+
+impl Config {
+    /// The settings of each account, in the order of `accounts`, or the single account of `imap`
+    pub fn accounts(&self) -> Vec<Config> {
+        if self.accounts.is_empty() {
+            return vec![self.clone()];
+        }
+        self.accounts
+            .iter()
+            .map(|account| Config {
+                imap: Some(account.imap.clone()),
+                mail_mover: account.mail_mover.clone().unwrap_or_else(|| self.mail_mover.clone()),
+                smtp: account.smtp.clone().or_else(|| self.smtp.clone()),
+                account: Some(account.clone()),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// The settings of the account called `name`
+    pub fn account(&self, name: &str) -> Option<Config> {
+        self.accounts().into_iter().find(|account| account.account_name() == name)
+    }
+
+    /// Name of the account these settings are for
+    pub fn account_name(&self) -> &str {
+        self.account.as_ref().map_or(DEFAULT_ACCOUNT, |account| account.name.as_str())
+    }
+
+    /// Names of every account
+    pub fn account_names(&self) -> Vec<String> {
+        self.accounts().iter().map(|account| account.account_name().to_string()).collect()
+    }
+
+    /// IMAP settings of the account; missing from the top-level settings when there are several accounts
+    pub fn imap(&self) -> anyhow::Result<&ImapConfig> {
+        self.imap.as_ref().ok_or_else(|| anyhow::anyhow!("No imap settings: pick one of the accounts"))
+    }
+
+    /// Why the web interface and the REST server cannot run together, if they would listen on the same address
+    pub fn web_and_rest_clash(&self) -> Option<String> {
+        same_listen_address(&self.web.address(), &self.server.address()).then(|| {
+            format!(
+                "web.host/web.port ({}) and server.host/server.port ({}) are the same address, so --web and --rest cannot run together",
+                self.web.address(), self.server.address()
+            )
+        })
+    }
+
+    /// Check what deserializing cannot, returning one message per problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        match (&self.imap, self.accounts.is_empty()) {
+            (None, true) => errors.push("imap: required unless accounts are listed".to_string()),
+            (Some(_), false) => errors.push("imap: not used when accounts are listed, move it into one of them".to_string()),
+            _ => {}
+        }
+        let mut names = HashSet::new();
+        for (index, account) in self.accounts.iter().enumerate() {
+            let valid_name = !account.name.is_empty()
+                && account.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid_name {
+                errors.push(format!("accounts[{}].name: {:?} must only have letters, digits, - and _", index, account.name));
+            }
+            if !names.insert(account.name.as_str()) {
+                errors.push(format!("accounts[{}].name: {:?} is used by another account", index, account.name));
+            }
+            if let Some(mail_mover) = &account.mail_mover {
+                validate_schedules(&format!("accounts[{}].mail_mover", index), mail_mover, &mut errors);
+            }
+        }
+
+        validate_schedules("mail_mover", &self.mail_mover, &mut errors);
+
+        for (index, webhook) in self.webhooks.iter().enumerate() {
+            if let Err(e) = reqwest::Url::parse(&webhook.url) {
+                errors.push(format!("webhooks[{}].url: invalid URL {:?}: {}", index, webhook.url, e));
+            }
+        }
+
+        errors
+    }
+}
+
+fn validate_schedules(prefix: &str, mail_mover: &MailMoverConfig, errors: &mut Vec<String>) {
+    let rules_setting = if mail_mover.jobs.rules.is_some() { "jobs.rules" } else { "check_interval" };
+    let schedules = [(rules_setting, Some(mail_mover.rules_schedule())), ("jobs.spam", mail_mover.jobs.spam.clone())];
+    for (setting, schedule) in schedules {
+        match schedule {
+            Some(Schedule::Every(0)) => errors.push(format!("{}.{}: must be at least 1 second", prefix, setting)),
+            Some(Schedule::Cron(expression)) => {
+                if let Err(e) = crate::mail_move_rules::scheduler::parse_cron(&expression) {
+                    errors.push(format!("{}.{}: {}", prefix, setting, e));
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -19,76 +164,472 @@ pub struct ImapConfig {
     pub username: String,
 }
 
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS (usually port 587)
+    #[default]
+    Starttls,
+    /// TLS from the start (usually port 465)
+    Tls,
+    /// No encryption, only meant for local test servers
+    None,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub server: String,
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// Log in with the stored IMAP credentials; local test servers usually need none
+    #[serde(default = "default_true")]
+    pub authenticate: bool,
+    /// Sender address, e.g. `"Jane Doe <jane@example.com>"`; defaults to the IMAP username
+    #[serde(default)]
+    pub from: Option<String>,
+    /// Folder where a copy of the sent messages is stored
+    #[serde(default = "default_sent_folder")]
+    pub sent_folder: String,
+}
+
+/// Something that happened and can be sent to a webhook
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    NewMessage,
+    RuleMatched,
+    SpamDeleted,
+    Error,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Events sent to this URL; all of them when not set
+    #[serde(default = "all_webhook_events")]
+    pub events: Vec<WebhookEvent>,
+    /// Key of the HMAC-SHA256 signature sent in the `X-Almambet-Signature` header
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Deliveries are retried with an increasing delay, then dropped after this many attempts
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
+    /// Address of a listener serving only `/metrics`, e.g. `"127.0.0.1:9090"`
+    pub listen: String,
+}
+
+fn all_webhook_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::NewMessage, WebhookEvent::RuleMatched, WebhookEvent::SpamDeleted, WebhookEvent::Error]
+}
+
+fn default_max_attempts() -> u32 {
+    8
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_sent_folder() -> String {
+    "Sent".to_string()
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct MailMoverConfig {
     #[serde(rename = "check_interval")]
     pub interval_seconds: u64,
+    /// Each scheduled run waits a random delay of up to this many seconds, spreading the load on the server
+    #[serde(default)]
+    pub jitter: u64,
+    /// What happens when a run is due while the previous run of the same job is still going
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+}
+
+impl MailMoverConfig {
+    /// When the rules are applied, every `check_interval` seconds unless `jobs.rules` says otherwise
+    pub fn rules_schedule(&self) -> Schedule {
+        self.jobs.rules.clone().unwrap_or(Schedule::Every(self.interval_seconds))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// Drop the run
+    #[default]
+    Skip,
+    /// Start the run as soon as the previous one ends; at most one run waits
+    Queue,
+}
+
+/// When a job runs, e.g. `every: 60` or `cron: "0 30 3 * * *"`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "ScheduleFields")]
+pub enum Schedule {
+    /// Every so many seconds
+    Every(u64),
+    /// At the times matching a cron expression with seconds (`sec min hour day month weekday`), local time
+    Cron(String),
+}
+
+// How a schedule is written in the settings, with exactly one of the fields
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFields {
+    every: Option<u64>,
+    cron: Option<String>,
+}
+
+impl TryFrom<ScheduleFields> for Schedule {
+    type Error = String;
+
+    fn try_from(fields: ScheduleFields) -> Result<Self, Self::Error> {
+        match (fields.every, fields.cron) {
+            (Some(seconds), None) => Ok(Schedule::Every(seconds)),
+            (None, Some(expression)) => Ok(Schedule::Cron(expression)),
+            _ => Err("a schedule needs either `every` (seconds) or `cron`".to_string()),
+        }
+    }
+}
+
+/// Schedules of the jobs of `--periodic`; a job without one does not run
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct JobsConfig {
+    /// Applying the rules to INBOX, by default every `check_interval` seconds
+    #[serde(default)]
+    pub rules: Option<Schedule>,
+    /// Deleting the messages of the Spam folder
+    #[serde(default)]
+    pub spam: Option<Schedule>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
+    /// Address the REST server listens on; only this machine by default, since the API has no authentication
+    #[serde(default = "default_host")]
     pub host: String,
     pub port: u16,
+    /// Seconds between two checks of the mailboxes for live updates in the web UI
+    #[serde(default = "default_watch_interval")]
+    pub watch_interval: u64,
+    /// Directory whose `templates/` and `static/` files replace the ones built into the binary
+    #[serde(default)]
+    pub assets_dir: Option<PathBuf>,
+    /// Mark a message as read when it is opened in the web UI
+    #[serde(default = "default_true")]
+    pub mark_seen_on_open: bool,
 }
 
-pub fn load_settings() -> Result<Config, Error> {
-    let config_path = find_config_file().unwrap_or_else(|| {
-        error!("Could not find settings.yaml in any of the expected locations");
-        panic!("Cannot find settings.yaml");
-    });
+impl ServerConfig {
+    /// `host:port` the REST server listens on
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
 
-    let file = match File::open(&config_path) {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Error opening config file at {:?}: {}", config_path, err);
-            panic!("Cannot open settings file: {}", err);
-        }
-    };
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// Listen address of the web interface, which runs next to the REST server in the same process
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebConfig {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_web_port")]
+    pub port: u16,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig { host: default_host(), port: default_web_port() }
+    }
+}
 
-    let reader = BufReader::new(file);
+impl WebConfig {
+    /// `host:port` the web interface listens on
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn default_web_port() -> u16 {
+    8080
+}
+
+/// Whether two `host:port` listen addresses would take the same port, counting `0.0.0.0` as every address
+pub fn same_listen_address(first: &str, second: &str) -> bool {
+    let resolve = |address: &str| address.to_socket_addrs().map(|addresses| addresses.collect::<Vec<_>>()).unwrap_or_default();
+    let second = resolve(second);
+    resolve(first).iter().any(|first| {
+        second.iter().any(|second| {
+            first.port() == second.port()
+                && (first.ip() == second.ip() || first.ip().is_unspecified() || second.ip().is_unspecified())
+        })
+    })
+}
+
+fn default_watch_interval() -> u64 {
+    30
+}
+
+/// Why the settings cannot be loaded
+#[derive(Debug)]
+pub enum SettingsError {
+    /// No settings file was found and no `ALMAMBET__` variable is set
+    NotFound,
+    /// The settings file exists but cannot be read
+    Read { path: PathBuf, source: std::io::Error },
+    /// The YAML is malformed or does not describe valid settings; the position is known unless
+    /// environment overrides were applied
+    Parse { path: Option<PathBuf>, line: Option<usize>, column: Option<usize>, message: String },
+    /// An `ALMAMBET__` environment variable cannot be applied
+    Override { variable: String, message: String },
+}
 
-    match yaml_serde::from_reader(reader) {
-        Ok(config) => Ok(config),
-        Err(err) => {
-            error!("Error parsing config: {}", err);
-            panic!("Cannot deserialize settings: {}", err);
+impl SettingsError {
+    fn parse(path: Option<&Path>, err: Error) -> Self {
+        let location = err.location();
+        let mut message = err.to_string();
+        // The position is reported separately
+        if let Some(location) = &location {
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            if let Some(stripped) = message.strip_suffix(&suffix) {
+                message = stripped.to_string();
+            }
+        }
+        SettingsError::Parse {
+            path: path.map(Path::to_path_buf),
+            line: location.as_ref().map(|location| location.line()),
+            column: location.as_ref().map(|location| location.column()),
+            message,
         }
     }
 }
 
-fn find_config_file() -> Option<PathBuf> {
-    // Build the list of possible paths, handling Options properly
-    let mut possible_paths: Vec<PathBuf> = Vec::new();
-    
-    // 1. Current working directory
-    possible_paths.push(PathBuf::from("settings.yaml"));
-    
-    // 2. Config directory on Unix/Linux
-    if let Some(config_dir) = dirs::config_dir() {
-        possible_paths.push(config_dir.join("myapp/settings.yaml"));
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::NotFound => write!(
+                f,
+                "No settings file found: pass one with --config, put settings.yaml in one of the configuration \
+                directories or set {}, or set the {}... variables",
+                CONFIG_DIR_VARIABLE, OVERRIDE_PREFIX
+            ),
+            SettingsError::Read { path, source } => write!(f, "Cannot read {}: {}", path.display(), source),
+            SettingsError::Parse { path, line, column, message } => {
+                match path {
+                    Some(path) => write!(f, "{}", path.display())?,
+                    None => write!(f, "Settings from the environment")?,
+                }
+                match (line, column) {
+                    (Some(line), Some(column)) => write!(f, ":{}:{}: {}", line, column, message),
+                    _ => write!(f, ": {}", message),
+                }
+            }
+            SettingsError::Override { variable, message } => write!(f, "Invalid {} variable: {}", variable, message),
+        }
     }
-    
-    // 3. User's home config directory on Windows (same as above but different app name)
-    if let Some(config_dir) = dirs::config_dir() {
-        possible_paths.push(config_dir.join("MyApp").join("settings.yaml"));
+}
+
+impl std::error::Error for SettingsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SettingsError::Read { source, .. } => Some(source),
+            _ => None,
+        }
     }
-    
-    // 4. Relative to the executable (for production deployments)
-    if let Ok(exe_path) = std::env::current_exe() {
-        if let Some(exe_dir) = exe_path.parent() {
-            possible_paths.push(exe_dir.join("settings.yaml"));
+}
+
+/// Load the settings file, with the `ALMAMBET__` environment overrides applied
+pub fn load_settings() -> Result<Config, SettingsError> {
+    let overrides = env_overrides();
+    let config_path = find_config_file();
+    // Without overrides the file is deserialized directly, so errors point at its lines
+    if overrides.is_empty() {
+        let config_path = config_path.ok_or(SettingsError::NotFound)?;
+        return parse_settings(&read_settings_file(&config_path)?, Some(&config_path));
+    }
+
+    // A container may have no file at all, every setting coming from the environment
+    let mut document = match &config_path {
+        Some(config_path) => {
+            let text = read_settings_file(config_path)?;
+            yaml_serde::from_str(&text).map_err(|err| SettingsError::parse(Some(config_path), err))?
         }
+        None => Value::Mapping(Mapping::new()),
+    };
+    for (path, value) in &overrides {
+        apply_override(&mut document, path, value)
+            .map_err(|message| SettingsError::Override { variable: variable_name(path), message })?;
     }
-    
-    // 5. Project source directory (development fallback)
-    possible_paths.push(PathBuf::from("src/resources/settings.yaml"));
 
-    // Find the first existing file
-    for path in possible_paths {
-        if path.exists() && path.is_file() {
-            return Some(path);
+    yaml_serde::from_value(document).map_err(|err| SettingsError::parse(config_path.as_deref(), err))
+}
+
+/// Deserialize settings read from `path`, which only serves the error messages
+pub fn parse_settings(text: &str, path: Option<&Path>) -> Result<Config, SettingsError> {
+    yaml_serde::from_str(text).map_err(|err| SettingsError::parse(path, err))
+}
+
+fn read_settings_file(config_path: &Path) -> Result<String, SettingsError> {
+    std::fs::read_to_string(config_path).map_err(|source| SettingsError::Read { path: config_path.to_path_buf(), source })
+}
+
+/// Names of the `ALMAMBET__` variables overriding settings
+pub fn override_variables() -> Vec<String> {
+    env_overrides().iter().map(|(path, _)| variable_name(path)).collect()
+}
+
+fn variable_name(path: &[String]) -> String {
+    format!("{}{}", OVERRIDE_PREFIX, path.join("__").to_uppercase())
+}
+
+// The `ALMAMBET__...` environment variables, as the lowercase path of the setting and its value
+fn env_overrides() -> Vec<(Vec<String>, String)> {
+    let mut overrides: Vec<(Vec<String>, String)> = std::env::vars()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(OVERRIDE_PREFIX)?;
+            Some((path.split("__").map(str::to_lowercase).collect(), value))
+        })
+        .collect();
+    sort_overrides(&mut overrides);
+    overrides
+}
+
+// A part of the path of a setting: a position in a list compares as a number, so that 2 comes before 10
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum PathSegment<'a> {
+    Position(usize),
+    Key(&'a str),
+}
+
+/// Put overrides in the order they are applied: whole sections before the fields inside them, and the
+/// items of a list by position, since a list can only grow one item at a time
+pub fn sort_overrides(overrides: &mut [(Vec<String>, String)]) {
+    fn segments(path: &[String]) -> Vec<PathSegment<'_>> {
+        path.iter()
+            .map(|key| key.parse().map_or(PathSegment::Key(key), PathSegment::Position))
+            .collect()
+    }
+    overrides.sort_by(|(first, first_value), (second, second_value)| {
+        segments(first).cmp(&segments(second)).then_with(|| first_value.cmp(second_value))
+    });
+}
+
+/// Set the setting at `path` (e.g. `["imap", "server"]`) of a settings document, creating the
+/// sections on the way; a number in the path is a position in a list, e.g. `["webhooks", "0", "url"]`.
+///
+/// The value is read as YAML, so `993` is a number, `true` a boolean and `[a, b]` a list;
+/// a text looking like a number must be quoted.
+pub fn apply_override(document: &mut Value, path: &[String], value: &str) -> Result<(), String> {
+    let mut node = document;
+    for key in path {
+        if node.is_null() {
+            *node = match key.parse::<usize>() {
+                Ok(_) => Value::Sequence(Vec::new()),
+                Err(_) => Value::Mapping(Mapping::new()),
+            };
         }
+        node = match node {
+            Value::Mapping(mapping) => mapping.entry(Value::String(key.clone())).or_insert(Value::Null),
+            Value::Sequence(sequence) => {
+                let index: usize = key.parse().map_err(|_| format!("{} is a list, expected a position", key))?;
+                if index == sequence.len() {
+                    sequence.push(Value::Null);
+                }
+                sequence.get_mut(index).ok_or_else(|| format!("position {} is past the end of the list", index))?
+            }
+            _ => return Err(format!("cannot set {} inside a value that is not a section", key)),
+        };
+    }
+
+    *node = match yaml_serde::from_str(value) {
+        // An empty value is an empty text rather than nothing
+        Ok(Value::Null) if !matches!(value.trim(), "null" | "~") => Value::String(value.to_string()),
+        Ok(parsed) => parsed,
+        Err(_) => Value::String(value.to_string()),
+    };
+    Ok(())
+}
+
+/// Use `path` as the settings file instead of searching for one
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+/// Path of the settings file in use, if any
+pub fn config_file_path() -> Option<PathBuf> {
+    find_config_file()
+}
+
+fn find_config_file() -> Option<PathBuf> {
+    if let Some(path) = CONFIG_PATH.get() {
+        return Some(path.clone());
+    }
+    find_in_config_dirs(&["settings.yaml"])
+}
+
+// Print the deprecation warning of a file once, as the files are looked for often
+fn warn_deprecated(path: &Path) {
+    static WARNED: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+    let mut warned = WARNED.get_or_init(|| Mutex::new(HashSet::new())).lock().unwrap();
+    if warned.insert(path.to_path_buf()) {
+        warn!("Using {:?}: the myapp directories are deprecated, move the file to an almambet directory", path);
     }
+}
 
+/// First existing file named like one of `names`, in this order: in `$ALMAMBET_CONFIG_DIR`, in the
+/// working directory, in the user configuration directory (`~/.config/almambet` on Linux), next to
+/// the executable, in `/etc/almambet`, then in `src/resources` while developing. The former `myapp`
+/// directories are still searched, after the matching `almambet` one, with a warning.
+pub fn find_in_config_dirs(names: &[&str]) -> Option<PathBuf> {
+    for (directory, deprecated) in config_dirs() {
+        for name in names {
+            let path = directory.join(name);
+            if path.is_file() {
+                if deprecated {
+                    warn_deprecated(&path);
+                }
+                return Some(path);
+            }
+        }
+    }
     None
-}
\ No newline at end of file
+}
+
+/// Whether `path` was found in one of the deprecated `myapp` directories
+pub fn in_deprecated_dir(path: &Path) -> bool {
+    config_dirs().into_iter().any(|(directory, deprecated)| deprecated && path.parent() == Some(directory.as_path()))
+}
+
+// The directories searched by `find_in_config_dirs` in order, and whether they are deprecated
+fn config_dirs() -> Vec<(PathBuf, bool)> {
+    let mut directories: Vec<(PathBuf, bool)> = Vec::new();
+
+    if let Some(config_dir) = std::env::var_os(CONFIG_DIR_VARIABLE) {
+        directories.push((PathBuf::from(config_dir), false));
+    }
+    directories.push((PathBuf::new(), false));
+    if let Some(config_dir) = dirs::config_dir() {
+        directories.push((config_dir.join("almambet"), false));
+        directories.push((config_dir.join("myapp"), true));
+        directories.push((config_dir.join("MyApp"), true));
+    }
+    if let Some(exe_dir) = std::env::current_exe().ok().as_deref().and_then(Path::parent) {
+        directories.push((exe_dir.to_path_buf(), false));
+    }
+    directories.push((PathBuf::from("/etc/almambet"), false));
+    directories.push((PathBuf::from("/etc/myapp"), true));
+    directories.push((PathBuf::from("src/resources"), false));
+    directories
+}
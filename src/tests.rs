@@ -1,14 +1,32 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     
-    use crate::mail_move_rules::check_message_matches;
-    use crate::mail_move_rules::mail_move_settings::Rule;
+    use crate::mail_move_rules::{check_message_matches, first_matching_rule, domain_pattern, list_id_pattern, sender_pattern, subject_pattern};
+    use crate::mail_move_rules::mail_move_settings::{load_mail_move_config, update_mail_move_config, Rule, RuleWrapper, RulesConfig};
+    use crate::mail_reader::encryption::CredentialStore;
+    use crate::mail_reader::attachment::{content_disposition, is_attachment_part, is_previewable};
+    use crate::mail_reader::flags::{replace_query, store_query};
+    use crate::mail_reader::html::sanitize_email_html;
     use crate::mail_reader::message::Message;
+    use crate::mail_reader::search::SearchQuery;
+    use crate::mail_sender::compose::Draft;
+    use crate::mail_sender::smtp::send_draft;
+    use crate::mail_reader::thread::{assign_thread_ids, group_threads, parse_message_ids, synthetic_id_uid};
+    use crate::web_services::{account_request, parse_fields, select_fields};
+    use crate::web_services::openapi::ApiDoc;
+    use crate::webhooks::{backoff_secs, signature, WebhookQueue};
+    use crate::health;
+    use crate::metrics;
+    use crate::mail_move_rules::scheduler::{failure_backoff, parse_cron, period};
+    use crate::settings::{apply_override, parse_settings, same_listen_address, sort_overrides, Config, MailMoverConfig, OverlapPolicy, Schedule, SettingsError, WebhookEvent};
+    use std::time::Duration;
+    use utoipa::OpenApi;
     
     #[test]
     fn test_mail_mover_matches_domain() {
         let settings = Rule {
-            from: Some(vec![r"nuovapromo\\.it$".to_string()]),
+            from: Some(vec![r"nuovapromo\.it$".to_string()]),
             ..Default::default()
         };
         
@@ -34,4 +52,859 @@ mod tests {
         
         assert!(!check_message_matches(&good_message, &settings));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_search_query_translates_to_imap_criteria() {
+        let query = SearchQuery::parse(r#"from:"John Doe" subject:invoice since:2024-02-01 is:unread"#).unwrap();
+
+        assert_eq!(
+            query.to_imap(),
+            r#"CHARSET UTF-8 FROM "John Doe" SUBJECT "invoice" SINCE 1-Feb-2024 UNSEEN"#
+        );
+    }
+
+    #[test]
+    fn test_search_query_bare_words_and_attachments() {
+        let query = SearchQuery::parse("has:attachment report").unwrap();
+
+        assert_eq!(
+            query.to_imap(),
+            r#"CHARSET UTF-8 HEADER Content-Type "multipart/mixed" TEXT "report""#
+        );
+    }
+
+    #[test]
+    fn test_search_query_rejects_bad_input() {
+        assert!(SearchQuery::parse("since:yesterday").is_err());
+        assert!(SearchQuery::parse("is:important").is_err());
+        assert!(SearchQuery::parse(r#"from:"unterminated"#).is_err());
+    }
+
+    #[test]
+    fn test_html_sanitizer_strips_active_content() {
+        let html = r#"<p onclick="steal()">Hi</p><script>alert(1)</script><form action="/x"><input name="p"></form>"#;
+
+        let sanitized = sanitize_email_html(html, false, "/cid");
+
+        assert!(!sanitized.html.contains("onclick"));
+        assert!(!sanitized.html.contains("script"));
+        assert!(!sanitized.html.contains("<form"));
+        assert!(!sanitized.html.contains("<input"));
+        assert!(sanitized.html.contains("Hi"));
+    }
+
+    #[test]
+    fn test_html_sanitizer_blocks_remote_images_and_rewrites_cid() {
+        let html = r#"<img src="https://tracker.example/pixel.gif"><img src="cid:logo@example">"#;
+
+        let blocked = sanitize_email_html(html, false, "/email/INBOX/1/cid");
+        assert_eq!(blocked.blocked_remote, 1);
+        assert!(!blocked.html.contains("tracker.example"));
+        assert!(blocked.html.contains(r#"src="/email/INBOX/1/cid/logo%40example""#));
+
+        let allowed = sanitize_email_html(html, true, "/email/INBOX/1/cid");
+        assert_eq!(allowed.blocked_remote, 0);
+        assert!(allowed.html.contains("tracker.example"));
+    }
+
+    #[test]
+    fn test_attachment_content_disposition_and_preview() {
+        assert_eq!(
+            content_disposition("report \"final\".pdf", false),
+            "attachment; filename=\"report _final_.pdf\"; filename*=UTF-8''report%20%22final%22.pdf"
+        );
+        assert!(content_disposition("photo.jpg", true).starts_with("inline;"));
+
+        assert!(is_previewable("image/png"));
+        assert!(is_previewable("application/pdf; name=\"a.pdf\""));
+        assert!(!is_previewable("image/svg+xml"));
+        assert!(!is_previewable("text/html"));
+
+        assert!(is_attachment_part("application/pdf", true, false));
+        assert!(is_attachment_part("image/png", false, true));
+        assert!(!is_attachment_part("text/html", false, true));
+        assert!(!is_attachment_part("text/plain", false, false));
+        // A multipart with an attachment disposition is listed through its parts
+        assert!(!is_attachment_part("multipart/mixed", true, false));
+    }
+
+    fn threaded_message(id: &str, subject: &str, date: &str, references: &[&str]) -> Message {
+        Message {
+            message_id: Some(id.to_string()),
+            subject: subject.to_string(),
+            date: date.to_string(),
+            references: references.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_threading_follows_references() {
+        let mut messages = vec![
+            threaded_message("<c@x>", "Re: Plan", "Wed, 3 Jan 2024 10:00:00 +0000", &["<a@x>", "<b@x>"]),
+            threaded_message("<other@x>", "Unrelated", "Wed, 3 Jan 2024 09:00:00 +0000", &[]),
+            threaded_message("<b@x>", "Re: Plan", "Tue, 2 Jan 2024 10:00:00 +0000", &["<a@x>"]),
+        ];
+
+        assign_thread_ids(&mut messages);
+
+        // The root message was not fetched, its id still names the thread
+        assert_eq!(messages[0].thread_id.as_deref(), Some("<a@x>"));
+        assert_eq!(messages[2].thread_id.as_deref(), Some("<a@x>"));
+        assert_eq!(messages[1].thread_id.as_deref(), Some("<other@x>"));
+
+        let threads = group_threads(messages);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].id, "<a@x>");
+        assert_eq!(threads[0].messages[0].message_id.as_deref(), Some("<b@x>"));
+        assert_eq!(threads[0].messages[1].message_id.as_deref(), Some("<c@x>"));
+    }
+
+    #[test]
+    fn test_threading_groups_replies_without_references_by_subject() {
+        let mut messages = vec![
+            threaded_message("<reply@x>", "RE: Fwd: Invoice 42", "Tue, 2 Jan 2024 10:00:00 +0000", &[]),
+            threaded_message("<original@x>", "Invoice 42", "Mon, 1 Jan 2024 10:00:00 +0000", &[]),
+        ];
+
+        assign_thread_ids(&mut messages);
+
+        assert_eq!(messages[0].thread_id.as_deref(), Some("<original@x>"));
+        assert_eq!(messages[1].thread_id.as_deref(), Some("<original@x>"));
+    }
+
+    #[test]
+    fn test_threading_id_of_message_without_message_id_only_depends_on_its_uid() {
+        let without_id = |uid| Message { uid: Some(uid), subject: "No id".to_string(), ..Default::default() };
+        let mut alone = vec![without_id(7)];
+        let mut among_others = vec![threaded_message("<a@x>", "Other", "Mon, 1 Jan 2024 10:00:00 +0000", &[]), without_id(7)];
+
+        assign_thread_ids(&mut alone);
+        assign_thread_ids(&mut among_others);
+
+        assert_eq!(alone[0].thread_id, among_others[1].thread_id);
+        assert_eq!(synthetic_id_uid(alone[0].thread_id.as_deref().unwrap()), Some(7));
+    }
+
+    #[test]
+    fn test_parse_message_ids() {
+        assert_eq!(
+            parse_message_ids("<a@x>\r\n <b@y> junk <c@z>"),
+            vec!["<a@x>", "<b@y>", "<c@z>"]
+        );
+        assert!(parse_message_ids("no ids here").is_empty());
+    }
+
+    #[test]
+    fn test_rule_from_sender_matches_only_that_sender() {
+        let settings = Rule {
+            from: Some(vec![sender_pattern("Deals <promo@shop.example>").unwrap()]),
+            ..Default::default()
+        };
+
+        let same_sender = Message {
+            from: "Other Name <PROMO@shop.example>".to_string(),
+            ..Default::default()
+        };
+        let other_sender = Message {
+            from: "notpromo@shop.example".to_string(),
+            ..Default::default()
+        };
+
+        assert!(check_message_matches(&same_sender, &settings));
+        assert!(!check_message_matches(&other_sender, &settings));
+    }
+
+    #[test]
+    fn test_rule_validation() {
+        let valid = Rule {
+            target_folder: "Spam".to_string(),
+            title: Some(vec!["^Win".to_string()]),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_empty());
+
+        let invalid = Rule {
+            target_folder: " ".to_string(),
+            from: Some(vec!["(unclosed".to_string()]),
+            ..Default::default()
+        };
+        let errors = invalid.validate();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[1].starts_with("from: invalid regex"));
+
+        assert_eq!(Rule { target_folder: "Spam".to_string(), ..Default::default() }.validate().len(), 1);
+    }
+
+    #[test]
+    fn test_rule_suggestions_from_message() {
+        assert_eq!(domain_pattern("Shop <news@shop.example>").as_deref(), Some(r"(?i)@shop\.example>?$"));
+        assert_eq!(subject_pattern("[Jira] Issue updated"), r"^\[Jira\]");
+        assert_eq!(subject_pattern("Invoice: March 2024"), "^Invoice:");
+        assert_eq!(subject_pattern("Re: lunch?"), r"^Re: lunch\?$");
+
+        let message = Message {
+            from: "Other <info@lists.example>".to_string(),
+            list_id: Some("news.lists.example".to_string()),
+            ..Default::default()
+        };
+        let rule = Rule {
+            target_folder: "Lists".to_string(),
+            list_id: Some(vec![list_id_pattern("news.lists.example")]),
+            ..Default::default()
+        };
+        assert!(check_message_matches(&message, &rule));
+        assert!(!check_message_matches(&Message { list_id: Some("other.lists.example".to_string()), ..message }, &rule));
+    }
+
+    #[test]
+    fn test_reply_all_threads_and_skips_own_address() {
+        let original = Message {
+            subject: "Lunch".to_string(),
+            from: "Alice <alice@example.com>".to_string(),
+            to: Some("me@example.com, Bob <bob@example.com>".to_string()),
+            cc: Some("alice@example.com, carol@example.com".to_string()),
+            message_id: Some("<2@example.com>".to_string()),
+            references: vec!["<1@example.com>".to_string()],
+            content: Some("Pizza?".to_string()),
+            ..Default::default()
+        };
+
+        let draft = Draft::reply(&original, true, "Me <me@example.com>");
+        assert_eq!(draft.to, "Alice <alice@example.com>");
+        assert_eq!(draft.cc, "Bob <bob@example.com>, carol@example.com");
+        assert_eq!(draft.subject, "Re: Lunch");
+        assert!(draft.body.ends_with("wrote:\n> Pizza?\n"));
+        assert_eq!(Draft::reply(&Message { subject: "RE: Lunch".to_string(), ..original.clone() }, false, "").subject, "RE: Lunch");
+
+        let sent = String::from_utf8(draft.build("me@example.com").unwrap().formatted()).unwrap();
+        assert!(sent.contains("In-Reply-To: <2@example.com>\r\n"));
+        assert!(sent.contains("References: <1@example.com> <2@example.com>\r\n"));
+
+        assert!(Draft { to: " ".to_string(), cc: String::new(), ..draft }.build("me@example.com").is_err());
+    }
+
+    /// What a local SMTP sink received in one session
+    struct ReceivedMail {
+        sender: String,
+        recipients: Vec<String>,
+        data: String,
+    }
+
+    // Accept one SMTP session on a local port, answering every command positively
+    async fn smtp_sink() -> (u16, tokio::task::JoinHandle<ReceivedMail>) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let session = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut received = ReceivedMail { sender: String::new(), recipients: Vec::new(), data: String::new() };
+            writer.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let command = line.to_uppercase();
+                let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+                    b"250 sink\r\n"
+                } else if command.starts_with("MAIL FROM:") {
+                    received.sender = line[10..].trim().to_string();
+                    b"250 OK\r\n"
+                } else if command.starts_with("RCPT TO:") {
+                    received.recipients.push(line[8..].trim().to_string());
+                    b"250 OK\r\n"
+                } else if command == "DATA" {
+                    writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await.unwrap();
+                    while let Some(data) = lines.next_line().await.unwrap() {
+                        if data == "." {
+                            break;
+                        }
+                        received.data.push_str(&data);
+                        received.data.push('\n');
+                    }
+                    b"250 OK queued\r\n"
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            received
+        });
+        (port, session)
+    }
+
+    #[tokio::test]
+    async fn test_send_draft_to_local_smtp_server() {
+        let (port, session) = smtp_sink().await;
+        // Without imap settings the copy to the sent folder fails, which is only logged
+        let config: Config = yaml_serde::from_str(&format!(
+            "server: {{host: 127.0.0.1, port: 3001}}\n\
+            mail_mover: {{check_interval: 60}}\n\
+            smtp: {{server: 127.0.0.1, port: {}, security: none, authenticate: false, from: \"Me <me@example.com>\"}}\n",
+            port
+        )).unwrap();
+        let draft = Draft {
+            to: "Alice <alice@example.com>".to_string(),
+            cc: "bob@example.com".to_string(),
+            bcc: "carol@example.com".to_string(),
+            subject: "Lunch".to_string(),
+            body: "Noon at the usual place?".to_string(),
+            in_reply_to: None,
+            references: Vec::new(),
+        };
+
+        let sent = send_draft(&config, &draft).await.unwrap();
+        let received = session.await.unwrap();
+
+        assert!(sent.message_id.is_some());
+        assert_eq!(sent.saved_to, None);
+        assert_eq!(received.sender, "<me@example.com>");
+        assert_eq!(received.recipients, vec!["<alice@example.com>", "<bob@example.com>", "<carol@example.com>"]);
+        assert!(received.data.contains("Subject: Lunch\n"));
+        assert!(received.data.contains("To: Alice <alice@example.com>"), "{}", received.data);
+        assert!(!received.data.contains("carol@example.com"), "Bcc must not be in the headers");
+        assert!(received.data.contains("Noon at the usual place?"));
+    }
+
+    #[test]
+    fn test_flag_store_queries() {
+        assert_eq!(store_query(true, &["\\Seen".to_string()]).unwrap(), "+FLAGS (\\Seen)");
+        assert_eq!(
+            store_query(false, &["\\Flagged".to_string(), "$Work".to_string()]).unwrap(),
+            "-FLAGS (\\Flagged $Work)"
+        );
+
+        assert!(store_query(true, &[]).is_err());
+        assert!(store_query(true, &["\\Recent".to_string()]).is_err());
+        assert!(store_query(true, &["two words".to_string()]).is_err());
+        assert!(store_query(true, &["bad)".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_flag_replace_query() {
+        assert_eq!(replace_query(&["\\Seen".to_string(), "$Work".to_string()]).unwrap(), "FLAGS (\\Seen $Work)");
+        assert_eq!(replace_query(&[]).unwrap(), "FLAGS ()");
+        assert!(replace_query(&["\\Recent".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_select_fields() {
+        let message = Message {
+            uid: Some(7),
+            subject: "Hello".to_string(),
+            from: "alice@example.com".to_string(),
+            ..Default::default()
+        };
+
+        let fields = parse_fields("uid, subject").unwrap();
+        let value = select_fields(&message, Some(&fields)).unwrap();
+        assert_eq!(value, serde_json::json!({"uid": 7, "subject": "Hello"}));
+
+        assert!(parse_fields("uid,password").is_err());
+        assert!(select_fields(&message, None).unwrap().get("from").is_some());
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let rule = |target: &str, pattern: &str| RuleWrapper {
+            rule: Rule {
+                target_folder: target.to_string(),
+                from: Some(vec![pattern.to_string()]),
+                ..Default::default()
+            },
+        };
+        let rules = vec![rule("Work", "@example\\.com"), rule("Alice", "^alice@")];
+        let message = Message {
+            from: "alice@example.com".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(first_matching_rule(&message, &rules), Some(0));
+        assert_eq!(first_matching_rule(&message, &rules[1..]), Some(0));
+        assert_eq!(first_matching_rule(&message, &rules[..0]), None);
+    }
+
+    #[test]
+    fn test_reorder_rules() {
+        let rule = |target: &str| RuleWrapper {
+            rule: Rule {
+                target_folder: target.to_string(),
+                ..Default::default()
+            },
+        };
+        let mut config = RulesConfig {
+            messages_to_check: 10,
+            rules: vec![rule("A"), rule("B"), rule("C")],
+        };
+
+        assert!(config.reorder(&[0, 0, 1]).is_err());
+        assert!(config.reorder(&[0, 1]).is_err());
+        config.reorder(&[2, 0, 1]).unwrap();
+        let targets: Vec<&str> = config.rules.iter().map(|wrapper| wrapper.rule.target_folder.as_str()).collect();
+        assert_eq!(targets, ["C", "A", "B"]);
+    }
+
+    #[test]
+    fn test_concurrent_rule_updates_are_all_kept() {
+        let dir = test_dir();
+        let rules_path = dir.join("rules.yaml");
+        std::fs::write(&rules_path, "messages_to_check: 10\nrules: []\n").unwrap();
+        let settings = format!(
+            "server: {{host: 127.0.0.1, port: 3000}}\nmail_mover: {{check_interval: 60}}\n\
+            accounts:\n- {{name: work, imap: {{server: imap.work.com, port: 993, username: me}}, rules: {}}}\n",
+            rules_path.display()
+        );
+        let config = yaml_serde::from_str::<Config>(&settings).unwrap().account("work").unwrap();
+
+        std::thread::scope(|scope| {
+            for n in 0..8 {
+                let config = &config;
+                scope.spawn(move || {
+                    update_mail_move_config(config, |rules_config| {
+                        rules_config.rules.push(RuleWrapper {
+                            rule: Rule { target_folder: format!("Folder {}", n), ..Default::default() },
+                        });
+                        Ok::<_, anyhow::Error>(())
+                    }).unwrap();
+                });
+            }
+        });
+
+        let mut targets: Vec<String> = load_mail_move_config(&config).unwrap().rules
+            .into_iter()
+            .map(|wrapper| wrapper.rule.target_folder)
+            .collect();
+        targets.sort();
+        assert_eq!(targets, (0..8).map(|n| format!("Folder {}", n)).collect::<Vec<_>>());
+
+        // Nothing is saved when the change fails
+        let result = update_mail_move_config(&config, |rules_config| {
+            rules_config.rules.clear();
+            Err::<(), _>(anyhow::anyhow!("no rule 9"))
+        });
+        assert!(result.is_err());
+        assert_eq!(load_mail_move_config(&config).unwrap().rules.len(), 8);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        let paths = document["paths"].as_object().unwrap();
+        for path in ["/api/v1/emails/{folder}", "/api/v1/emails/{folder}/{uid}/flags", "/api/v1/folders/{folder}", "/api/v1/rules/run", "/api/v1/send", "/readyz"] {
+            assert!(paths.contains_key(path), "missing {}", path);
+        }
+        assert!(paths["/api/v1/emails/{folder}/{uid}/flags"].get("put").is_some());
+
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        for schema in ["Message", "Attachment", "Rule", "ErrorBody"] {
+            assert!(schemas.contains_key(schema), "missing schema {}", schema);
+        }
+    }
+
+    #[test]
+    fn test_webhook_signature_and_backoff() {
+        assert_eq!(
+            signature("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+
+        assert_eq!(backoff_secs(1), 5);
+        assert_eq!(backoff_secs(2), 10);
+        assert_eq!(backoff_secs(5), 80);
+        assert_eq!(backoff_secs(40), 3600);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_delivery_to_local_receiver() {
+        use axum::http::{HeaderMap, StatusCode};
+        use std::sync::{Arc, Mutex};
+
+        // The receiver fails the first request, then accepts the retry
+        let received: Arc<Mutex<Vec<(HeaderMap, String)>>> = Arc::default();
+        let requests = received.clone();
+        let app = axum::Router::new().route("/hook", axum::routing::post(move |headers: HeaderMap, body: String| async move {
+            let mut requests = requests.lock().unwrap();
+            requests.push((headers, body));
+            if requests.len() == 1 { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let dir = test_dir();
+        let queue_file = dir.join("webhook_queue.json");
+        let queue = WebhookQueue::open(&queue_file);
+        let config: Config = yaml_serde::from_str(&format!(
+            "server: {{host: 127.0.0.1, port: 3001}}\n\
+            mail_mover: {{check_interval: 60}}\n\
+            webhooks: [{{url: \"http://127.0.0.1:{}/hook\", events: [error], secret: s3cret, max_attempts: 3}}]\n",
+            port
+        )).unwrap();
+
+        queue.notify(&config, WebhookEvent::Error, serde_json::json!({"message": "boom"}));
+        assert!(!std::fs::read_to_string(&queue_file).unwrap().contains("s3cret"));
+        assert!(queue.deliver_due().await.is_some(), "the failed delivery is retried");
+        assert_eq!(received.lock().unwrap().len(), 1);
+        // Queued deliveries are read back by the next run
+        assert!(WebhookQueue::open(&queue_file).deliver_due().await.is_some());
+
+        // The retry is due after the first backoff
+        tokio::time::timeout(Duration::from_secs(15), async {
+            while queue.deliver_due().await.is_some() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }).await.expect("the delivery is retried within the timeout");
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let (first, _) = &received[0];
+        let (headers, body) = &received[1];
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-almambet-event"], "error");
+        assert_eq!(headers["x-almambet-delivery"], first["x-almambet-delivery"]);
+        assert_eq!(headers["x-almambet-signature"].to_str().unwrap(), signature("s3cret", body.as_bytes()));
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"], "error");
+        assert_eq!(payload["account"], "default");
+        assert_eq!(payload["data"]["message"], "boom");
+        drop(received);
+
+        assert_eq!(std::fs::read_to_string(&queue_file).unwrap(), "[]");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_account_paths_keep_their_encoding() {
+        use axum::extract::{Path, Request};
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        let echo = axum::Router::new().route("/api/v1/emails/{folder}", axum::routing::get(|Path(folder): Path<String>, request: Request| async move {
+            format!("{}|{}", folder, request.uri().query().unwrap_or_default())
+        }));
+        let routers = Arc::new(HashMap::from([("work".to_string(), echo)]));
+        let forward = |uri: &str| {
+            let routers = routers.clone();
+            let request = Request::builder().uri(uri).body(axum::body::Body::empty()).unwrap();
+            async move {
+                let response = account_request(routers, "work".to_string(), request).await;
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(forward("/api/v1/accounts/work/emails/Sent%20Items?limit=5").await, (axum::http::StatusCode::OK, "Sent Items|limit=5".to_string()));
+        assert_eq!(forward("/api/v1/accounts/work/emails/a%2Fb").await, (axum::http::StatusCode::OK, "a/b|".to_string()));
+    }
+
+    #[test]
+    fn test_health_is_kept_per_account() {
+        health::record_login("health-work", &Err::<(), _>("refused"));
+        health::record_login("health-home", &Ok::<(), String>(()));
+        health::rule_run_succeeded("health-home");
+
+        let state = health::snapshot();
+        let work = state.account("health-work");
+        assert_eq!(work.last_login.unwrap().error.as_deref(), Some("refused"));
+        assert!(work.last_rule_run.is_none());
+        let home = state.account("health-home");
+        assert!(home.last_login.unwrap().error.is_none());
+        assert!(home.last_rule_run.is_some());
+    }
+
+    #[test]
+    fn test_metrics_render() {
+        metrics::rule_matched(3, None, "Spam");
+        metrics::rule_matched(0, Some("newsletters"), "News");
+        metrics::message_operation("move", 2, &Ok::<(), ()>(()));
+
+        let text = metrics::render().unwrap();
+        assert!(text.contains(r##"almambet_rule_matches_total{rule="#3",target_folder="Spam"}"##));
+        assert!(text.contains(r#"almambet_rule_matches_total{rule="newsletters",target_folder="News"}"#));
+        assert!(text.contains(r#"almambet_message_operations_total{operation="move",result="success"}"#));
+    }
+
+    #[test]
+    fn test_job_schedules() {
+        let config: MailMoverConfig = yaml_serde::from_str(
+            "check_interval: 60\noverlap: queue\njobs:\n  spam:\n    cron: \"0 30 3 * * *\"\n",
+        ).unwrap();
+        assert_eq!(config.overlap, OverlapPolicy::Queue);
+        assert_eq!(config.rules_schedule(), Schedule::Every(60));
+        assert_eq!(config.jobs.spam, Some(Schedule::Cron("0 30 3 * * *".to_string())));
+
+        assert_eq!(period(&Schedule::Every(60)).unwrap(), Duration::from_secs(60));
+        assert_eq!(period(&Schedule::Cron("0 */5 * * * *".to_string())).unwrap(), Duration::from_secs(300));
+        assert!(parse_cron("*/5 * * * *").is_err());
+
+        assert_eq!(failure_backoff(1), Duration::from_secs(30));
+        assert_eq!(failure_backoff(3), Duration::from_secs(120));
+        assert_eq!(failure_backoff(40), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_reloaded_config_validation() {
+        let rules = RulesConfig {
+            messages_to_check: 10,
+            rules: vec![
+                RuleWrapper { rule: Rule { target_folder: "Spam".to_string(), title: Some(vec!["^Win".to_string()]), ..Default::default() } },
+                RuleWrapper { rule: Rule { target_folder: "News".to_string(), from: Some(vec!["(unclosed".to_string()]), ..Default::default() } },
+            ],
+        };
+        let errors = rules.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("rule 2: from: invalid regex"));
+
+        let settings = "imap: {server: imap.example.com, port: 993, username: me}\n\
+            server: {host: 0.0.0.0, port: 3000}\n\
+            mail_mover: {check_interval: 60, jobs: {spam: {cron: \"* * *\"}}}\n\
+            webhooks: [{url: \"not a url\"}, {url: \"https://example.com/hook\"}]\n";
+        let config: Config = yaml_serde::from_str(settings).unwrap();
+        let errors = config.validate();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("mail_mover.jobs.spam: Invalid cron expression"));
+        assert!(errors[1].starts_with("webhooks[0].url"));
+    }
+
+    #[test]
+    fn test_settings_env_overrides() {
+        let mut document: yaml_serde::Value = yaml_serde::from_str(
+            "imap: {server: imap.example.com, port: 993, username: me}\nserver: {host: 0.0.0.0, port: 3000}\n",
+        ).unwrap();
+        let path = |setting: &str| setting.split('.').map(str::to_string).collect::<Vec<_>>();
+        apply_override(&mut document, &path("imap.server"), "imap.example.org").unwrap();
+        apply_override(&mut document, &path("mail_mover.check_interval"), "120").unwrap();
+        apply_override(&mut document, &path("webhooks.0.url"), "https://example.com/hook").unwrap();
+        apply_override(&mut document, &path("webhooks.0.events"), "[error]").unwrap();
+        assert!(apply_override(&mut document, &path("webhooks.2.url"), "https://example.com/other").is_err());
+        assert!(apply_override(&mut document, &path("imap.port.number"), "1").is_err());
+
+        let config: Config = yaml_serde::from_value(document).unwrap();
+        assert_eq!(config.imap().unwrap().server, "imap.example.org");
+        assert_eq!(config.imap().unwrap().port, 993);
+        assert_eq!(config.mail_mover.interval_seconds, 120);
+        assert_eq!(config.webhooks[0].url, "https://example.com/hook");
+        assert_eq!(config.webhooks[0].events.len(), 1);
+    }
+
+    #[test]
+    fn test_settings_env_overrides_of_long_lists() {
+        // Applied in the order of the environment, where WEBHOOKS__10 sorts before WEBHOOKS__2 as text
+        let mut overrides: Vec<(Vec<String>, String)> = (0..12)
+            .rev()
+            .map(|n| (vec!["webhooks".to_string(), n.to_string(), "url".to_string()], format!("https://example.com/{}", n)))
+            .collect();
+        overrides.push((vec!["server".to_string()], "{port: 3000}".to_string()));
+        overrides.push((vec!["server".to_string(), "port".to_string()], "3001".to_string()));
+        sort_overrides(&mut overrides);
+        assert_eq!(overrides[0].0, ["server"]);
+        assert_eq!(overrides[1].0, ["server", "port"]);
+
+        let mut document: yaml_serde::Value = yaml_serde::from_str("mail_mover: {check_interval: 60}\n").unwrap();
+        for (path, value) in &overrides {
+            apply_override(&mut document, path, value).unwrap();
+        }
+        let config: Config = yaml_serde::from_value(document).unwrap();
+        assert_eq!(config.server.port, 3001);
+        assert_eq!(config.webhooks.len(), 12);
+        assert_eq!(config.webhooks[2].url, "https://example.com/2");
+        assert_eq!(config.webhooks[10].url, "https://example.com/10");
+    }
+
+    #[test]
+    fn test_server_listens_on_localhost_by_default() {
+        let config: Config = yaml_serde::from_str(
+            "imap: {server: imap.example.com, port: 993, username: me}\nserver: {port: 3000}\nmail_mover: {check_interval: 60}\n",
+        ).unwrap();
+        assert_eq!(config.server.host, "127.0.0.1");
+        // The web interface runs in the same process, on another port
+        assert_eq!(config.web.address(), "127.0.0.1:8080");
+        assert_ne!(config.web.address(), config.server.address());
+    }
+
+    #[test]
+    fn test_web_and_rest_address_clash() {
+        assert!(same_listen_address("127.0.0.1:3000", "127.0.0.1:3000"));
+        assert!(same_listen_address("0.0.0.0:3000", "127.0.0.1:3000"));
+        assert!(!same_listen_address("127.0.0.1:3000", "127.0.0.1:8080"));
+        assert!(!same_listen_address("127.0.0.1:3000", "127.0.0.2:3000"));
+
+        let settings = |web: &str| format!(
+            "imap: {{server: imap.example.com, port: 993, username: me}}\nserver: {{host: 0.0.0.0, port: 3000}}\n\
+            mail_mover: {{check_interval: 60}}\n{}",
+            web
+        );
+        let config: Config = yaml_serde::from_str(&settings("")).unwrap();
+        assert!(config.web_and_rest_clash().is_none());
+        let config: Config = yaml_serde::from_str(&settings("web: {port: 3000}\n")).unwrap();
+        assert!(config.web_and_rest_clash().unwrap().contains("cannot run together"));
+    }
+
+    #[test]
+    fn test_settings_errors_have_a_location() {
+        let text = "imap:\n  server: imap.example.com\n  port: 99999\n  username: me\n";
+        let error = parse_settings(text, Some(std::path::Path::new("settings.yaml"))).unwrap_err();
+        match &error {
+            SettingsError::Parse { line, column, message, .. } => {
+                assert_eq!((*line, *column), (Some(3), Some(9)));
+                assert!(message.starts_with("imap.port: invalid value"));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(error.to_string().starts_with("settings.yaml:3:9: imap.port"));
+    }
+
+    #[test]
+    fn test_accounts() {
+        let single: Config = yaml_serde::from_str(
+            "imap: {server: imap.example.com, port: 993, username: me}\n\
+            server: {host: 0.0.0.0, port: 3000}\n\
+            mail_mover: {check_interval: 60}\n",
+        ).unwrap();
+        assert!(single.validate().is_empty());
+        assert_eq!(single.account_names(), vec!["default"]);
+        assert!(single.accounts()[0].account.is_none());
+
+        let settings = "server: {host: 0.0.0.0, port: 3000}\n\
+            mail_mover: {check_interval: 60}\n\
+            accounts:\n\
+            - {name: work, imap: {server: imap.work.com, port: 993, username: me@work.com}, rules: work_rules.yaml}\n\
+            - {name: home, imap: {server: imap.home.net, port: 993, username: me@home.net}, mail_mover: {check_interval: 600}}\n";
+        let config: Config = yaml_serde::from_str(settings).unwrap();
+        assert!(config.validate().is_empty());
+        assert_eq!(config.account_names(), vec!["work", "home"]);
+        let home = config.account("home").unwrap();
+        assert_eq!(home.imap().unwrap().server, "imap.home.net");
+        assert_eq!(home.mail_mover.interval_seconds, 600);
+        assert_eq!(config.account("work").unwrap().mail_mover.interval_seconds, 60);
+        assert!(config.account("default").is_none());
+
+        let invalid: Config = yaml_serde::from_str(&format!(
+            "imap: {{server: imap.example.com, port: 993, username: me}}\n{}\
+            - {{name: \"my work\", imap: {{server: imap.work.com, port: 993, username: me}}}}\n\
+            - {{name: home, imap: {{server: imap.work.com, port: 993, username: other}}, mail_mover: {{check_interval: 0}}}}\n",
+            settings,
+        )).unwrap();
+        let errors = invalid.validate();
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].starts_with("imap: not used when accounts are listed"));
+        assert!(errors[1].starts_with("accounts[2].name"));
+        assert!(errors[2].starts_with("accounts[3].name: \"home\" is used by another account"));
+        assert_eq!(errors[3], "accounts[3].mail_mover.check_interval: must be at least 1 second");
+    }
+
+    // A directory of the test's own, removed by the test
+    fn test_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("almambet-test-{:x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_stored_password_with_the_key_of_another_store() {
+        let dir = test_dir();
+        let store = CredentialStore::new(&dir);
+        store.store("work", "secret").unwrap();
+        assert_eq!(store.password("work").unwrap().as_deref(), Some("secret"));
+        assert_eq!(store.password("home").unwrap(), None);
+
+        // Key B replaces key A, which the password was encrypted with
+        let other_dir = test_dir();
+        CredentialStore::new(&other_dir).encryption_key().unwrap();
+        std::fs::copy(other_dir.join(".encryption_key"), dir.join(".encryption_key")).unwrap();
+        let error = store.password("work").unwrap_err().to_string();
+        assert!(error.contains("the two files do not belong together"), "{}", error);
+        assert!(error.contains("almambet credentials set --account work"), "{}", error);
+
+        std::fs::remove_file(dir.join(".encryption_key")).unwrap();
+        let error = store.password("work").unwrap_err().to_string();
+        assert!(error.contains("is missing"), "{}", error);
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_stores_share_one_private_key() {
+        let dir = test_dir();
+        // Several accounts storing their first password at once, each with a store of its own
+        std::thread::scope(|scope| {
+            for n in 0..8 {
+                let dir = &dir;
+                scope.spawn(move || CredentialStore::new(dir).store(&format!("account{}", n), &format!("secret {}", n)).unwrap());
+            }
+        });
+        let store = CredentialStore::new(&dir);
+        for n in 0..8 {
+            assert_eq!(store.password(&format!("account{}", n)).unwrap(), Some(format!("secret {}", n)));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |name: &str| std::fs::metadata(dir.join(name)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(".encryption_key"), 0o600);
+            assert_eq!(mode(".encrypted_password.account0"), 0o600);
+            store.rotate_key().unwrap();
+            assert_eq!(mode(".encryption_key"), 0o600);
+            assert_eq!(mode(".encrypted_password.account0"), 0o600);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_key_keeps_the_passwords() {
+        let dir = test_dir();
+        let store = CredentialStore::new(&dir);
+        store.store("default", "first secret").unwrap();
+        store.store("work", "second secret").unwrap();
+        let key = std::fs::read(dir.join(".encryption_key")).unwrap();
+        let encrypted = std::fs::read(dir.join(".encrypted_password.work")).unwrap();
+
+        let files = store.rotate_key().unwrap();
+        assert_eq!(files, vec![dir.join(".encrypted_password"), dir.join(".encrypted_password.work")]);
+        assert_ne!(std::fs::read(dir.join(".encryption_key")).unwrap(), key);
+        assert_ne!(std::fs::read(dir.join(".encrypted_password.work")).unwrap(), encrypted);
+        assert_eq!(store.password("default").unwrap().as_deref(), Some("first secret"));
+        assert_eq!(store.password("work").unwrap().as_deref(), Some("second secret"));
+        // No temporary file is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_key_changes_nothing_when_a_password_cannot_be_decrypted() {
+        let dir = test_dir();
+        let store = CredentialStore::new(&dir);
+        store.store("default", "first secret").unwrap();
+
+        // The password of `work` was encrypted with another key
+        let other_dir = test_dir();
+        CredentialStore::new(&other_dir).store("work", "second secret").unwrap();
+        std::fs::copy(other_dir.join(".encrypted_password.work"), dir.join(".encrypted_password.work")).unwrap();
+
+        let read_all = || {
+            [".encryption_key", ".encrypted_password", ".encrypted_password.work"]
+                .map(|name| std::fs::read(dir.join(name)).unwrap())
+        };
+        let before = read_all();
+        let error = store.rotate_key().unwrap_err().to_string();
+        assert!(error.contains(".encrypted_password.work cannot be decrypted"), "{}", error);
+        assert!(error.contains("nothing was changed"), "{}", error);
+        assert_eq!(read_all(), before);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(store.password("default").unwrap().as_deref(), Some("first secret"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(other_dir).unwrap();
+    }
+}
@@ -1,13 +1,41 @@
-use axum::{response::Html, routing::get, Router, Extension, response::Redirect};
+mod assets;
+mod compose;
+mod rules;
+
+use axum::{response::Html, routing::{get, post}, Router, Extension, response::Redirect};
+use axum::http::{header, StatusCode};
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tokio::sync::broadcast::error::RecvError;
 use tera::Tera;
+use tower::ServiceExt;
 use std::sync::Arc;
+use crate::events::{ensure_watching, publish, subscribe, MailEvent};
+use crate::mail_reader::flags::{store_query, FLAGGED, SEEN};
+use crate::mail_reader::attachment::{content_disposition, AttachmentPart};
+use crate::mail_reader::html::{content_security_policy, sanitize_email_html};
 use crate::mail_reader::message::Message;
-use crate::mail_reader::imap::{create_session, fetch_messages_from_server, find_message_by_id, move_email_with_authentication, list_imap_folders};
+use crate::mail_reader::imap::{create_session, select_mailbox, fetch_messages, fetch_attachment_content, fetch_attachment_parts, delete_messages_by_uids, fetch_message_by_uid, fetch_messages_by_uids, fetch_thread, move_email_with_authentication, move_messages_by_uids, store_flags_by_uids, list_imap_folders, search_messages};
+use crate::mail_reader::search::SearchQuery;
+use crate::mail_reader::thread::group_threads;
+use crate::mail_move_rules::sender_pattern;
+use crate::mail_move_rules::mail_move_settings::{rules_save_path, update_mail_move_config, Rule, RuleWrapper};
 use crate::settings::Config;
+use crate::metrics;
+use crate::web_services;
 use log::info;
-use anyhow::Error;
+use anyhow::{anyhow, bail, Error};
+use itertools::Itertools;
+use serde::Serialize;
 type AppError = Error;
 
+const MESSAGES_PER_PAGE: u32 = 10;
+// Remembers the account picked with the switcher
+const ACCOUNT_COOKIE: &str = "almambet_account";
+
 async fn render_error(tera: Arc<Tera>, error_message: String) -> Html<String> {
     let mut ctx = tera::Context::new();
     ctx.insert("error_message", &error_message);
@@ -17,87 +45,561 @@ async fn render_error(tera: Arc<Tera>, error_message: String) -> Html<String> {
     }
 }
 
+// Fetch the latest messages of a folder, or the ones matching the search query
+async fn load_messages(
+    config: &Config,
+    folder_name: &str,
+    search_query: &str,
+) -> Result<Vec<Message>, AppError> {
+    let query = SearchQuery::parse(search_query)?;
+    let mut imap_session = create_session(config).await?;
+
+    let messages = if query.is_empty() {
+        fetch_messages(&mut imap_session, folder_name, MESSAGES_PER_PAGE).await?
+    } else {
+        search_messages(&mut imap_session, folder_name, &query, 0, MESSAGES_PER_PAGE).await?
+    };
+
+    imap_session.logout().await?;
+    Ok(messages)
+}
+
 async fn render_messages_page(
+    config: &Config,
     folder_name: Arc<String>,
+    search_query: Arc<String>,
     messages: Arc<Vec<Message>>,
     folders: Arc<Vec<String>>,
     tera: Arc<Tera>,
 ) -> Result<Html<String>, AppError> {
     let mut ctx = tera::Context::new();
     ctx.insert("folder_name", &*folder_name);
-    ctx.insert("messages", &*messages);
+    ctx.insert("search_query", &*search_query);
+    ctx.insert("threads", &group_threads(messages.to_vec()));
     ctx.insert("folders", &*folders);
+    ctx.insert("accounts", &config.account_names());
+    ctx.insert("current_account", config.account_name());
     let html = tera.render("emails.html", &ctx)?;
     Ok(Html(html))
 }
 
+async fn load_message(
+    config: &Config,
+    folder_name: &str,
+    uid: u32,
+) -> Result<Message, AppError> {
+    let mut imap_session = create_session(config).await?;
+    let message = fetch_message_by_uid(&mut imap_session, folder_name, uid).await?;
+    imap_session.logout().await?;
+
+    message.ok_or_else(|| anyhow!("Message {} not found in {}", uid, folder_name))
+}
+
+// Download the first attachment of a message accepted by `select`
+async fn load_attachment(
+    config: &Config,
+    folder_name: &str,
+    uid: u32,
+    select: impl Fn(&AttachmentPart) -> bool,
+) -> Result<(AttachmentPart, Vec<u8>), AppError> {
+    let mut imap_session = create_session(config).await?;
+    let part = fetch_attachment_parts(&mut imap_session, folder_name, uid)
+        .await?
+        .into_iter()
+        .find(select)
+        .ok_or_else(|| anyhow!("Attachment not found in message {}", uid))?;
+    let content = fetch_attachment_content(&mut imap_session, uid, &part).await?;
+    imap_session.logout().await?;
+
+    Ok((part, content))
+}
+
+/// Serve a decoded attachment, inline only when browsers can preview it safely
+pub(crate) fn attachment_response(part: &AttachmentPart, content: Vec<u8>, inline: bool) -> Response {
+    let inline = inline && part.is_previewable();
+
+    (
+        [
+            (header::CONTENT_TYPE, part.content_type.clone()),
+            (header::CONTENT_DISPOSITION, content_disposition(&part.filename, inline)),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        content,
+    ).into_response()
+}
+
+// Base URL under which the inline parts of a message are served
+fn cid_base_url(folder_name: &str, uid: u32) -> String {
+    format!("/email/{}/{}/cid", urlencoding::encode(folder_name), uid)
+}
+
 async fn render_email_detail(
     config: &Config,
-    message_id: String,
     folder_name: String,
+    uid: u32,
+    remote_content: bool,
     tera: Arc<Tera>,
 ) -> Result<Html<String>, AppError> {
     let mut imap_session = create_session(config).await?;
-    let message = find_message_by_id(&mut imap_session, &message_id, &folder_name).await?.expect("Error");
-    
+    let mut message = fetch_message_by_uid(&mut imap_session, &folder_name, uid)
+        .await?
+        .ok_or_else(|| anyhow!("Message {} not found in {}", uid, folder_name))?;
+
+    if config.server.mark_seen_on_open && !message.seen {
+        store_flags_by_uids(&mut imap_session, &folder_name, &[uid], &store_query(true, &[SEEN.to_string()])?).await?;
+        message.seen = true;
+        message.flags.push(SEEN.to_string());
+    }
+    imap_session.logout().await?;
+
+    // Sanitize once here only to tell the user how much remote content was blocked
+    let blocked_remote = message.html_content
+        .as_deref()
+        .map(|html| sanitize_email_html(html, remote_content, &cid_base_url(&folder_name, uid)).blocked_remote)
+        .unwrap_or(0);
+
     let mut ctx = tera::Context::new();
     ctx.insert("message", &message);
+    ctx.insert("folder_name", &folder_name);
+    ctx.insert("remote_content", &remote_content);
+    ctx.insert("blocked_remote", &blocked_remote);
     let html = tera.render("email_detail.html", &ctx)?;
     Ok(Html(html))
 }
 
+// Sanitized HTML body, displayed inside a sandboxed iframe of the detail page
+async fn render_email_body(
+    config: &Config,
+    folder_name: String,
+    uid: u32,
+    remote_content: bool,
+) -> Result<Response, AppError> {
+    let message = load_message(config, &folder_name, uid).await?;
+    let html = message.html_content
+        .ok_or_else(|| anyhow!("Message {} has no HTML body", uid))?;
+    let sanitized = sanitize_email_html(&html, remote_content, &cid_base_url(&folder_name, uid));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CONTENT_SECURITY_POLICY, content_security_policy(remote_content)),
+        ],
+        sanitized.html,
+    ).into_response())
+}
+
+// Inline part referenced from the HTML body through a cid: URL
+async fn inline_part(
+    config: &Config,
+    folder_name: String,
+    uid: u32,
+    content_id: String,
+) -> Result<Response, AppError> {
+    let (part, content) = load_attachment(config, &folder_name, uid, |part| {
+        part.content_id.as_deref() == Some(content_id.as_str())
+    }).await?;
+
+    Ok(attachment_response(&part, content, true))
+}
+
+async fn download_attachment(
+    config: &Config,
+    folder_name: String,
+    uid: u32,
+    index: usize,
+    inline: bool,
+) -> Result<Response, AppError> {
+    let (part, content) = load_attachment(config, &folder_name, uid, |part| part.index == index).await?;
+    Ok(attachment_response(&part, content, inline))
+}
+
+async fn render_thread(
+    config: &Config,
+    folder_name: String,
+    thread_id: String,
+    tera: Arc<Tera>,
+) -> Result<Html<String>, AppError> {
+    let mut imap_session = create_session(config).await?;
+    let messages = fetch_thread(&mut imap_session, &folder_name, &thread_id).await?;
+    imap_session.logout().await?;
+
+    let thread = group_threads(messages)
+        .into_iter()
+        .find(|thread| thread.id == thread_id)
+        .ok_or_else(|| anyhow!("Conversation {} not found in {}", thread_id, folder_name))?;
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("thread", &thread);
+    ctx.insert("folder_name", &folder_name);
+    let html = tera.render("thread.html", &ctx)?;
+    Ok(Html(html))
+}
+
+/// A bulk action submitted from the message list
+#[derive(Debug, Default)]
+struct BulkRequest {
+    action: String,
+    uids: Vec<u32>,
+    target_folder: Option<String>,
+    keyword: Option<String>,
+}
+
+/// Outcome of a bulk action, displayed on the results summary page
+#[derive(Debug, Serialize)]
+struct BulkResult {
+    action: String,
+    folder_name: String,
+    count: usize,
+    success: bool,
+    details: Vec<String>,
+}
+
+// Decode an application/x-www-form-urlencoded body, keeping repeated keys
+fn parse_form(body: &[u8]) -> Vec<(String, String)> {
+    let decode = |value: &str| {
+        let value = value.replace('+', " ");
+        urlencoding::decode(&value)
+            .map(|decoded| decoded.into_owned())
+            .unwrap_or(value)
+    };
+
+    String::from_utf8_lossy(body)
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn parse_bulk_request(body: &[u8]) -> BulkRequest {
+    let mut request = BulkRequest::default();
+
+    for (key, value) in parse_form(body) {
+        match key.as_str() {
+            "action" => request.action = value,
+            "target_folder" if !value.is_empty() => request.target_folder = Some(value),
+            "keyword" if !value.trim().is_empty() => request.keyword = Some(value.trim().to_string()),
+            // A checkbox covers a whole conversation, so its value may hold several UIDs
+            "uid" => request.uids.extend(value.split(',').filter_map(|uid| uid.trim().parse::<u32>().ok())),
+            _ => {}
+        }
+    }
+
+    request.uids.sort_unstable();
+    request.uids.dedup();
+    request
+}
+
+// STORE query and summary of a flag changing action, shared by bulk and single message actions
+fn flag_action_query(action: &str, keyword: Option<&str>) -> Result<(String, String), AppError> {
+    let keyword = || -> Result<Vec<String>, AppError> {
+        keyword
+            .map(|keyword| vec![keyword.to_string()])
+            .ok_or_else(|| anyhow!("No keyword given"))
+    };
+
+    let (query, description) = match action {
+        "mark_read" => (store_query(true, &[SEEN.to_string()])?, "Marked as read".to_string()),
+        "mark_unread" => (store_query(false, &[SEEN.to_string()])?, "Marked as unread".to_string()),
+        "flag" => (store_query(true, &[FLAGGED.to_string()])?, "Flagged".to_string()),
+        "unflag" => (store_query(false, &[FLAGGED.to_string()])?, "Flag removed".to_string()),
+        "add_keyword" => {
+            let keywords = keyword()?;
+            (store_query(true, &keywords)?, format!("Keyword {} added", keywords[0]))
+        }
+        "remove_keyword" => {
+            let keywords = keyword()?;
+            (store_query(false, &keywords)?, format!("Keyword {} removed", keywords[0]))
+        }
+        other => bail!("Unknown flag action '{}'", other),
+    };
+
+    Ok((query, description))
+}
+
+// Change the flags of one message from the list or the detail page, then go back there
+async fn change_message_flags(
+    config: &Config,
+    folder_name: &str,
+    uid: u32,
+    body: &[u8],
+) -> Result<Redirect, AppError> {
+    let form = parse_form(body);
+    let field = |name: &str| form
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+
+    let action = field("action").unwrap_or_default();
+    let (query, _) = flag_action_query(&action, field("keyword").as_deref())?;
+
+    let mut imap_session = create_session(config).await?;
+    store_flags_by_uids(&mut imap_session, folder_name, &[uid], &query).await?;
+    imap_session.logout().await?;
+
+    // Only local paths, so the form cannot be used to redirect elsewhere
+    let return_to = field("return_to")
+        .filter(|path| path.starts_with('/') && !path.starts_with("//"))
+        .unwrap_or_else(|| format!("/email/{}/{}", urlencoding::encode(folder_name), uid));
+    Ok(Redirect::to(&return_to))
+}
+
+// Run one bulk action as a single batched IMAP operation, returning a summary
+async fn run_bulk_action(
+    config: &Config,
+    folder_name: &str,
+    request: &BulkRequest,
+) -> Result<Vec<String>, AppError> {
+    if request.uids.is_empty() {
+        bail!("No messages selected");
+    }
+    let target_folder = || request.target_folder
+        .clone()
+        .ok_or_else(|| anyhow!("No target folder selected"));
+
+    let mut imap_session = create_session(config).await?;
+    let uids = &request.uids;
+
+    let details = match request.action.as_str() {
+        "move" => {
+            let target_folder = target_folder()?;
+            move_messages_by_uids(&mut imap_session, folder_name, uids, &target_folder).await?;
+            for &uid in uids {
+                publish(config, MailEvent::Moved {
+                    folder: folder_name.to_string(),
+                    target_folder: target_folder.clone(),
+                    uid: Some(uid),
+                    message_id: None,
+                });
+            }
+            vec![format!("Moved to {}", target_folder)]
+        }
+        "delete" => {
+            delete_messages_by_uids(&mut imap_session, folder_name, uids).await?;
+            for &uid in uids {
+                publish(config, MailEvent::Deleted {
+                    folder: folder_name.to_string(),
+                    uid: Some(uid),
+                    message_id: None,
+                });
+            }
+            vec!["Deleted permanently".to_string()]
+        }
+        "mark_read" | "mark_unread" | "flag" | "unflag" | "add_keyword" | "remove_keyword" => {
+            let (query, description) = flag_action_query(&request.action, request.keyword.as_deref())?;
+            store_flags_by_uids(&mut imap_session, folder_name, uids, &query).await?;
+            vec![description]
+        }
+        "create_rule" => {
+            let target_folder = target_folder()?;
+            select_mailbox(&mut imap_session, folder_name).await?;
+            let messages = fetch_messages_by_uids(&mut imap_session, uids).await?;
+            let patterns: Vec<String> = messages
+                .iter()
+                .filter_map(|message| sender_pattern(&message.from))
+                .unique()
+                .collect();
+            if patterns.is_empty() {
+                bail!("No sender address found in the selected messages");
+            }
+
+            update_mail_move_config(config, |rules_config| {
+                rules_config.rules.push(RuleWrapper {
+                    rule: Rule {
+                        target_folder: target_folder.clone(),
+                        from: Some(patterns.clone()),
+                        ..Default::default()
+                    },
+                });
+                Ok::<_, AppError>(())
+            })?;
+            let path = rules_save_path(config);
+
+            let mut details = vec![format!("Rule moving to {} saved in {}", target_folder, path.display())];
+            details.extend(patterns.into_iter().map(|pattern| format!("from: {}", pattern)));
+            details
+        }
+        other => bail!("Unknown bulk action '{}'", other),
+    };
+
+    imap_session.logout().await?;
+    Ok(details)
+}
+
+async fn render_bulk_result(
+    config: &Config,
+    folder_name: String,
+    body: &[u8],
+    tera: Arc<Tera>,
+) -> Result<Html<String>, AppError> {
+    let request = parse_bulk_request(body);
+
+    let result = match run_bulk_action(config, &folder_name, &request).await {
+        Ok(details) => BulkResult {
+            action: request.action.clone(),
+            folder_name,
+            count: request.uids.len(),
+            success: true,
+            details,
+        },
+        Err(e) => BulkResult {
+            action: request.action.clone(),
+            folder_name,
+            count: request.uids.len(),
+            success: false,
+            details: vec![e.to_string()],
+        },
+    };
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("result", &result);
+    let html = tera.render("bulk_result.html", &ctx)?;
+    Ok(Html(html))
+}
+
 async fn move_message(
     message_id: String,
     target_folder: String,
     config: &Config,
 ) -> Result<Redirect, AppError> {
     let mut imap_session = create_session(config).await?;
-    let _ = move_email_with_authentication(&mut imap_session, message_id, "INBOX", &target_folder).await;
+    if move_email_with_authentication(&mut imap_session, message_id.clone(), "INBOX", &target_folder).await.is_ok() {
+        publish(config, MailEvent::Moved {
+            folder: "INBOX".to_string(),
+            target_folder,
+            uid: None,
+            message_id: Some(message_id),
+        });
+    }
 
     Ok(Redirect::to("/"))
 }
 
-async fn start_server(router: Router) -> Result<(), AppError> {
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    info!("Server running on http://localhost:3000");
-    axum::serve(listener, router).await?;
-    Ok(())
+// Stream the events of `account` to the browser as Server-Sent Events, until the server shuts down
+fn event_stream(account: String, shutdown: CancellationToken) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = futures::stream::unfold((subscribe(), account), |(mut receiver, account)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(published) if published.concerns(&account) => {
+                    let event = Event::default().event(published.event.name()).json_data(&published.event);
+                    return Some((event, (receiver, account)));
+                }
+                Ok(_) => {}
+                // A slow client missed some events, the next ones are still worth sending
+                Err(RecvError::Lagged(skipped)) => info!("Event stream skipped {} events", skipped),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream.take_until(shutdown.cancelled_owned())).keep_alive(KeepAlive::default())
 }
 
+
 async fn create_router(
     tera: Arc<Tera>,
     config: &Config,
+    shutdown: CancellationToken,
 ) -> Router {
     let tera_for_list = tera.clone();
     let tera_for_detail = tera.clone();
     let tera_for_error = tera.clone();
+    let tera_for_thread = tera.clone();
+    let tera_for_bulk = tera.clone();
     let settings_for_move_message = config.clone();
     let settings_for_spam = config.clone();
     let config_for_detail = config.clone();
+    let config_for_body = config.clone();
+    let config_for_inline = config.clone();
+    let config_for_attachment = config.clone();
+    let config_for_thread = config.clone();
+    let config_for_bulk = config.clone();
+    let config_for_flags = config.clone();
+    let account = config.account_name().to_string();
+    let assets_dir = config.server.assets_dir.clone();
     
     Router::new()
         .route("/", get(|| async { Redirect::permanent("/inbox/INBOX") }))
-        .route("/inbox/:folder_name", get(move |axum::extract::Path(folder_name): axum::extract::Path<String>| async move {
-            let messages = fetch_messages_from_server(&settings_for_spam.clone(), &folder_name, 10)
-                .await
-                .expect("Cannot fetch messages");
+        .route("/inbox/{folder_name}", get(move |axum::extract::Path(folder_name): axum::extract::Path<String>,
+                                                axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>| async move {
+            let search_query = params.get("q").cloned().unwrap_or_default();
+            let messages = match load_messages(&settings_for_spam, &folder_name, &search_query).await {
+                Ok(messages) => messages,
+                Err(e) => return render_error(tera_for_list.clone(), format!("Error loading messages: {}", e)).await
+            };
             let folders = list_imap_folders(&settings_for_spam.clone()).await.expect("Cannot fetch folders");
             match render_messages_page(
+                &settings_for_spam,
                 Arc::new(folder_name),
-                Arc::new(messages.clone()), 
+                Arc::new(search_query),
+                Arc::new(messages),
                 Arc::new(folders.clone()
             ), tera_for_list.clone()).await {
                 Ok(html) => html,
                 Err(e) => render_error(tera_for_list.clone(), format!("Error loading messages: {}", e)).await
             }
         }))
-        .route("/email/:folder_name/:message_id", get(move |axum::extract::Path((folder_name, message_id))| async move {
-            match render_email_detail(&config_for_detail.clone(), message_id, folder_name, tera_for_detail.clone()).await {
+        .route("/email/{folder_name}/{uid}", get(move |axum::extract::Path((folder_name, uid)): axum::extract::Path<(String, u32)>,
+                                                   axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>| async move {
+            let remote_content = params.get("remote").is_some_and(|value| value == "1");
+            match render_email_detail(&config_for_detail.clone(), folder_name, uid, remote_content, tera_for_detail.clone()).await {
                 Ok(html) => html,
                 Err(e) => render_error(tera_for_detail.clone(), format!("Error loading email: {}", e)).await
             }
         }))
-        .route("/email/:message_id/move/:target_folder", get(
+        .route("/email/{folder_name}/{uid}/body", get(move |axum::extract::Path((folder_name, uid)): axum::extract::Path<(String, u32)>,
+                                                        axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>| async move {
+            let remote_content = params.get("remote").is_some_and(|value| value == "1");
+            match render_email_body(&config_for_body.clone(), folder_name, uid, remote_content).await {
+                Ok(response) => response,
+                Err(e) => (StatusCode::NOT_FOUND, format!("Error loading email body: {}", e)).into_response()
+            }
+        }))
+        .route("/email/{folder_name}/{uid}/cid/{content_id}", get(
+            move |axum::extract::Path((folder_name, uid, content_id)): axum::extract::Path<(String, u32, String)>| async move {
+                match inline_part(&config_for_inline.clone(), folder_name, uid, content_id).await {
+                    Ok(response) => response,
+                    Err(e) => (StatusCode::NOT_FOUND, format!("Error loading inline part: {}", e)).into_response()
+                }
+            }
+        ))
+        .route("/email/{folder_name}/{uid}/attachment/{index}", get(
+            move |axum::extract::Path((folder_name, uid, index)): axum::extract::Path<(String, u32, usize)>,
+                  axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>| async move {
+                let inline = params.get("inline").is_some_and(|value| value == "1");
+                match download_attachment(&config_for_attachment.clone(), folder_name, uid, index, inline).await {
+                    Ok(response) => response,
+                    Err(e) => (StatusCode::NOT_FOUND, format!("Error loading attachment: {}", e)).into_response()
+                }
+            }
+        ))
+        .route("/thread/{folder_name}/{thread_id}", get(
+            move |axum::extract::Path((folder_name, thread_id)): axum::extract::Path<(String, String)>| async move {
+                match render_thread(&config_for_thread.clone(), folder_name, thread_id, tera_for_thread.clone()).await {
+                    Ok(html) => html,
+                    Err(e) => render_error(tera_for_thread.clone(), format!("Error loading conversation: {}", e)).await
+                }
+            }
+        ))
+        .route("/email/{folder_name}/{uid}/flags", post(
+            move |axum::extract::Path((folder_name, uid)): axum::extract::Path<(String, u32)>, axum::extract::RawForm(body): axum::extract::RawForm| async move {
+                change_message_flags(&config_for_flags.clone(), &folder_name, uid, &body)
+                    .await
+                    .unwrap_or_else(|e| Redirect::to(&format!("/error?message={}", urlencoding::encode(&format!("Error changing flags: {}", e)))))
+            }
+        ))
+        .route("/bulk/{folder_name}", post(
+            move |axum::extract::Path(folder_name): axum::extract::Path<String>, axum::extract::RawForm(body): axum::extract::RawForm| async move {
+                match render_bulk_result(&config_for_bulk.clone(), folder_name, &body, tera_for_bulk.clone()).await {
+                    Ok(html) => html,
+                    Err(e) => render_error(tera_for_bulk.clone(), format!("Error running bulk action: {}", e)).await
+                }
+            }
+        ))
+        .route("/email/{message_id}/move/{target_folder}", get(
             move |axum::extract::Path((message_id, target_folder)): axum::extract::Path<(String, String)>| async move {
                 match move_message(message_id, target_folder, &settings_for_move_message.clone()).await {
                     Ok(redirect) => redirect,
@@ -105,20 +607,77 @@ async fn create_router(
                 }
             }
         ))
+        .route("/events", get(move || async move { event_stream(account.clone(), shutdown.clone()) }))
+        .route("/static/{file}", get(move |axum::extract::Path(file): axum::extract::Path<String>| async move {
+            assets::static_file(assets_dir.as_deref(), &file)
+        }))
         .route("/error", get(move |axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>| async move {
             let error_message = params.get("message").cloned().unwrap_or_else(|| "Unknown error".to_string());
             render_error(tera_for_error.clone(), error_message).await
         }))
+        .merge(rules::rules_router(tera.clone(), config))
+        .merge(compose::compose_router(tera.clone(), config))
+        .merge(metrics::metrics_router())
         .layer(Extension(tera.clone()))
+        .layer(axum::middleware::from_fn(|request, next| metrics::track_requests("web", request, next)))
+}
+
+// Pick the router of the account chosen with the switcher, the first account when none was chosen
+async fn dispatch(routers: Arc<Vec<(String, Router)>>, request: Request) -> Response {
+    let selected = request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(ACCOUNT_COOKIE)?.strip_prefix('='))
+        .map(str::to_string);
+    let (_, router) = routers
+        .iter()
+        .find(|(name, _)| Some(name) == selected.as_ref())
+        .unwrap_or(&routers[0]);
+    router.clone().oneshot(request).await.into_response()
+}
+
+// Remember the chosen account in a cookie and show its inbox
+async fn switch_account(routers: Arc<Vec<(String, Router)>>, name: String) -> Response {
+    if !routers.iter().any(|(known, _)| *known == name) {
+        return (StatusCode::NOT_FOUND, format!("No account named {}", name)).into_response();
+    }
+    let cookie = format!("{}={}; Path=/; SameSite=Lax", ACCOUNT_COOKIE, name);
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response()
 }
 
-pub async fn start_web_server(config: &Config) -> Result<(), AppError> {
-    let tera = Arc::new(Tera::new("templates/**/*.html")?);
+// One router per account; with several, the switcher sets which one answers
+async fn create_account_routers(tera: Arc<Tera>, config: &Config, shutdown: CancellationToken) -> Router {
+    let mut routers = Vec::new();
+    for account in config.accounts() {
+        let router = create_router(Arc::clone(&tera), &account, shutdown.clone()).await;
+        routers.push((account.account_name().to_string(), router));
+    }
+    if routers.len() == 1 {
+        return routers.remove(0).1;
+    }
+
+    let routers = Arc::new(routers);
+    let routers_for_switch = routers.clone();
+    Router::new()
+        .route("/accounts/{name}", get(move |axum::extract::Path(name): axum::extract::Path<String>| {
+            switch_account(routers_for_switch.clone(), name)
+        }))
+        .fallback(move |request: Request| dispatch(routers.clone(), request))
+}
+
+pub async fn start_web_server(config: &Config, shutdown: CancellationToken) -> Result<(), AppError> {
+    let tera = Arc::new(assets::load_templates(config.server.assets_dir.as_deref())?);
     
-    let router = create_router(Arc::clone(&tera), config).await;
-    start_server(router).await
+    let router = create_account_routers(Arc::clone(&tera), config, shutdown.clone()).await;
+    ensure_watching(config);
+    web_services::serve(&config.web.address(), router, shutdown).await?;
+    Ok(())
 }
 
-pub async fn entrypoint(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    start_web_server(config).await.map_err(Into::into)
+/// Serve the web interface until `shutdown` is cancelled, letting the requests in progress finish
+pub async fn entrypoint(config: &Config, shutdown: CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    start_web_server(config, shutdown).await.map_err(Into::into)
 }
\ No newline at end of file
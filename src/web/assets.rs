@@ -0,0 +1,75 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use log::info;
+use tera::Tera;
+
+// Templates compiled into the binary, so the web UI works from any directory
+const TEMPLATES: &[(&str, &str)] = &[
+    ("bulk_result.html", include_str!("../../templates/bulk_result.html")),
+    ("compose.html", include_str!("../../templates/compose.html")),
+    ("email_detail.html", include_str!("../../templates/email_detail.html")),
+    ("emails.html", include_str!("../../templates/emails.html")),
+    ("error.html", include_str!("../../templates/error.html")),
+    ("rule_edit.html", include_str!("../../templates/rule_edit.html")),
+    ("rule_from_message.html", include_str!("../../templates/rule_from_message.html")),
+    ("rule_preview.html", include_str!("../../templates/rule_preview.html")),
+    ("rules.html", include_str!("../../templates/rules.html")),
+    ("thread.html", include_str!("../../templates/thread.html")),
+];
+
+// Static files served under /static, with their content type
+const STATIC_FILES: &[(&str, &str, &[u8])] = &[
+    ("style.css", "text/css; charset=utf-8", include_bytes!("../../static/style.css")),
+];
+
+/// Build the template engine from the embedded templates; every `templates/*.html`
+/// file found in `assets_dir` replaces the embedded template with the same name
+pub fn load_templates(assets_dir: Option<&Path>) -> Result<Tera> {
+    let mut tera = Tera::default();
+    tera.add_raw_templates(TEMPLATES.iter().copied())?;
+
+    let Some(templates_dir) = assets_dir.map(|dir| dir.join("templates")) else {
+        return Ok(tera);
+    };
+    if !templates_dir.is_dir() {
+        return Ok(tera);
+    }
+
+    let mut overrides: Vec<(PathBuf, Option<String>)> = Vec::new();
+    for entry in fs::read_dir(&templates_dir)
+        .with_context(|| format!("Cannot read templates directory {:?}", templates_dir))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "html") {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+            info!("Using template {:?}", path);
+            overrides.push((path, name));
+        }
+    }
+    tera.add_template_files(overrides)?;
+
+    Ok(tera)
+}
+
+/// Serve a static file, from `assets_dir/static` when present there, otherwise embedded
+pub fn static_file(assets_dir: Option<&Path>, name: &str) -> Response {
+    let Some(&(_, content_type, embedded)) = STATIC_FILES.iter().find(|(file, _, _)| *file == name) else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+
+    let content = assets_dir
+        .and_then(|dir| fs::read(dir.join("static").join(name)).ok())
+        .unwrap_or_else(|| embedded.to_vec());
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "public, max-age=3600"),
+        ],
+        content,
+    ).into_response()
+}
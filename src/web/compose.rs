@@ -0,0 +1,103 @@
+use axum::{extract::{Path, RawForm}, response::{Html, Redirect}, routing::{get, MethodRouter}, Router};
+use std::sync::Arc;
+use tera::Tera;
+
+use super::{load_message, parse_form, render_error, AppError};
+use crate::mail_reader::thread::parse_message_ids;
+use crate::mail_sender::compose::Draft;
+use crate::mail_sender::smtp::{send_draft, sender_address};
+use crate::settings::Config;
+
+fn draft_from_form(body: &[u8]) -> Draft {
+    let mut draft = Draft::default();
+
+    for (key, value) in parse_form(body) {
+        match key.as_str() {
+            "to" => draft.to = value,
+            "cc" => draft.cc = value,
+            "bcc" => draft.bcc = value,
+            "subject" => draft.subject = value,
+            "body" => draft.body = value,
+            "in_reply_to" => draft.in_reply_to = Some(value).filter(|id| !id.trim().is_empty()),
+            "references" => draft.references = parse_message_ids(&value),
+            _ => {}
+        }
+    }
+
+    draft
+}
+
+fn render_compose(config: &Config, draft: &Draft, error: Option<String>, tera: Arc<Tera>) -> Result<Html<String>, AppError> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("draft", draft);
+    ctx.insert("from", &sender_address(config));
+    ctx.insert("error", &error);
+    ctx.insert("can_send", &config.smtp.is_some());
+    let html = tera.render("compose.html", &ctx)?;
+    Ok(Html(html))
+}
+
+// Prefill the compose form to answer or forward a message
+async fn compose_from_message(
+    config: &Config,
+    folder_name: &str,
+    uid: u32,
+    action: &str,
+    tera: Arc<Tera>,
+) -> Result<Html<String>, AppError> {
+    let message = load_message(config, folder_name, uid).await?;
+    let draft = match action {
+        "reply" => Draft::reply(&message, false, &sender_address(config)),
+        "reply_all" => Draft::reply(&message, true, &sender_address(config)),
+        _ => Draft::forward(&message),
+    };
+    render_compose(config, &draft, None, tera)
+}
+
+// Send the draft; on failure the form comes back with the error and everything typed so far
+async fn send(config: &Config, body: &[u8], tera: Arc<Tera>) -> Result<Result<Redirect, Html<String>>, AppError> {
+    let draft = draft_from_form(body);
+
+    match send_draft(config, &draft).await {
+        Ok(sent) => {
+            let folder = sent.saved_to.unwrap_or_else(|| "INBOX".to_string());
+            Ok(Ok(Redirect::to(&format!("/inbox/{}", urlencoding::encode(&folder)))))
+        }
+        Err(e) => Ok(Err(render_compose(config, &draft, Some(e.to_string()), tera)?)),
+    }
+}
+
+pub(super) fn compose_router(tera: Arc<Tera>, config: &Config) -> Router {
+    let tera_for_new = tera.clone();
+    let tera_for_send = tera.clone();
+    let config_for_new = config.clone();
+    let config_for_send = config.clone();
+
+    Router::new()
+        .route("/compose", get(move || async move {
+            match render_compose(&config_for_new, &Draft::default(), None, tera_for_new.clone()) {
+                Ok(html) => html,
+                Err(e) => render_error(tera_for_new.clone(), format!("Error loading compose form: {}", e)).await
+            }
+        }).post(move |RawForm(body): RawForm| async move {
+            match send(&config_for_send, &body, tera_for_send.clone()).await {
+                Ok(Ok(redirect)) => Ok(redirect),
+                Ok(Err(form)) => Err(form),
+                Err(e) => Err(render_error(tera_for_send.clone(), format!("Error sending message: {}", e)).await)
+            }
+        }))
+        .route("/email/{folder_name}/{uid}/reply", answer_route("reply", tera.clone(), config))
+        .route("/email/{folder_name}/{uid}/reply_all", answer_route("reply_all", tera.clone(), config))
+        .route("/email/{folder_name}/{uid}/forward", answer_route("forward", tera, config))
+}
+
+fn answer_route(action: &'static str, tera: Arc<Tera>, config: &Config) -> MethodRouter {
+    let config = config.clone();
+
+    get(move |Path((folder_name, uid)): Path<(String, u32)>| async move {
+        match compose_from_message(&config, &folder_name, uid, action, tera.clone()).await {
+            Ok(html) => html,
+            Err(e) => render_error(tera.clone(), format!("Error loading message: {}", e)).await
+        }
+    })
+}
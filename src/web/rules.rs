@@ -0,0 +1,423 @@
+use axum::{extract::{Path, RawForm}, response::{Html, Redirect}, routing::{get, post}, Json, Router};
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+use std::sync::Arc;
+use tera::Tera;
+
+use super::{load_message, parse_form, render_error, AppError, BulkResult};
+use crate::mail_move_rules::scheduler::{run_exclusive, JobKind};
+use crate::mail_move_rules::{apply_rule_to_inbox, check_message_matches, domain_pattern, list_id_pattern, sender_pattern, subject_pattern};
+use crate::mail_move_rules::mail_move_settings::{load_mail_move_config, mail_move_config_path, update_mail_move_config, Rule, RuleWrapper};
+use crate::mail_reader::imap::{create_session, fetch_messages, list_imap_folders};
+use crate::mail_reader::message::Message;
+use crate::reload::{last_outcome, ConfigFile};
+use crate::settings::Config;
+
+// Number of recent INBOX messages checked when previewing a rule
+const PREVIEW_MESSAGES: u32 = 100;
+
+#[derive(Debug, Serialize)]
+struct ValidationResult {
+    valid: bool,
+    errors: Vec<String>,
+}
+
+fn lines_to_patterns(value: &str) -> Option<Vec<String>> {
+    let patterns: Vec<String> = value
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    (!patterns.is_empty()).then_some(patterns)
+}
+
+// Build a rule from the fields of the edit form, one pattern per line
+fn rule_from_form(body: &[u8]) -> Rule {
+    let mut rule = Rule::default();
+
+    for (key, value) in parse_form(body) {
+        match key.as_str() {
+            "name" => rule.name = Some(value.trim().to_string()).filter(|name| !name.is_empty()),
+            "target_folder" => rule.target_folder = value.trim().to_string(),
+            "from" => rule.from = lines_to_patterns(&value),
+            "title" => rule.title = lines_to_patterns(&value),
+            "body" => rule.body = lines_to_patterns(&value),
+            "user_agent" => rule.user_agent = lines_to_patterns(&value),
+            "to" => rule.to = lines_to_patterns(&value),
+            "list_id" => rule.list_id = lines_to_patterns(&value),
+            _ => {}
+        }
+    }
+
+    rule
+}
+
+/// State of the "create rule from this message" form: suggested patterns and the
+/// one the rule is built from. Patterns of a rule are ORed, so a rule matching
+/// on both the sender and the subject would move far more than this kind of message.
+#[derive(Debug, Default, Serialize)]
+struct MessageRuleForm {
+    name: String,
+    target_folder: String,
+    // "address", "domain", "list_id" or "title"
+    criterion: String,
+    from_address: String,
+    from_domain: String,
+    list_id: String,
+    title: String,
+    apply_now: bool,
+}
+
+impl MessageRuleForm {
+    fn suggest(message: &Message) -> Self {
+        let from_address = sender_pattern(&message.from).unwrap_or_default();
+        let list_id = message.list_id.as_deref().map(list_id_pattern).unwrap_or_default();
+
+        // The List-Id describes a mailing list better than the address it is sent from
+        let criterion = if !list_id.is_empty() {
+            "list_id"
+        } else if !from_address.is_empty() {
+            "address"
+        } else {
+            "title"
+        };
+
+        MessageRuleForm {
+            criterion: criterion.to_string(),
+            from_address,
+            from_domain: domain_pattern(&message.from).unwrap_or_default(),
+            list_id,
+            title: subject_pattern(&message.subject),
+            ..Default::default()
+        }
+    }
+
+    fn parse(body: &[u8]) -> Self {
+        let mut form = MessageRuleForm::default();
+
+        for (key, value) in parse_form(body) {
+            let value = value.trim().to_string();
+            match key.as_str() {
+                "name" => form.name = value,
+                "target_folder" => form.target_folder = value,
+                "criterion" => form.criterion = value,
+                "from_address" => form.from_address = value,
+                "from_domain" => form.from_domain = value,
+                "list_id" => form.list_id = value,
+                "title" => form.title = value,
+                "apply_now" => form.apply_now = true,
+                _ => {}
+            }
+        }
+
+        form
+    }
+
+    fn to_rule(&self) -> Rule {
+        let pattern = |value: &str| (!value.is_empty()).then(|| vec![value.to_string()]);
+
+        let mut rule = Rule {
+            name: Some(self.name.clone()).filter(|name| !name.is_empty()),
+            target_folder: self.target_folder.clone(),
+            ..Default::default()
+        };
+        match self.criterion.as_str() {
+            "address" => rule.from = pattern(&self.from_address),
+            "domain" => rule.from = pattern(&self.from_domain),
+            "list_id" => rule.list_id = pattern(&self.list_id),
+            "title" => rule.title = pattern(&self.title),
+            _ => {}
+        }
+        rule
+    }
+}
+
+fn check_index(index: usize, rules: &[RuleWrapper]) -> Result<(), AppError> {
+    if index >= rules.len() {
+        bail!("Rule {} does not exist", index + 1);
+    }
+    Ok(())
+}
+
+async fn render_rules_list(config: &Config, tera: Arc<Tera>) -> Result<Html<String>, AppError> {
+    let rules_config = load_mail_move_config(config)?;
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("rules", &rules_config.rules);
+    ctx.insert("messages_to_check", &rules_config.messages_to_check);
+    ctx.insert("rules_path", &mail_move_config_path(config).map(|path| path.display().to_string()));
+    // The file on disk is shown, but the rule runner keeps the previous rules while it is invalid
+    let failed_reload = last_outcome(ConfigFile::Rules).filter(|outcome| outcome.error.is_some());
+    ctx.insert("reload_error", &failed_reload.as_ref().and_then(|outcome| outcome.error.clone()));
+    ctx.insert("reload_failed_at", &failed_reload.map(|outcome| outcome.at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string()));
+    let html = tera.render("rules.html", &ctx)?;
+    Ok(Html(html))
+}
+
+async fn render_rule_form(
+    config: &Config,
+    index: Option<usize>,
+    rule: Rule,
+    errors: Vec<String>,
+    tera: Arc<Tera>,
+) -> Result<Html<String>, AppError> {
+    // The folder list is only a convenience for the form, so IMAP errors are not fatal
+    let folders = list_imap_folders(config).await.unwrap_or_default();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("index", &index);
+    ctx.insert("rule", &rule);
+    ctx.insert("errors", &errors);
+    ctx.insert("folders", &folders);
+    let html = tera.render("rule_edit.html", &ctx)?;
+    Ok(Html(html))
+}
+
+async fn edit_rule_form(config: &Config, index: usize, tera: Arc<Tera>) -> Result<Html<String>, AppError> {
+    let rules_config = load_mail_move_config(config)?;
+    check_index(index, &rules_config.rules)?;
+    render_rule_form(config, Some(index), rules_config.rules[index].rule.clone(), Vec::new(), tera).await
+}
+
+// Create (index None) or replace a rule; invalid rules send the form back with the errors
+async fn save_rule(
+    config: &Config,
+    index: Option<usize>,
+    body: &[u8],
+    tera: Arc<Tera>,
+) -> Result<Result<Redirect, Html<String>>, AppError> {
+    let rule = rule_from_form(body);
+    let errors = rule.validate();
+    if !errors.is_empty() {
+        return Ok(Err(render_rule_form(config, index, rule, errors, tera).await?));
+    }
+
+    update_mail_move_config(config, |rules_config| {
+        match index {
+            Some(index) => {
+                check_index(index, &rules_config.rules)?;
+                rules_config.rules[index].rule = rule;
+            }
+            None => rules_config.rules.push(RuleWrapper { rule }),
+        }
+        Ok::<_, AppError>(())
+    })?;
+
+    Ok(Ok(Redirect::to("/rules")))
+}
+
+fn delete_rule(config: &Config, index: usize) -> Result<Redirect, AppError> {
+    update_mail_move_config(config, |rules_config| {
+        check_index(index, &rules_config.rules)?;
+        rules_config.rules.remove(index);
+        Ok::<_, AppError>(())
+    })?;
+    Ok(Redirect::to("/rules"))
+}
+
+// Rules are applied in order, so moving one up gives it priority
+fn move_rule(config: &Config, index: usize, direction: &str) -> Result<Redirect, AppError> {
+    update_mail_move_config(config, |rules_config| {
+        check_index(index, &rules_config.rules)?;
+
+        let other = match direction {
+            "up" if index > 0 => index - 1,
+            "down" if index + 1 < rules_config.rules.len() => index + 1,
+            "up" | "down" => return Ok(()),
+            other => return Err(anyhow!("Unknown direction '{}'", other)),
+        };
+
+        rules_config.rules.swap(index, other);
+        Ok(())
+    })?;
+    Ok(Redirect::to("/rules"))
+}
+
+async fn render_rule_preview(config: &Config, index: usize, tera: Arc<Tera>) -> Result<Html<String>, AppError> {
+    let rules_config = load_mail_move_config(config)?;
+    check_index(index, &rules_config.rules)?;
+    let rule = &rules_config.rules[index].rule;
+
+    let mut imap_session = create_session(config).await?;
+    let messages = fetch_messages(&mut imap_session, "INBOX", PREVIEW_MESSAGES.min(rules_config.messages_to_check)).await?;
+    imap_session.logout().await?;
+
+    let checked = messages.len();
+    let matching: Vec<_> = messages
+        .into_iter()
+        .filter(|message| check_message_matches(message, rule))
+        .collect();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("index", &index);
+    ctx.insert("rule", rule);
+    ctx.insert("checked", &checked);
+    ctx.insert("messages", &matching);
+    let html = tera.render("rule_preview.html", &ctx)?;
+    Ok(Html(html))
+}
+
+async fn render_message_rule_form(
+    config: &Config,
+    folder_name: &str,
+    message: &Message,
+    form: &MessageRuleForm,
+    errors: Vec<String>,
+    tera: Arc<Tera>,
+) -> Result<Html<String>, AppError> {
+    let folders = list_imap_folders(config).await.unwrap_or_default();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("folder_name", folder_name);
+    ctx.insert("message", message);
+    ctx.insert("form", form);
+    ctx.insert("errors", &errors);
+    ctx.insert("folders", &folders);
+    let html = tera.render("rule_from_message.html", &ctx)?;
+    Ok(Html(html))
+}
+
+async fn message_rule_form(config: &Config, folder_name: &str, uid: u32, tera: Arc<Tera>) -> Result<Html<String>, AppError> {
+    let message = load_message(config, folder_name, uid).await?;
+    render_message_rule_form(config, folder_name, &message, &MessageRuleForm::suggest(&message), Vec::new(), tera).await
+}
+
+// Append the rule built from a message, then optionally run it on INBOX straight away
+async fn create_rule_from_message(
+    config: &Config,
+    folder_name: &str,
+    uid: u32,
+    body: &[u8],
+    tera: Arc<Tera>,
+) -> Result<Result<Redirect, Html<String>>, AppError> {
+    let form = MessageRuleForm::parse(body);
+    let rule = form.to_rule();
+    let errors = rule.validate();
+    if !errors.is_empty() {
+        let message = load_message(config, folder_name, uid).await?;
+        return Ok(Err(render_message_rule_form(config, folder_name, &message, &form, errors, tera).await?));
+    }
+
+    let number = update_mail_move_config(config, |rules_config| {
+        rules_config.rules.push(RuleWrapper { rule: rule.clone() });
+        Ok::<_, AppError>(rules_config.rules.len())
+    })?;
+
+    if !form.apply_now {
+        return Ok(Ok(Redirect::to("/rules")));
+    }
+
+    let result = match run_exclusive(JobKind::Rules, config, apply_rule_to_inbox(config, &rule)).await {
+        Ok(count) => BulkResult {
+            action: "create_rule".to_string(),
+            folder_name: "INBOX".to_string(),
+            count,
+            success: true,
+            details: vec![
+                format!("Rule added as number {}", number),
+                format!("Moved to {}", rule.target_folder),
+            ],
+        },
+        Err(e) => BulkResult {
+            action: "create_rule".to_string(),
+            folder_name: "INBOX".to_string(),
+            count: 0,
+            success: false,
+            details: vec![
+                format!("Rule added as number {}", number),
+                format!("Applying it failed: {}", e),
+            ],
+        },
+    };
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("result", &result);
+    Ok(Err(Html(tera.render("bulk_result.html", &ctx)?)))
+}
+
+fn error_redirect(message: String) -> Redirect {
+    Redirect::to(&format!("/error?message={}", urlencoding::encode(&message)))
+}
+
+pub(super) fn rules_router(tera: Arc<Tera>, config: &Config) -> Router {
+    let tera_for_list = tera.clone();
+    let tera_for_new = tera.clone();
+    let tera_for_create = tera.clone();
+    let tera_for_edit = tera.clone();
+    let tera_for_update = tera.clone();
+    let tera_for_preview = tera.clone();
+    let config_for_list = config.clone();
+    let config_for_new = config.clone();
+    let config_for_create = config.clone();
+    let config_for_edit = config.clone();
+    let config_for_update = config.clone();
+    let config_for_delete = config.clone();
+    let config_for_move = config.clone();
+    let config_for_preview = config.clone();
+    let tera_for_message_form = tera.clone();
+    let tera_for_message_create = tera.clone();
+    let config_for_message_form = config.clone();
+    let config_for_message_create = config.clone();
+
+    Router::new()
+        .route("/rules", get(move || async move {
+            match render_rules_list(&config_for_list, tera_for_list.clone()).await {
+                Ok(html) => html,
+                Err(e) => render_error(tera_for_list.clone(), format!("Error loading rules: {}", e)).await
+            }
+        }))
+        .route("/rules/new", get(move || async move {
+            match render_rule_form(&config_for_new, None, Rule::default(), Vec::new(), tera_for_new.clone()).await {
+                Ok(html) => html,
+                Err(e) => render_error(tera_for_new.clone(), format!("Error loading rule form: {}", e)).await
+            }
+        }).post(move |RawForm(body): RawForm| async move {
+            match save_rule(&config_for_create, None, &body, tera_for_create.clone()).await {
+                Ok(Ok(redirect)) => Ok(redirect),
+                Ok(Err(form)) => Err(form),
+                Err(e) => Err(render_error(tera_for_create.clone(), format!("Error saving rule: {}", e)).await)
+            }
+        }))
+        .route("/rules/validate", post(|RawForm(body): RawForm| async move {
+            let errors = rule_from_form(&body).validate();
+            Json(ValidationResult { valid: errors.is_empty(), errors })
+        }))
+        .route("/rules/{index}/edit", get(move |Path(index): Path<usize>| async move {
+            match edit_rule_form(&config_for_edit, index, tera_for_edit.clone()).await {
+                Ok(html) => html,
+                Err(e) => render_error(tera_for_edit.clone(), format!("Error loading rule: {}", e)).await
+            }
+        }).post(move |Path(index): Path<usize>, RawForm(body): RawForm| async move {
+            match save_rule(&config_for_update, Some(index), &body, tera_for_update.clone()).await {
+                Ok(Ok(redirect)) => Ok(redirect),
+                Ok(Err(form)) => Err(form),
+                Err(e) => Err(render_error(tera_for_update.clone(), format!("Error saving rule: {}", e)).await)
+            }
+        }))
+        .route("/rules/{index}/delete", post(move |Path(index): Path<usize>| async move {
+            delete_rule(&config_for_delete, index).unwrap_or_else(|e| error_redirect(format!("Error deleting rule: {}", e)))
+        }))
+        .route("/rules/{index}/move/{direction}", post(move |Path((index, direction)): Path<(usize, String)>| async move {
+            move_rule(&config_for_move, index, &direction).unwrap_or_else(|e| error_redirect(format!("Error moving rule: {}", e)))
+        }))
+        .route("/rules/{index}/preview", get(move |Path(index): Path<usize>| async move {
+            match render_rule_preview(&config_for_preview, index, tera_for_preview.clone()).await {
+                Ok(html) => html,
+                Err(e) => render_error(tera_for_preview.clone(), format!("Error previewing rule: {}", e)).await
+            }
+        }))
+        .route("/email/{folder_name}/{uid}/rule", get(move |Path((folder_name, uid)): Path<(String, u32)>| async move {
+            match message_rule_form(&config_for_message_form, &folder_name, uid, tera_for_message_form.clone()).await {
+                Ok(html) => html,
+                Err(e) => render_error(tera_for_message_form.clone(), format!("Error loading rule form: {}", e)).await
+            }
+        }).post(move |Path((folder_name, uid)): Path<(String, u32)>, RawForm(body): RawForm| async move {
+            match create_rule_from_message(&config_for_message_create, &folder_name, uid, &body, tera_for_message_create.clone()).await {
+                Ok(Ok(redirect)) => Ok(redirect),
+                Ok(Err(page)) => Err(page),
+                Err(e) => Err(render_error(tera_for_message_create.clone(), format!("Error creating rule: {}", e)).await)
+            }
+        }))
+}
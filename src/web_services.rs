@@ -1,75 +1,476 @@
-use axum::{Router, routing::get};
+mod folders;
+mod health;
+mod messages;
+pub(crate) mod openapi;
+mod rules;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{Router, routing::{any, get, post}};
 use crate::settings::Config;
-use crate::mail_reader::imap::fetch_messages_from_server;
+use crate::web::attachment_response;
+use crate::mail_reader::attachment::AttachmentPart;
+use crate::mail_reader::imap::{create_session, fetch_attachment_content, fetch_attachment_parts, fetch_messages_from_server, search_messages_from_server};
+use crate::mail_reader::search::SearchQuery;
+use crate::mail_sender::compose::Draft;
+use crate::mail_sender::smtp::{send_draft, sender_address, smtp_config, SentMessage};
 use axum::{
     response::{IntoResponse, Response},
     Json,
-    extract::Path,
-    http::StatusCode,
+    http::{StatusCode, Uri},
 };
-use std::fmt;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Path, Request};
+use crate::mail_reader::error::ImapError;
+use crate::mail_reader::message::Message;
+use crate::metrics;
+use tokio_util::sync::CancellationToken;
+use log::info;
+use tower::ServiceExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+
+// Default and maximum number of messages returned by a listing
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 100;
 
-// Assuming you have some error type that implements std::error::Error
+/// An API failure, returned as `{"error": {"code": "...", "message": "..."}}`
 #[derive(Debug)]
 struct AppError {
+    status: StatusCode,
+    code: &'static str,
     message: String,
+    /// Individual problems, e.g. every validation error of a rule
+    details: Vec<String>,
 }
 
-impl std::error::Error for AppError {}
+impl AppError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        AppError { status, code, message: message.into(), details: Vec::new() }
+    }
+
+    fn with_details(mut self, details: Vec<String>) -> Self {
+        self.details = details;
+        self
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        AppError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+}
 
-impl fmt::Display for AppError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.message)
+// Pick the status from the typed errors of the IMAP layer; anything else is a 500
+impl From<anyhow::Error> for AppError {
+    fn from(error: anyhow::Error) -> Self {
+        let message = error.to_string();
+        if let Some(imap_error) = error.downcast_ref::<ImapError>() {
+            return match imap_error {
+                ImapError::Unreachable(_) => AppError::new(StatusCode::BAD_GATEWAY, "imap_unreachable", message),
+                ImapError::Authentication(_) => AppError::new(StatusCode::UNAUTHORIZED, "authentication_failed", message),
+                ImapError::MailboxNotFound(_) => AppError::new(StatusCode::NOT_FOUND, "folder_not_found", message),
+                ImapError::MessageNotFound { .. } => AppError::new(StatusCode::NOT_FOUND, "message_not_found", message),
+                ImapError::Rejected(_) => AppError::new(StatusCode::CONFLICT, "rejected", message),
+                ImapError::Unsupported(_) => AppError::new(StatusCode::NOT_IMPLEMENTED, "unsupported", message),
+            };
+        }
+        if error.downcast_ref::<async_imap::error::Error>().is_some() {
+            return AppError::new(StatusCode::BAD_GATEWAY, "imap_error", message);
+        }
+        AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
     }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error: {}", self.message),
-        ).into_response()
+        let body = ErrorBody {
+            error: ErrorDetail { code: self.code.to_string(), message: self.message, details: self.details },
+        };
+        (self.status, Json(body)).into_response()
     }
 }
 
-async fn get_data(folder: String, config: Config) -> Result<Json<String>, AppError> {
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        AppError::bad_request(rejection.body_text())
+    }
+}
 
-    let emails = fetch_messages_from_server(&config, &folder, 10)
-        .await
-        .map_err(|e| AppError {
-            message: e.to_string(),
-        })?;
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::bad_request(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        AppError::bad_request(rejection.body_text())
+    }
+}
+
+// Extractors answering malformed input with the JSON error envelope
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+struct ApiJson<T>(T);
 
-    let json = serde_json::to_string(&emails)
-        .map_err(|e| AppError {
-            message: e.to_string(),
-        })?;
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+struct ApiQuery<T>(T);
 
-    Ok(Json(json))
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+struct ApiPath<T>(T);
+
+/// Body of every error response
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorDetail {
+    /// Machine readable code, e.g. `folder_not_found`
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    details: Vec<String>,
+}
+
+/// Paging and projection parameters shared by the listings
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListParams {
+    /// Number of messages to return
+    #[serde(default = "default_limit")]
+    #[param(default = 10, minimum = 1, maximum = 100)]
+    limit: u32,
+    /// Number of newest messages to skip
+    #[serde(default)]
+    offset: u32,
+    /// Comma separated message fields to return, e.g. `uid,subject,from`
+    fields: Option<String>,
+}
+
+fn default_limit() -> u32 {
+    DEFAULT_LIMIT
+}
+
+/// A page of messages, newest first
+#[derive(Debug, Serialize, ToSchema)]
+struct MessagePage {
+    /// Messages, reduced to the requested `fields` if any
+    #[schema(value_type = Vec<Message>)]
+    messages: Vec<Value>,
+    limit: u32,
+    offset: u32,
 }
 
-async fn not_found() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "404 - Page Not Found")
+impl ListParams {
+    // Check the parameters before going to the server, returning the requested fields
+    fn validate(&self) -> Result<Option<Vec<String>>, AppError> {
+        if self.limit == 0 || self.limit > MAX_LIMIT {
+            return Err(AppError::bad_request(format!("limit must be between 1 and {}", MAX_LIMIT)));
+        }
+        self.fields.as_deref().map(parse_fields).transpose().map_err(AppError::bad_request)
+    }
+
+    fn page(&self, messages: Vec<Message>, fields: Option<Vec<String>>) -> Result<MessagePage, AppError> {
+        let messages = messages
+            .iter()
+            .map(|message| select_fields(message, fields.as_deref()))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(MessagePage { messages, limit: self.limit, offset: self.offset })
+    }
+}
+
+/// Parse a `fields` parameter, rejecting names that are not message fields
+pub(crate) fn parse_fields(fields: &str) -> Result<Vec<String>, String> {
+    let Value::Object(known) = serde_json::to_value(Message::default()).map_err(|e| e.to_string())? else {
+        return Err("Messages do not serialize to objects".to_string());
+    };
+
+    let fields: Vec<String> = fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(unknown) = fields.iter().find(|field| !known.contains_key(field.as_str())) {
+        return Err(format!("Unknown field '{}'", unknown));
+    }
+    Ok(fields)
+}
+
+/// Serialize a message, keeping only `fields` when given
+pub(crate) fn select_fields(message: &Message, fields: Option<&[String]>) -> anyhow::Result<Value> {
+    let mut value = serde_json::to_value(message)?;
+    if let (Some(fields), Value::Object(object)) = (fields, &mut value) {
+        object.retain(|key, _| fields.iter().any(|field| field == key));
+    }
+    Ok(value)
 }
 
-pub async fn entrypoint(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+/// List the newest messages of a folder
+#[utoipa::path(
+    get,
+    path = "/api/v1/emails/{folder}",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ListParams),
+    responses(
+        (status = 200, description = "A page of messages", body = MessagePage),
+        (status = "4XX", description = "Bad input or unknown folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn get_data(folder: String, params: ListParams, config: Config) -> Result<Json<MessagePage>, AppError> {
+    let fields = params.validate()?;
+    let emails = fetch_messages_from_server(&config, &folder, params.offset, params.limit).await?;
+    Ok(Json(params.page(emails, fields)?))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchParams {
+    /// Search query, e.g. `from:alice is:unread`
+    q: String,
+    /// Folder to search
+    #[serde(default = "default_search_folder")]
+    #[param(default = "INBOX")]
+    folder: String,
+}
+
+fn default_search_folder() -> String {
+    "INBOX".to_string()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AttachmentParams {
+    /// Let the browser display the attachment instead of downloading it
+    #[serde(default)]
+    inline: bool,
+}
+
+/// Search the messages of a folder
+#[utoipa::path(
+    get,
+    path = "/api/v1/search",
+    tag = "messages",
+    params(SearchParams, ListParams),
+    responses(
+        (status = 200, description = "A page of matching messages", body = MessagePage),
+        (status = "4XX", description = "Bad query or unknown folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn search_data(params: SearchParams, list: ListParams, config: Config) -> Result<Json<MessagePage>, AppError> {
+    let fields = list.validate()?;
+    let query = SearchQuery::parse(&params.q).map_err(|e| AppError::bad_request(e.to_string()))?;
+
+    let emails = search_messages_from_server(&config, &params.folder, &query, list.offset, list.limit).await?;
+    Ok(Json(list.page(emails, fields)?))
+}
+
+/// List the attachments of a message
+#[utoipa::path(
+    get,
+    path = "/api/v1/emails/{folder}/{uid}/attachments",
+    tag = "attachments",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    responses(
+        (status = 200, description = "Attachments, in MIME order", body = Vec<AttachmentPart>),
+        (status = "4XX", description = "Unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn list_attachments(folder: String, uid: u32, config: Config) -> Result<Json<Vec<AttachmentPart>>, AppError> {
+    let mut imap_session = create_session(&config).await?;
+
+    let parts = fetch_attachment_parts(&mut imap_session, &folder, uid).await?;
+
+    let _ = imap_session.logout().await;
+    Ok(Json(parts))
+}
+
+/// Download an attachment, fetching only its MIME part from the server
+#[utoipa::path(
+    get,
+    path = "/api/v1/emails/{folder}/{uid}/attachments/{index}",
+    tag = "attachments",
+    params(
+        ("folder" = String, Path, description = "Folder name"),
+        ("uid" = u32, Path, description = "Message UID"),
+        ("index" = usize, Path, description = "Position in the attachment list"),
+        AttachmentParams,
+    ),
+    responses(
+        (status = 200, description = "Content of the attachment", content_type = "application/octet-stream"),
+        (status = "4XX", description = "Unknown folder, message or attachment", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn get_attachment(folder: String, uid: u32, index: usize, inline: bool, config: Config) -> Result<Response, AppError> {
+    let mut imap_session = create_session(&config).await?;
+
+    let part = fetch_attachment_parts(&mut imap_session, &folder, uid)
+        .await?
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| AppError::not_found(format!("Attachment {} not found in message {}", index, uid)))?;
+
+    let content = fetch_attachment_content(&mut imap_session, uid, &part).await?;
+
+    let _ = imap_session.logout().await;
+    Ok(attachment_response(&part, content, inline))
+}
+
+/// Send a message, keeping a copy in the sent folder
+#[utoipa::path(
+    post,
+    path = "/api/v1/send",
+    tag = "sending",
+    request_body = Draft,
+    responses(
+        (status = 200, description = "The message was sent", body = SentMessage),
+        (status = 400, description = "Invalid or missing addresses", body = ErrorBody),
+        (status = 503, description = "Sending is not configured", body = ErrorBody),
+        (status = "5XX", description = "SMTP or IMAP failure", body = ErrorBody),
+    )
+)]
+async fn send_message(draft: Draft, config: Config) -> Result<Json<SentMessage>, AppError> {
+    if smtp_config(&config).is_err() {
+        return Err(AppError::new(StatusCode::SERVICE_UNAVAILABLE, "sending_not_configured", "Sending is not configured"));
+    }
+    // Report addressing mistakes as bad input rather than as sending failures
+    draft.build(&sender_address(&config)).map_err(|e| AppError::bad_request(e.to_string()))?;
+
+    let sent = send_draft(&config, &draft).await?;
+
+    Ok(Json(sent))
+}
+
+async fn not_found() -> AppError {
+    AppError::not_found("No such endpoint")
+}
+
+/// Names of the accounts
+#[utoipa::path(
+    get,
+    path = "/api/v1/accounts",
+    tag = "accounts",
+    responses((status = 200, description = "The account names; the first one answers the paths without an account", body = Vec<String>))
+)]
+async fn list_accounts(config: Config) -> Json<Vec<String>> {
+    Json(config.account_names())
+}
+
+// The routes of one account, with the paths they have without an account
+fn account_router(config: &Config) -> Router {
     // Clone settings once at the start instead of multiple times
     let settings_clone = config.clone();
-    
+    let settings_for_search = config.clone();
+    let settings_for_attachments = config.clone();
+    let settings_for_attachment = config.clone();
+    let settings_for_send = config.clone();
+
+    Router::new()
+        .route("/api/v1/emails/{folder}", get(
+            move |ApiPath(folder): ApiPath<String>, ApiQuery(params): ApiQuery<ListParams>| get_data(folder, params, settings_clone)
+        ))
+        .route("/api/v1/emails/{folder}/{uid}/attachments", get(move |ApiPath((folder, uid)): ApiPath<(String, u32)>| {
+            list_attachments(folder, uid, settings_for_attachments)
+        }))
+        .route("/api/v1/emails/{folder}/{uid}/attachments/{index}", get(
+            move |ApiPath((folder, uid, index)): ApiPath<(String, u32, usize)>, ApiQuery(params): ApiQuery<AttachmentParams>| {
+                get_attachment(folder, uid, index, params.inline, settings_for_attachment)
+            }
+        ))
+        .route("/api/v1/search", get(
+            move |ApiQuery(params): ApiQuery<SearchParams>, ApiQuery(list): ApiQuery<ListParams>| search_data(params, list, settings_for_search)
+        ))
+        .route("/api/v1/send", post(move |ApiJson(draft): ApiJson<Draft>| send_message(draft, settings_for_send)))
+        .merge(folders::folders_router(config))
+        .merge(messages::messages_router(config))
+        .merge(rules::rules_router(config))
+}
+
+/// `/api/v1/accounts/{account}/...` is answered by the router of that account as `/api/v1/...`
+pub(crate) async fn account_request(routers: Arc<HashMap<String, Router>>, account: String, request: Request) -> Response {
+    let Some(router) = routers.get(&account).cloned() else {
+        return AppError::new(StatusCode::NOT_FOUND, "account_not_found", format!("No account named {}", account)).into_response();
+    };
+
+    // The rest of the path as received, still percent-encoded: decoding it would turn `Sent%20Items`
+    // into an invalid URI and `a%2Fb` into two segments
+    let rest = request
+        .uri()
+        .path()
+        .strip_prefix("/api/v1/accounts/")
+        .and_then(|path| path.split_once('/'))
+        .map_or("", |(_, rest)| rest);
+    let path = match request.uri().query() {
+        Some(query) => format!("/api/v1/{}?{}", rest, query),
+        None => format!("/api/v1/{}", rest),
+    };
+    let uri: Uri = match path.parse() {
+        Ok(uri) => uri,
+        Err(e) => return AppError::bad_request(format!("Invalid path: {}", e)).into_response(),
+    };
+    // A new request, without the extensions of this route: its path parameters and matched path
+    // would be mixed with those of the account router
+    let (parts, body) = request.into_parts();
+    let mut forwarded = Request::new(body);
+    *forwarded.method_mut() = parts.method;
+    *forwarded.uri_mut() = uri;
+    *forwarded.version_mut() = parts.version;
+    *forwarded.headers_mut() = parts.headers;
+    router.oneshot(forwarded).await.into_response()
+}
+
+/// Serve the REST interface until `shutdown` is cancelled, letting the requests in progress finish.
+///
+/// Every account is served under `/api/v1/accounts/{account}`; the paths without an account are
+/// those of the first one.
+pub async fn entrypoint(config: &Config, shutdown: CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    let accounts = config.accounts();
+    let track = || axum::middleware::from_fn(|request, next| metrics::track_requests("rest", request, next));
+    let routers: HashMap<String, Router> = accounts
+        .iter()
+        .map(|account| (account.account_name().to_string(), account_router(account).fallback(not_found).layer(track())))
+        .collect();
+    let routers = Arc::new(routers);
+    let config_for_accounts = config.clone();
+
     // Build our application with a route
     let app = Router::new()
-        .route("/api/v1/emails/:folder", get(move |Path(folder): Path<String>| get_data(folder, settings_clone)))
-        .fallback(not_found);
+        .route("/api/v1/accounts", get(move || list_accounts(config_for_accounts)))
+        .merge(account_router(&accounts[0]))
+        .merge(health::health_router(config))
+        .merge(openapi::openapi_router())
+        .merge(metrics::metrics_router())
+        .fallback(not_found)
+        .layer(track())
+        // Counted by the router of the account
+        .route("/api/v1/accounts/{account}/{*rest}", any(
+            move |Path((account, _)): Path<(String, String)>, request: Request| account_request(routers.clone(), account, request)
+        ));
 
     // Run our app with hyper
-    let addr = format!(
-        "{}:{}", 
-        config.server.host, 
-        config.server.port
-    );
-    
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
-    
+    serve(&config.server.address(), app, shutdown).await?;
     Ok(())
 }
+
+/// Serve `app` on `address` until `shutdown` is cancelled, letting the requests in progress finish;
+/// used by the REST server and the web interface alike
+pub async fn serve(address: &str, app: Router, shutdown: CancellationToken) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Listening on http://{}", address);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
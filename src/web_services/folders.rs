@@ -0,0 +1,145 @@
+use axum::{http::StatusCode, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ApiJson, ApiPath, AppError, ErrorBody};
+use crate::mail_reader::folders::{create_folder, delete_folder, folder_status, list_folders, rename_folder, FolderStatus};
+use crate::mail_reader::imap::create_session;
+use crate::settings::Config;
+
+#[derive(Debug, Serialize, ToSchema)]
+struct FolderList {
+    folders: Vec<String>,
+}
+
+/// Body of the create and rename requests, e.g. `{"name": "Archive/2024"}`
+#[derive(Debug, Deserialize, ToSchema)]
+struct FolderName {
+    name: String,
+}
+
+impl FolderName {
+    fn validate(self) -> Result<String, AppError> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err(AppError::bad_request("The folder name is empty"));
+        }
+        Ok(name.to_string())
+    }
+}
+
+/// List the folders
+#[utoipa::path(
+    get,
+    path = "/api/v1/folders",
+    tag = "folders",
+    responses(
+        (status = 200, description = "Every folder of the account", body = FolderList),
+        (status = "4XX", description = "Bad input", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn get_folders(config: Config) -> Result<Json<FolderList>, AppError> {
+    let mut imap_session = create_session(&config).await?;
+    let folders = list_folders(&mut imap_session).await?;
+    let _ = imap_session.logout().await;
+    Ok(Json(FolderList { folders }))
+}
+
+/// Counters of a folder
+#[utoipa::path(
+    get,
+    path = "/api/v1/folders/{folder}",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name")),
+    responses(
+        (status = 200, description = "Status of the folder", body = FolderStatus),
+        (status = "4XX", description = "Unknown folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn get_folder(folder: String, config: Config) -> Result<Json<FolderStatus>, AppError> {
+    let mut imap_session = create_session(&config).await?;
+    let status = folder_status(&mut imap_session, &folder).await?;
+    let _ = imap_session.logout().await;
+    Ok(Json(status))
+}
+
+/// Create a folder
+#[utoipa::path(
+    post,
+    path = "/api/v1/folders",
+    tag = "folders",
+    request_body = FolderName,
+    responses(
+        (status = 201, description = "The folder was created", body = FolderStatus),
+        (status = "4XX", description = "Invalid name or existing folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn post_folder(request: FolderName, config: Config) -> Result<(StatusCode, Json<FolderStatus>), AppError> {
+    let name = request.validate()?;
+    let mut imap_session = create_session(&config).await?;
+    create_folder(&mut imap_session, &name).await?;
+    let status = folder_status(&mut imap_session, &name).await?;
+    let _ = imap_session.logout().await;
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+/// Rename a folder
+#[utoipa::path(
+    patch,
+    path = "/api/v1/folders/{folder}",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name")),
+    request_body = FolderName,
+    responses(
+        (status = 200, description = "Status of the renamed folder", body = FolderStatus),
+        (status = "4XX", description = "Invalid name or unknown folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn patch_folder(folder: String, request: FolderName, config: Config) -> Result<Json<FolderStatus>, AppError> {
+    let new_name = request.validate()?;
+    let mut imap_session = create_session(&config).await?;
+    rename_folder(&mut imap_session, &folder, &new_name).await?;
+    let status = folder_status(&mut imap_session, &new_name).await?;
+    let _ = imap_session.logout().await;
+    Ok(Json(status))
+}
+
+/// Delete a folder and the messages it contains
+#[utoipa::path(
+    delete,
+    path = "/api/v1/folders/{folder}",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name")),
+    responses(
+        (status = 204, description = "The folder was deleted"),
+        (status = "4XX", description = "Unknown folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn remove_folder(folder: String, config: Config) -> Result<StatusCode, AppError> {
+    let mut imap_session = create_session(&config).await?;
+    delete_folder(&mut imap_session, &folder).await?;
+    let _ = imap_session.logout().await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(super) fn folders_router(config: &Config) -> Router {
+    let config_for_list = config.clone();
+    let config_for_create = config.clone();
+    let config_for_status = config.clone();
+    let config_for_rename = config.clone();
+    let config_for_delete = config.clone();
+
+    Router::new()
+        .route("/api/v1/folders", get(move || get_folders(config_for_list))
+            .post(move |ApiJson(request): ApiJson<FolderName>| post_folder(request, config_for_create)))
+        .route("/api/v1/folders/{folder}", get(move |ApiPath(folder): ApiPath<String>| get_folder(folder, config_for_status))
+            .patch(move |ApiPath(folder): ApiPath<String>, ApiJson(request): ApiJson<FolderName>| {
+                patch_folder(folder, request, config_for_rename)
+            })
+            .delete(move |ApiPath(folder): ApiPath<String>| remove_folder(folder, config_for_delete)))
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use axum::{http::StatusCode, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::health::{record_login, snapshot, AccountHealth, HealthState};
+use crate::mail_move_rules::mail_move_settings::load_mail_move_config;
+use crate::mail_move_rules::scheduler::period;
+use crate::mail_reader::imap::create_session;
+use crate::settings::Config;
+
+// Longest wait for the login of one account, so that a server that does not answer cannot hold
+// `/readyz` past the deadline of the probe
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Liveness {
+    status: String,
+    version: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ReadinessStatus {
+    Ready,
+    NotReady,
+}
+
+/// Result of one readiness check
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct Check {
+    ok: bool,
+    /// When the checked event happened, e.g. the last IMAP login
+    #[serde(skip_serializing_if = "Option::is_none")]
+    at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct ReadinessChecks {
+    imap_login: Check,
+    rule_runs: Check,
+    rules_config: Check,
+}
+
+impl ReadinessChecks {
+    fn ok(&self) -> bool {
+        self.imap_login.ok && self.rule_runs.ok && self.rules_config.ok
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Readiness {
+    status: ReadinessStatus,
+    /// Every account together: a check passes when it passes for all of them
+    checks: ReadinessChecks,
+    /// The checks of each account, by name
+    accounts: BTreeMap<String, ReadinessChecks>,
+}
+
+/// Whether the process is alive
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "The process answers", body = Liveness))
+)]
+async fn healthz() -> Json<Liveness> {
+    Json(Liveness { status: "ok".to_string(), version: env!("CARGO_PKG_VERSION").to_string() })
+}
+
+/// Whether the IMAP server, the rule runner and the rules file are all working, for every account
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Every check passed", body = Readiness),
+        (status = 503, description = "At least one check failed", body = Readiness),
+    )
+)]
+async fn readyz(config: Config) -> (StatusCode, Json<Readiness>) {
+    // The accounts are checked together, so the slowest one sets the response time
+    let accounts: Vec<(String, ReadinessChecks)> = futures::future::join_all(config.accounts().iter().map(|account| async move {
+        (account.account_name().to_string(), account_checks(account).await)
+    })).await;
+
+    let checks = ReadinessChecks {
+        imap_login: combine(&accounts, |checks| &checks.imap_login),
+        rule_runs: combine(&accounts, |checks| &checks.rule_runs),
+        rules_config: combine(&accounts, |checks| &checks.rules_config),
+    };
+    let (status, readiness) = if checks.ok() {
+        (StatusCode::OK, ReadinessStatus::Ready)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, ReadinessStatus::NotReady)
+    };
+    (status, Json(Readiness { status: readiness, checks, accounts: accounts.into_iter().collect() }))
+}
+
+async fn account_checks(account: &Config) -> ReadinessChecks {
+    let interval = chrono::Duration::seconds(account.mail_mover.interval_seconds as i64);
+    // With a cron expression, the time between its next two occurrences
+    let rules_period = period(&account.mail_mover.rules_schedule())
+        .ok()
+        .and_then(|period| chrono::Duration::from_std(period).ok())
+        .unwrap_or(interval);
+
+    // Logins of the rule runner and the API count too; only log in here when none happened lately
+    let name = account.account_name();
+    let recent_login = snapshot().account(name).last_login.is_some_and(|login| Utc::now() - login.at <= interval);
+    if !recent_login {
+        let started = Utc::now();
+        let login = tokio::time::timeout(LOGIN_TIMEOUT, async {
+            if let Ok(mut session) = create_session(account).await {
+                let _ = session.logout().await;
+            }
+        });
+        // A login that went through before the logout got stuck is still recorded as it was
+        let logged_in = |health: AccountHealth| health.last_login.is_some_and(|login| login.at >= started);
+        if login.await.is_err() && !logged_in(snapshot().account(name)) {
+            record_login(name, &Err::<(), _>(format!("No answer from the IMAP server within {:?}", LOGIN_TIMEOUT)));
+        }
+    }
+
+    let state = snapshot();
+    let health = state.account(name);
+    ReadinessChecks {
+        imap_login: imap_login_check(&health),
+        rule_runs: rule_runs_check(&state, &health, rules_period),
+        rules_config: rules_config_check(account),
+    }
+}
+
+// The check of a single account as is; with several, the details name the accounts unless they all agree
+fn combine(accounts: &[(String, ReadinessChecks)], check: impl Fn(&ReadinessChecks) -> &Check) -> Check {
+    if let [(_, checks)] = accounts {
+        return check(checks).clone();
+    }
+    let ok = accounts.iter().all(|(_, checks)| check(checks).ok);
+    let first = accounts.first().map(|(_, checks)| check(checks).detail.clone()).unwrap_or_default();
+    if first.is_some() && accounts.iter().all(|(_, checks)| check(checks).detail == first) {
+        return Check { ok, at: None, detail: first };
+    }
+    let details: Vec<String> = accounts
+        .iter()
+        .filter_map(|(name, checks)| check(checks).detail.as_ref().map(|detail| format!("{}: {}", name, detail)))
+        .collect();
+    Check { ok, at: None, detail: (!details.is_empty()).then(|| details.join("; ")) }
+}
+
+fn imap_login_check(health: &AccountHealth) -> Check {
+    match &health.last_login {
+        Some(login) => Check { ok: login.error.is_none(), at: Some(login.at.to_rfc3339()), detail: login.error.clone() },
+        None => Check { ok: false, at: None, detail: Some("No IMAP login attempted yet".to_string()) },
+    }
+}
+
+// Without the periodic runner there is nothing to wait for; with it, a run must have succeeded
+// within two intervals (counted from the start of the runner until the first run)
+fn rule_runs_check(state: &HealthState, health: &AccountHealth, interval: chrono::Duration) -> Check {
+    let Some(started) = state.scheduler_started else {
+        return Check { ok: true, at: None, detail: Some("The periodic rule runner is not running".to_string()) };
+    };
+
+    let reference: DateTime<Utc> = health.last_rule_run.unwrap_or(started);
+    let ok = Utc::now() - reference <= interval * 2;
+    let detail = (!ok).then(|| {
+        format!("No successful rule run for {} seconds, expected every {}", (Utc::now() - reference).num_seconds(), interval.num_seconds())
+    });
+    Check { ok, at: health.last_rule_run.map(|run| run.to_rfc3339()), detail }
+}
+
+fn rules_config_check(account: &Config) -> Check {
+    match load_mail_move_config(account) {
+        Ok(rules) => Check { ok: true, at: None, detail: Some(format!("{} rules", rules.rules.len())) },
+        Err(e) => Check { ok: false, at: None, detail: Some(e.to_string()) },
+    }
+}
+
+pub(super) fn health_router(config: &Config) -> Router {
+    let config_for_ready = config.clone();
+
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(move || readyz(config_for_ready)))
+}
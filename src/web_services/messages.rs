@@ -0,0 +1,391 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{ApiJson, ApiPath, AppError, ErrorBody};
+use crate::events::{publish, MailEvent};
+use crate::mail_reader::error::ImapError;
+use crate::mail_reader::flags::{replace_query, store_query};
+use crate::mail_reader::imap::{
+    copy_messages_by_uids, create_session, delete_messages_by_uids, fetch_flags, fetch_message_by_uid,
+    fetch_raw_message, move_messages_by_uids, store_flags_by_uids, ImapSession,
+};
+use crate::mail_reader::message::Message;
+use crate::settings::Config;
+
+// Most messages a single batch request may change
+const MAX_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum BatchAction {
+    Move,
+    Copy,
+    Delete,
+    Flags,
+}
+
+/// One operation on several messages of a folder, e.g.
+/// `{"action": "move", "uids": [4, 8], "target_folder": "Archive"}`
+#[derive(Debug, Deserialize, ToSchema)]
+struct BatchRequest {
+    action: BatchAction,
+    uids: Vec<u32>,
+    target_folder: Option<String>,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct BatchResult {
+    action: BatchAction,
+    folder: String,
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_folder: Option<String>,
+}
+
+/// Flags to add to and remove from a message, e.g. `{"add": ["\\Seen", "$Work"]}`
+#[derive(Debug, Deserialize, ToSchema)]
+struct FlagsChange {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+/// Every flag a message should have, e.g. `{"flags": ["\\Seen"]}`
+#[derive(Debug, Deserialize, ToSchema)]
+struct FlagsReplacement {
+    flags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct TargetFolder {
+    target_folder: String,
+}
+
+// STORE queries of a flags change, checked before touching the server
+fn flag_queries(add: &[String], remove: &[String]) -> Result<Vec<String>, AppError> {
+    let mut queries = Vec::new();
+    for (is_add, flags) in [(true, add), (false, remove)] {
+        if !flags.is_empty() {
+            queries.push(store_query(is_add, flags).map_err(|e| AppError::bad_request(e.to_string()))?);
+        }
+    }
+    if queries.is_empty() {
+        return Err(AppError::bad_request("No flags to add or remove"));
+    }
+    Ok(queries)
+}
+
+impl BatchRequest {
+    // Check the request and return the STORE queries of a flags action
+    fn validate(&self) -> Result<Vec<String>, AppError> {
+        if self.uids.is_empty() {
+            return Err(AppError::bad_request("No UIDs given"));
+        }
+        if self.uids.len() > MAX_BATCH {
+            return Err(AppError::bad_request(format!("At most {} messages per batch", MAX_BATCH)));
+        }
+        match self.action {
+            BatchAction::Move | BatchAction::Copy if self.target_folder.is_none() => {
+                Err(AppError::bad_request("target_folder is required"))
+            }
+            BatchAction::Flags => flag_queries(&self.add, &self.remove),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+// Run a validated batch in one IMAP command per step
+async fn run_batch(
+    config: &Config,
+    session: &mut ImapSession,
+    folder: &str,
+    request: &BatchRequest,
+    queries: &[String],
+) -> Result<BatchResult, AppError> {
+    let uids = &request.uids;
+    let target_folder = request.target_folder.clone().unwrap_or_default();
+
+    match request.action {
+        BatchAction::Move => {
+            move_messages_by_uids(session, folder, uids, &target_folder).await?;
+            for &uid in uids {
+                publish(config, MailEvent::Moved {
+                    folder: folder.to_string(),
+                    target_folder: target_folder.clone(),
+                    uid: Some(uid),
+                    message_id: None,
+                });
+            }
+        }
+        BatchAction::Copy => copy_messages_by_uids(session, folder, uids, &target_folder).await?,
+        BatchAction::Delete => {
+            delete_messages_by_uids(session, folder, uids).await?;
+            for &uid in uids {
+                publish(config, MailEvent::Deleted {
+                    folder: folder.to_string(),
+                    uid: Some(uid),
+                    message_id: None,
+                });
+            }
+        }
+        BatchAction::Flags => {
+            for query in queries {
+                store_flags_by_uids(session, folder, uids, query).await?;
+            }
+        }
+    }
+
+    Ok(BatchResult {
+        action: request.action,
+        folder: folder.to_string(),
+        count: uids.len(),
+        target_folder: request.target_folder.clone(),
+    })
+}
+
+/// Move, copy, delete or change the flags of several messages at once
+#[utoipa::path(
+    post,
+    path = "/api/v1/emails/{folder}/batch",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name")),
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "The batch was applied", body = BatchResult),
+        (status = "4XX", description = "Bad input or unknown folder", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure, or deleting on a server without UIDPLUS", body = ErrorBody),
+    )
+)]
+async fn post_batch(folder: String, request: BatchRequest, config: Config) -> Result<Json<BatchResult>, AppError> {
+    let queries = request.validate()?;
+    let mut imap_session = create_session(&config).await?;
+    let result = run_batch(&config, &mut imap_session, &folder, &request, &queries).await?;
+    let _ = imap_session.logout().await;
+    Ok(Json(result))
+}
+
+// Run a batch on a single message, failing with 404 when it does not exist
+async fn run_single(folder: String, request: BatchRequest, config: Config) -> Result<BatchResult, AppError> {
+    let queries = request.validate()?;
+    let mut imap_session = create_session(&config).await?;
+    // UID commands silently skip unknown UIDs
+    fetch_flags(&mut imap_session, &folder, request.uids[0]).await?;
+    let result = run_batch(&config, &mut imap_session, &folder, &request, &queries).await?;
+    let _ = imap_session.logout().await;
+    Ok(result)
+}
+
+/// One message
+#[utoipa::path(
+    get,
+    path = "/api/v1/emails/{folder}/{uid}",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    responses(
+        (status = 200, description = "The message", body = Message),
+        (status = "4XX", description = "Unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn get_message(folder: String, uid: u32, config: Config) -> Result<Json<Message>, AppError> {
+    let mut imap_session = create_session(&config).await?;
+    let message = fetch_message_by_uid(&mut imap_session, &folder, uid)
+        .await?
+        .ok_or_else(|| anyhow::Error::from(ImapError::MessageNotFound { mailbox: folder.clone(), uid }))?;
+    let _ = imap_session.logout().await;
+    Ok(Json(message))
+}
+
+/// Download the RFC 822 source of a message as an .eml file
+#[utoipa::path(
+    get,
+    path = "/api/v1/emails/{folder}/{uid}/raw",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    responses(
+        (status = 200, description = "The message source", content_type = "message/rfc822"),
+        (status = "4XX", description = "Unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn get_raw_message(folder: String, uid: u32, config: Config) -> Result<Response, AppError> {
+    let mut imap_session = create_session(&config).await?;
+    let content = fetch_raw_message(&mut imap_session, &folder, uid).await?;
+    let _ = imap_session.logout().await;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "message/rfc822".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.eml\"", uid)),
+        ],
+        content,
+    ).into_response())
+}
+
+/// Delete a message permanently
+#[utoipa::path(
+    delete,
+    path = "/api/v1/emails/{folder}/{uid}",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    responses(
+        (status = 204, description = "The message was deleted"),
+        (status = "4XX", description = "Unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure, or deleting on a server without UIDPLUS", body = ErrorBody),
+    )
+)]
+async fn delete_message(folder: String, uid: u32, config: Config) -> Result<StatusCode, AppError> {
+    let request = BatchRequest { action: BatchAction::Delete, uids: vec![uid], target_folder: None, add: Vec::new(), remove: Vec::new() };
+    run_single(folder, request, config).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn transfer_message(action: BatchAction, folder: String, uid: u32, target: TargetFolder, config: Config) -> Result<Json<BatchResult>, AppError> {
+    let request = BatchRequest {
+        action,
+        uids: vec![uid],
+        target_folder: Some(target.target_folder),
+        add: Vec::new(),
+        remove: Vec::new(),
+    };
+    Ok(Json(run_single(folder, request, config).await?))
+}
+
+/// Move a message to another folder
+#[utoipa::path(
+    post,
+    path = "/api/v1/emails/{folder}/{uid}/move",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    request_body = TargetFolder,
+    responses(
+        (status = 200, description = "The message was moved", body = BatchResult),
+        (status = "4XX", description = "Unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn move_message(folder: String, uid: u32, target: TargetFolder, config: Config) -> Result<Json<BatchResult>, AppError> {
+    transfer_message(BatchAction::Move, folder, uid, target, config).await
+}
+
+/// Copy a message to another folder
+#[utoipa::path(
+    post,
+    path = "/api/v1/emails/{folder}/{uid}/copy",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    request_body = TargetFolder,
+    responses(
+        (status = 200, description = "The message was copied", body = BatchResult),
+        (status = "4XX", description = "Unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn copy_message(folder: String, uid: u32, target: TargetFolder, config: Config) -> Result<Json<BatchResult>, AppError> {
+    transfer_message(BatchAction::Copy, folder, uid, target, config).await
+}
+
+/// Add and remove flags, returning every flag the message has afterwards
+#[utoipa::path(
+    post,
+    path = "/api/v1/emails/{folder}/{uid}/flags",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    request_body = FlagsChange,
+    responses(
+        (status = 200, description = "Flags of the message", body = Vec<String>),
+        (status = "4XX", description = "Invalid flag, unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn update_flags(folder: String, uid: u32, change: FlagsChange, config: Config) -> Result<Json<Vec<String>>, AppError> {
+    let queries = flag_queries(&change.add, &change.remove)?;
+
+    let mut imap_session = create_session(&config).await?;
+    for query in queries {
+        store_flags_by_uids(&mut imap_session, &folder, &[uid], &query).await?;
+    }
+    let flags = fetch_flags(&mut imap_session, &folder, uid).await?;
+
+    let _ = imap_session.logout().await;
+    Ok(Json(flags))
+}
+
+/// Replace every flag of a message and return them
+#[utoipa::path(
+    put,
+    path = "/api/v1/emails/{folder}/{uid}/flags",
+    tag = "messages",
+    params(("folder" = String, Path, description = "Folder name"), ("uid" = u32, Path, description = "Message UID")),
+    request_body = FlagsReplacement,
+    responses(
+        (status = 200, description = "Flags of the message", body = Vec<String>),
+        (status = "4XX", description = "Invalid flag, unknown folder or message", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn replace_flags(folder: String, uid: u32, replacement: FlagsReplacement, config: Config) -> Result<Json<Vec<String>>, AppError> {
+    let query = replace_query(&replacement.flags).map_err(|e| AppError::bad_request(e.to_string()))?;
+
+    let mut imap_session = create_session(&config).await?;
+    fetch_flags(&mut imap_session, &folder, uid).await?;
+    store_flags_by_uids(&mut imap_session, &folder, &[uid], &query).await?;
+    let flags = fetch_flags(&mut imap_session, &folder, uid).await?;
+
+    let _ = imap_session.logout().await;
+    Ok(Json(flags))
+}
+
+pub(super) fn messages_router(config: &Config) -> Router {
+    let config_for_get = config.clone();
+    let config_for_delete = config.clone();
+    let config_for_raw = config.clone();
+    let config_for_move = config.clone();
+    let config_for_copy = config.clone();
+    let config_for_flags = config.clone();
+    let config_for_replace = config.clone();
+    let config_for_batch = config.clone();
+
+    Router::new()
+        .route("/api/v1/emails/{folder}/batch", post(
+            move |ApiPath(folder): ApiPath<String>, ApiJson(request): ApiJson<BatchRequest>| post_batch(folder, request, config_for_batch)
+        ))
+        .route("/api/v1/emails/{folder}/{uid}", get(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>| get_message(folder, uid, config_for_get)
+        ).delete(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>| delete_message(folder, uid, config_for_delete)
+        ))
+        .route("/api/v1/emails/{folder}/{uid}/raw", get(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>| get_raw_message(folder, uid, config_for_raw)
+        ))
+        .route("/api/v1/emails/{folder}/{uid}/move", post(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>, ApiJson(target): ApiJson<TargetFolder>| {
+                move_message(folder, uid, target, config_for_move)
+            }
+        ))
+        .route("/api/v1/emails/{folder}/{uid}/copy", post(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>, ApiJson(target): ApiJson<TargetFolder>| {
+                copy_message(folder, uid, target, config_for_copy)
+            }
+        ))
+        .route("/api/v1/emails/{folder}/{uid}/flags", post(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>, ApiJson(change): ApiJson<FlagsChange>| {
+                update_flags(folder, uid, change, config_for_flags)
+            }
+        ).put(
+            move |ApiPath((folder, uid)): ApiPath<(String, u32)>, ApiJson(replacement): ApiJson<FlagsReplacement>| {
+                replace_flags(folder, uid, replacement, config_for_replace)
+            }
+        ))
+}
@@ -0,0 +1,99 @@
+use axum::{routing::get, Json, Router};
+use utoipa::openapi::path::{ParameterBuilder, ParameterIn};
+use utoipa::openapi::{ObjectBuilder, PathItem, Required, Type};
+use utoipa::{Modify, OpenApi};
+
+use super::{folders, health, messages, rules};
+
+/// OpenAPI description of the REST interface, generated from the handlers
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Almambet REST API", description = "Read, search, organize and send mail through an IMAP account"),
+    paths(
+        super::get_data,
+        super::search_data,
+        super::list_attachments,
+        super::get_attachment,
+        super::send_message,
+        super::list_accounts,
+        folders::get_folders,
+        folders::post_folder,
+        folders::get_folder,
+        folders::patch_folder,
+        folders::remove_folder,
+        messages::get_message,
+        messages::get_raw_message,
+        messages::delete_message,
+        messages::move_message,
+        messages::copy_message,
+        messages::update_flags,
+        messages::replace_flags,
+        messages::post_batch,
+        rules::list_rules,
+        rules::create_rule,
+        rules::get_rule,
+        rules::update_rule,
+        rules::delete_rule,
+        rules::reorder_rules,
+        rules::validate_rule,
+        rules::run,
+        health::healthz,
+        health::readyz,
+    ),
+    modifiers(&AccountPaths),
+    tags(
+        (name = "accounts", description = "Mail accounts, each served under /api/v1/accounts/{account}"),
+        (name = "messages", description = "Listing, searching and managing messages"),
+        (name = "attachments", description = "Attachments of a message"),
+        (name = "folders", description = "Folders of the account"),
+        (name = "rules", description = "Rules moving INBOX messages to other folders"),
+        (name = "sending", description = "Sending mail over SMTP"),
+        (name = "health", description = "Liveness and readiness probes"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Adds the `/api/v1/accounts/{account}/...` copy of every path of an account
+struct AccountPaths;
+
+impl Modify for AccountPaths {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let account = ParameterBuilder::new()
+            .name("account")
+            .parameter_in(ParameterIn::Path)
+            .required(Required::True)
+            .description(Some("Account name"))
+            .schema(Some(ObjectBuilder::new().schema_type(Type::String)))
+            .build();
+
+        let copies: Vec<(String, PathItem)> = openapi
+            .paths
+            .paths
+            .iter()
+            .filter(|(path, _)| path.as_str() != "/api/v1/accounts")
+            .filter_map(|(path, item)| {
+                let rest = path.strip_prefix("/api/v1/")?;
+                let mut item = item.clone();
+                item.parameters.get_or_insert_with(Vec::new).insert(0, account.clone().into());
+                // Operation ids must stay unique
+                for operation in [&mut item.get, &mut item.put, &mut item.post, &mut item.delete, &mut item.patch].into_iter().flatten() {
+                    operation.operation_id = operation.operation_id.take().map(|id| format!("{}_of_account", id));
+                }
+                Some((format!("/api/v1/accounts/{{account}}/{}", rest), item))
+            })
+            .collect();
+        openapi.paths.paths.extend(copies);
+    }
+}
+
+pub(super) fn openapi_router() -> Router {
+    let router = Router::new().route("/api/v1/openapi.json", get(|| async { Json(ApiDoc::openapi()) }));
+
+    // The explorer loads the document from the route above
+    #[cfg(feature = "swagger-ui")]
+    let router = router.merge(
+        utoipa_swagger_ui::SwaggerUi::new("/api/v1/docs").config(utoipa_swagger_ui::Config::from("/api/v1/openapi.json")),
+    );
+
+    router
+}
@@ -0,0 +1,235 @@
+use axum::{http::StatusCode, routing::{get, post}, Json, Router};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::{ApiJson, ApiPath, ApiQuery, AppError, ErrorBody};
+use crate::mail_move_rules::mail_move_settings::{load_mail_move_config, update_mail_move_config, Rule, RuleWrapper, RulesConfig};
+use crate::mail_move_rules::scheduler::{run_exclusive, JobKind};
+use crate::mail_move_rules::{run_rules, RunReport};
+use crate::settings::Config;
+
+/// A rule with its position in the rules file, which is also the order rules are tried in.
+/// The ids of the following rules change when a rule is deleted, and all of them when the rules are reordered.
+#[derive(Debug, Serialize, ToSchema)]
+struct IndexedRule {
+    id: usize,
+    #[serde(flatten)]
+    rule: Rule,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct RuleList {
+    messages_to_check: u32,
+    rules: Vec<IndexedRule>,
+}
+
+impl From<RulesConfig> for RuleList {
+    fn from(config: RulesConfig) -> Self {
+        RuleList {
+            messages_to_check: config.messages_to_check,
+            rules: config.rules
+                .into_iter()
+                .enumerate()
+                .map(|(id, wrapper)| IndexedRule { id, rule: wrapper.rule })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ValidationResult {
+    valid: bool,
+    errors: Vec<String>,
+}
+
+/// New order of the rules, as the list of their current ids, e.g. `{"order": [2, 0, 1]}`
+#[derive(Debug, Deserialize, ToSchema)]
+struct Reorder {
+    order: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RunParams {
+    /// Only report what would be moved
+    #[serde(default)]
+    dry_run: bool,
+}
+
+fn check_rule(rule: &Rule) -> Result<(), AppError> {
+    let errors = rule.validate();
+    if !errors.is_empty() {
+        return Err(AppError::bad_request("The rule is not valid").with_details(errors));
+    }
+    Ok(())
+}
+
+fn rule_not_found(id: usize) -> AppError {
+    AppError::new(StatusCode::NOT_FOUND, "rule_not_found", format!("No rule with id {}", id))
+}
+
+/// List the rules, in the order they are tried
+#[utoipa::path(
+    get,
+    path = "/api/v1/rules",
+    tag = "rules",
+    responses(
+        (status = 200, description = "The rules", body = RuleList),
+        (status = "4XX", description = "Bad input", body = ErrorBody),
+        (status = 500, description = "The rules file cannot be read", body = ErrorBody),
+    )
+)]
+async fn list_rules(config: Config) -> Result<Json<RuleList>, AppError> {
+    Ok(Json(load_mail_move_config(&config)?.into()))
+}
+
+/// One rule
+#[utoipa::path(
+    get,
+    path = "/api/v1/rules/{id}",
+    tag = "rules",
+    params(("id" = usize, Path, description = "Position of the rule")),
+    responses(
+        (status = 200, description = "The rule", body = IndexedRule),
+        (status = "4XX", description = "Unknown rule", body = ErrorBody),
+    )
+)]
+async fn get_rule(id: usize, config: Config) -> Result<Json<IndexedRule>, AppError> {
+    let wrapper = load_mail_move_config(&config)?.rules.into_iter().nth(id).ok_or_else(|| rule_not_found(id))?;
+    Ok(Json(IndexedRule { id, rule: wrapper.rule }))
+}
+
+/// Append a rule
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules",
+    tag = "rules",
+    request_body = Rule,
+    responses(
+        (status = 201, description = "The rule was saved", body = IndexedRule),
+        (status = "4XX", description = "Invalid rule", body = ErrorBody),
+    )
+)]
+async fn create_rule(rule: Rule, config: Config) -> Result<(StatusCode, Json<IndexedRule>), AppError> {
+    check_rule(&rule)?;
+    let id = update_mail_move_config(&config, |rules_config| {
+        rules_config.rules.push(RuleWrapper { rule: rule.clone() });
+        Ok::<_, AppError>(rules_config.rules.len() - 1)
+    })?;
+    Ok((StatusCode::CREATED, Json(IndexedRule { id, rule })))
+}
+
+/// Replace a rule
+#[utoipa::path(
+    put,
+    path = "/api/v1/rules/{id}",
+    tag = "rules",
+    params(("id" = usize, Path, description = "Position of the rule")),
+    request_body = Rule,
+    responses(
+        (status = 200, description = "The rule was saved", body = IndexedRule),
+        (status = "4XX", description = "Invalid or unknown rule", body = ErrorBody),
+    )
+)]
+async fn update_rule(id: usize, rule: Rule, config: Config) -> Result<Json<IndexedRule>, AppError> {
+    check_rule(&rule)?;
+    update_mail_move_config(&config, |rules_config| {
+        let wrapper = rules_config.rules.get_mut(id).ok_or_else(|| rule_not_found(id))?;
+        wrapper.rule = rule.clone();
+        Ok::<_, AppError>(())
+    })?;
+    Ok(Json(IndexedRule { id, rule }))
+}
+
+/// Delete a rule; the rules after it move up by one id
+#[utoipa::path(
+    delete,
+    path = "/api/v1/rules/{id}",
+    tag = "rules",
+    params(("id" = usize, Path, description = "Position of the rule")),
+    responses(
+        (status = 204, description = "The rule was deleted"),
+        (status = "4XX", description = "Unknown rule", body = ErrorBody),
+    )
+)]
+async fn delete_rule(id: usize, config: Config) -> Result<StatusCode, AppError> {
+    update_mail_move_config(&config, |rules_config| {
+        if id >= rules_config.rules.len() {
+            return Err(rule_not_found(id));
+        }
+        rules_config.rules.remove(id);
+        Ok(())
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Put the rules in a new order, which gives them new ids
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules/reorder",
+    tag = "rules",
+    request_body = Reorder,
+    responses(
+        (status = 200, description = "The rules in their new order", body = RuleList),
+        (status = "4XX", description = "The order is not a permutation of the rule ids", body = ErrorBody),
+    )
+)]
+async fn reorder_rules(reorder: Reorder, config: Config) -> Result<Json<RuleList>, AppError> {
+    let rules_config = update_mail_move_config(&config, |rules_config| {
+        rules_config.reorder(&reorder.order).map_err(|e| AppError::bad_request(e.to_string()))?;
+        Ok::<_, AppError>(rules_config.clone())
+    })?;
+    Ok(Json(rules_config.into()))
+}
+
+/// Check a rule without saving it
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules/validate",
+    tag = "rules",
+    request_body = Rule,
+    responses(
+        (status = 200, description = "Problems found in the rule", body = ValidationResult),
+        (status = "4XX", description = "Malformed body", body = ErrorBody),
+    )
+)]
+async fn validate_rule(rule: Rule) -> Json<ValidationResult> {
+    let errors = rule.validate();
+    Json(ValidationResult { valid: errors.is_empty(), errors })
+}
+
+/// Apply the rules to INBOX now
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules/run",
+    tag = "rules",
+    params(RunParams),
+    responses(
+        (status = 200, description = "What happened to each matching message", body = RunReport),
+        (status = "4XX", description = "Bad input", body = ErrorBody),
+        (status = "5XX", description = "IMAP server failure", body = ErrorBody),
+    )
+)]
+async fn run(params: RunParams, config: Config) -> Result<Json<RunReport>, AppError> {
+    Ok(Json(run_exclusive(JobKind::Rules, &config, run_rules(&config, params.dry_run)).await?))
+}
+
+pub(super) fn rules_router(config: &Config) -> Router {
+    let config_for_list = config.clone();
+    let config_for_create = config.clone();
+    let config_for_reorder = config.clone();
+    let config_for_run = config.clone();
+    let config_for_get = config.clone();
+    let config_for_update = config.clone();
+    let config_for_delete = config.clone();
+
+    Router::new()
+        .route("/api/v1/rules", get(move || list_rules(config_for_list))
+            .post(move |ApiJson(rule): ApiJson<Rule>| create_rule(rule, config_for_create)))
+        .route("/api/v1/rules/reorder", post(move |ApiJson(reorder): ApiJson<Reorder>| reorder_rules(reorder, config_for_reorder)))
+        .route("/api/v1/rules/validate", post(|ApiJson(rule): ApiJson<Rule>| validate_rule(rule)))
+        .route("/api/v1/rules/run", post(move |ApiQuery(params): ApiQuery<RunParams>| run(params, config_for_run)))
+        .route("/api/v1/rules/{id}", get(move |ApiPath(id): ApiPath<usize>| get_rule(id, config_for_get))
+            .put(move |ApiPath(id): ApiPath<usize>, ApiJson(rule): ApiJson<Rule>| update_rule(id, rule, config_for_update))
+            .delete(move |ApiPath(id): ApiPath<usize>| delete_rule(id, config_for_delete)))
+}
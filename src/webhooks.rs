@@ -0,0 +1,298 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, KeyInit, Mac};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{subscribe, AccountEvent, MailEvent};
+use crate::settings::{Config, WebhookEvent};
+
+// Deliveries not acknowledged yet, kept across restarts
+const QUEUE_FILE: &str = ".webhook_queue.json";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Delay before the first retry, doubled at every failed attempt up to the maximum
+const INITIAL_BACKOFF_SECS: i64 = 5;
+const MAX_BACKOFF_SECS: i64 = 3600;
+// Longest sleep of the dispatcher when nothing is due
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Body POSTed to the webhooks
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    /// Name of the account the event happened in
+    account: &'a str,
+    timestamp: String,
+    data: &'a Value,
+}
+
+/// A payload waiting to be delivered to one URL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    id: String,
+    url: String,
+    /// `X-Almambet-Signature` header, computed when queued so that the secret is not written to the queue
+    #[serde(default)]
+    signature: Option<String>,
+    event: WebhookEvent,
+    body: String,
+    attempts: u32,
+    max_attempts: u32,
+    /// Unix time of the next attempt
+    next_attempt: i64,
+}
+
+/// Deliveries not acknowledged yet, saved to a file after every change so that they survive restarts
+pub struct WebhookQueue {
+    path: PathBuf,
+    deliveries: Mutex<Vec<Delivery>>,
+    // The dispatcher and a final flush must not send the same delivery twice
+    delivering: tokio::sync::Mutex<()>,
+    wake: Notify,
+}
+
+// The queue of the process, in the working directory
+fn queue() -> &'static WebhookQueue {
+    static QUEUE: OnceLock<WebhookQueue> = OnceLock::new();
+    QUEUE.get_or_init(|| WebhookQueue::open(QUEUE_FILE))
+}
+
+fn load_queue(path: &Path) -> Vec<Delivery> {
+    if !path.exists() {
+        return Vec::new();
+    }
+    match fs::read_to_string(path).map_err(anyhow::Error::from).and_then(|json| Ok(serde_json::from_str(&json)?)) {
+        Ok(deliveries) => deliveries,
+        Err(e) => {
+            error!("Cannot read the webhook queue {:?}, starting with an empty one: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `body`, as sent in `X-Almambet-Signature`
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    format!("sha256={}", digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+}
+
+/// Delay before the attempt following `attempts` failed ones
+pub fn backoff_secs(attempts: u32) -> i64 {
+    INITIAL_BACKOFF_SECS
+        .saturating_mul(2_i64.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF_SECS)
+}
+
+/// Queue `event` for every webhook interested in it; delivery happens in the background
+pub fn notify(config: &Config, event: WebhookEvent, data: Value) {
+    queue().notify(config, event, data);
+}
+
+/// Try every delivery of the process that is due once; see `WebhookQueue::deliver_due`
+pub async fn deliver_due() -> Option<i64> {
+    queue().deliver_due().await
+}
+
+async fn send(client: &reqwest::Client, delivery: &Delivery) -> Result<()> {
+    let mut request = client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Almambet-Event", serde_json::to_value(delivery.event)?.as_str().unwrap_or_default())
+        .header("X-Almambet-Delivery", &delivery.id)
+        .body(delivery.body.clone());
+    if let Some(signature) = &delivery.signature {
+        request = request.header("X-Almambet-Signature", signature);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{} answered {}", delivery.url, response.status()));
+    }
+    Ok(())
+}
+
+impl WebhookQueue {
+    /// The queue kept in `path`, with the deliveries left there by a previous run
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        WebhookQueue {
+            deliveries: Mutex::new(load_queue(&path)),
+            path,
+            delivering: tokio::sync::Mutex::const_new(()),
+            wake: Notify::new(),
+        }
+    }
+
+    fn save(&self, deliveries: &[Delivery]) {
+        let write = || -> Result<()> {
+            let temp_path = self.path.with_extension("json.tmp");
+            fs::write(&temp_path, serde_json::to_string(deliveries)?)?;
+            fs::rename(&temp_path, &self.path)?;
+            Ok(())
+        };
+        if let Err(e) = write() {
+            error!("Cannot save the webhook queue {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Queue `event` for every webhook of `config` interested in it
+    pub fn notify(&self, config: &Config, event: WebhookEvent, data: Value) {
+        let endpoints: Vec<_> = config.webhooks.iter().filter(|hook| hook.events.contains(&event)).collect();
+        if endpoints.is_empty() {
+            return;
+        }
+
+        let payload = Payload { event, account: config.account_name(), timestamp: chrono::Utc::now().to_rfc3339(), data: &data };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Cannot serialize the {:?} webhook payload: {}", event, e);
+                return;
+            }
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let mut deliveries = self.deliveries.lock().unwrap();
+        for hook in endpoints {
+            deliveries.push(Delivery {
+                id: format!("{:032x}", rand::random::<u128>()),
+                url: hook.url.clone(),
+                signature: hook.secret.as_ref().map(|secret| signature(secret, body.as_bytes())),
+                event,
+                body: body.clone(),
+                attempts: 0,
+                max_attempts: hook.max_attempts.max(1),
+                next_attempt: now,
+            });
+        }
+        self.save(&deliveries);
+        drop(deliveries);
+        self.wake.notify_one();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.deliveries.lock().unwrap().is_empty()
+    }
+
+    /// Try every delivery that is due once, rescheduling the failed ones.
+    /// Returns the Unix time of the next pending attempt, if any.
+    pub async fn deliver_due(&self) -> Option<i64> {
+        let _delivering = self.delivering.lock().await;
+
+        let now = chrono::Utc::now().timestamp();
+        let due: Vec<Delivery> = self.deliveries.lock().unwrap().iter().filter(|delivery| delivery.next_attempt <= now).cloned().collect();
+        if due.is_empty() {
+            return self.deliveries.lock().unwrap().iter().map(|delivery| delivery.next_attempt).min();
+        }
+
+        let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Cannot create the webhook HTTP client: {}", e);
+                return Some(now + INITIAL_BACKOFF_SECS);
+            }
+        };
+
+        let mut results = Vec::new();
+        for delivery in &due {
+            let result = send(&client, delivery).await;
+            match &result {
+                Ok(()) => info!("Delivered {:?} webhook {} to {}", delivery.event, delivery.id, delivery.url),
+                Err(e) => warn!("Webhook {} to {} failed (attempt {}): {}", delivery.id, delivery.url, delivery.attempts + 1, e),
+            }
+            results.push((delivery.id.clone(), result.is_ok()));
+        }
+
+        let mut deliveries = self.deliveries.lock().unwrap();
+        for (id, delivered) in results {
+            let Some(position) = deliveries.iter().position(|delivery| delivery.id == id) else { continue };
+            if delivered {
+                deliveries.remove(position);
+                continue;
+            }
+
+            let delivery = &mut deliveries[position];
+            delivery.attempts += 1;
+            if delivery.attempts >= delivery.max_attempts {
+                error!("Dropping webhook {} to {} after {} attempts", delivery.id, delivery.url, delivery.attempts);
+                deliveries.remove(position);
+            } else {
+                delivery.next_attempt = chrono::Utc::now().timestamp() + backoff_secs(delivery.attempts);
+            }
+        }
+        self.save(&deliveries);
+        deliveries.iter().map(|delivery| delivery.next_attempt).min()
+    }
+
+    // Deliver queued payloads as they become due, for as long as the process runs
+    async fn dispatch(&self) {
+        loop {
+            let sleep = match self.deliver_due().await {
+                Some(next_attempt) => {
+                    let wait = (next_attempt - chrono::Utc::now().timestamp()).max(0) as u64;
+                    Duration::from_secs(wait).min(IDLE_INTERVAL)
+                }
+                None => IDLE_INTERVAL,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+}
+
+/// Whether some webhook wants the `new_message` events, which need the folder watcher
+pub fn forwards_new_messages(config: &Config) -> bool {
+    config.webhooks.iter().any(|hook| hook.events.contains(&WebhookEvent::NewMessage))
+}
+
+/// Turn the new messages noticed by the folder watcher into webhook events, until `shutdown` is cancelled
+pub async fn forward_new_messages(config: Config, shutdown: CancellationToken) {
+    let mut events = subscribe();
+    loop {
+        let received = tokio::select! {
+            received = events.recv() => received,
+            _ = shutdown.cancelled() => return,
+        };
+        match received {
+            Ok(AccountEvent { account, event: event @ MailEvent::NewMessage { .. } }) => {
+                // Sent with the settings of the account the message arrived in
+                let Some(account) = account.and_then(|name| config.account(&name)) else { continue };
+                match serde_json::to_value(&event) {
+                    Ok(data) => notify(&account, WebhookEvent::NewMessage, data),
+                    Err(e) => error!("Cannot serialize {:?}: {}", event, e),
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => warn!("Webhooks missed {} mailbox events", skipped),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Start delivering webhooks in the background, including the ones left from a previous run.
+/// Does nothing once the dispatcher runs, so it can be called again after reloading the settings.
+pub fn start(config: &Config) {
+    static DISPATCHING: AtomicBool = AtomicBool::new(false);
+    if config.webhooks.is_empty() && queue().is_empty() {
+        return;
+    }
+    if DISPATCHING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    debug!("Starting the webhook dispatcher");
+    tokio::spawn(queue().dispatch());
+}